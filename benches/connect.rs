@@ -0,0 +1,74 @@
+//! Decoding of REST responses: the instruments dump and JSON payloads.
+//!
+//! ```text
+//! cargo bench --bench connect
+//! ```
+use std::fmt::Write;
+use std::fs;
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use serde_json::Value as JsonValue;
+
+use kiteconnect::instruments::InstrumentIndex;
+
+/// Instruments dump shaped like the full one, mostly options and futures
+fn instruments_csv(rows: u32) -> String {
+    let mut csv = String::from("instrument_token,exchange_token,tradingsymbol,name,last_price,expiry,strike,tick_size,lot_size,instrument_type,segment,exchange\n");
+    for row in 0..rows {
+        let token = (row << 8) | [1, 2, 3][row as usize % 3];
+        match row % 10 {
+            0 => writeln!(csv, "{},{},SYM{},COMPANY {},0,,0,0.05,1,EQ,NSE,NSE", token, row, row, row),
+            1 => writeln!(csv, "{},{},SYM{}FUT,SYM{},0,2024-01-25,0,0.05,50,FUT,NFO-FUT,NFO", token, row, row, row),
+            _ => writeln!(csv, "{},{},SYM{}{}CE,SYM{},0,2024-01-25,{},0.05,50,CE,NFO-OPT,NFO",
+                          token, row, row, 15000 + row % 200 * 50, row, 15000 + row % 200 * 50),
+        }.unwrap();
+    }
+    csv
+}
+
+/// Repeats the records of a mock response until `size` are present
+fn scaled(path: &str, size: usize, records: fn(&mut JsonValue) -> Vec<&mut Vec<JsonValue>>) -> String {
+    let mut response: JsonValue = serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap();
+    for list in records(&mut response) {
+        let template = list.clone();
+        *list = template.iter().cycle().take(size).cloned().collect();
+    }
+    response.to_string()
+}
+
+fn instruments(c: &mut Criterion) {
+    let csv = instruments_csv(100_000);
+    let mut group = c.benchmark_group("instruments");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(csv.len() as u64));
+    group.bench_function("from_csv/100000", |b| {
+        b.iter(|| InstrumentIndex::from_csv(black_box(csv.as_bytes())).unwrap())
+    });
+    group.finish();
+
+    let index = InstrumentIndex::from_csv(csv.as_bytes()).unwrap();
+    let symbols: Vec<String> = index.iter().step_by(1000).map(|instrument| instrument.symbol()).collect();
+    let symbols: Vec<&str> = symbols.iter().map(String::as_str).collect();
+    c.bench_function("instruments/resolve/100", |b| b.iter(|| index.resolve(black_box(&symbols)).unwrap()));
+}
+
+fn responses(c: &mut Criterion) {
+    let orders = scaled("mocks/orders.json", 500, |response| {
+        vec![response["data"].as_array_mut().unwrap()]
+    });
+    let positions = scaled("mocks/positions.json", 250, |response| {
+        let data = response["data"].as_object_mut().unwrap();
+        data.values_mut().filter_map(JsonValue::as_array_mut).collect()
+    });
+
+    let mut group = c.benchmark_group("json");
+    for (name, body) in [("orders/500", &orders), ("positions/250", &positions)].iter() {
+        group.throughput(Throughput::Bytes(body.len() as u64));
+        group.bench_function(*name, |b| {
+            b.iter(|| serde_json::from_str::<JsonValue>(black_box(body)).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, instruments, responses);
+criterion_main!(benches);
@@ -0,0 +1,77 @@
+//! Decoding and encoding of binary ticker frames.
+//!
+//! ```text
+//! cargo bench --bench ticker
+//! ```
+use chrono::TimeZone;
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+use kiteconnect::ticker::{encode_binary, ist, parse_binary, Depth, DepthItem, Mode, Ohlc, Tick};
+
+fn full_tick(index: u32) -> Tick {
+    let price = 1000.0 + index as f64 * 0.05;
+    let level = |offset: f64, quantity: u32| DepthItem { quantity, price: price + offset, orders: 3 };
+    Tick {
+        mode: Mode::Full,
+        // NSE equities are segment 1
+        instrument_token: (index << 8) | 1,
+        last_price: price,
+        last_quantity: 10,
+        average_price: price - 1.0,
+        volume: 250_000 + index,
+        buy_quantity: 12_000,
+        sell_quantity: 9_000,
+        ohlc: Ohlc { open: price - 5.0, high: price + 10.0, low: price - 12.0, close: price - 4.0 },
+        last_trade_time: ist().timestamp_opt(1577850300, 0).single(),
+        oi: 1500,
+        oi_day_high: 1600,
+        oi_day_low: 1400,
+        exchange_timestamp: ist().timestamp_opt(1577850301, 0).single(),
+        depth: Some(Depth {
+            buy: (1..=5).map(|n| level(-0.05 * n as f64, 100 * n)).collect(),
+            sell: (1..=5).map(|n| level(0.05 * n as f64, 90 * n)).collect(),
+        }),
+        ..Tick::default()
+    }
+}
+
+/// Frame with equal shares of ltp, quote and full packets
+fn mixed_ticks(count: u32) -> Vec<Tick> {
+    (0..count).map(|index| {
+        let mode = [Mode::Ltp, Mode::Quote, Mode::Full][index as usize % 3];
+        let tick = full_tick(index);
+        match mode {
+            Mode::Full => tick,
+            Mode::Quote => Tick { mode, last_trade_time: None, oi: 0, oi_day_high: 0, oi_day_low: 0,
+                                  exchange_timestamp: None, depth: None, ..tick },
+            Mode::Ltp => Tick { mode, instrument_token: tick.instrument_token, last_price: tick.last_price,
+                                ..Tick::default() },
+        }
+    }).collect()
+}
+
+fn decode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse_binary");
+    for &count in [1, 100, 500].iter() {
+        let frame = encode_binary(&(0..count).map(full_tick).collect::<Vec<_>>());
+        group.throughput(Throughput::Bytes(frame.len() as u64));
+        group.bench_with_input(BenchmarkId::new("full", count), &frame, |b, frame| {
+            b.iter(|| parse_binary(black_box(frame)).unwrap())
+        });
+    }
+
+    let frame = encode_binary(&mixed_ticks(500));
+    group.throughput(Throughput::Bytes(frame.len() as u64));
+    group.bench_with_input(BenchmarkId::new("mixed", 500), &frame, |b, frame| {
+        b.iter(|| parse_binary(black_box(frame)).unwrap())
+    });
+    group.finish();
+}
+
+fn encode(c: &mut Criterion) {
+    let ticks: Vec<Tick> = (0..500).map(full_tick).collect();
+    c.bench_function("encode_binary/full/500", |b| b.iter(|| encode_binary(black_box(&ticks))));
+}
+
+criterion_group!(benches, decode, encode);
+criterion_main!(benches);
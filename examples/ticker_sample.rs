@@ -1,9 +1,6 @@
 extern crate kiteconnect;
-extern crate ws;
-extern crate serde_json as json;
 
-use kiteconnect::ticker::{KiteTicker, KiteTickerHandler, WebSocketHandler};
-use ws::Message;
+use kiteconnect::ticker::{KiteTicker, KiteTickerHandler, Mode, Tick, WebSocketHandler};
 
 #[derive(Debug)]
 struct CustomHandler {
@@ -14,23 +11,23 @@ impl KiteTickerHandler for CustomHandler {
     fn on_open<T>(&mut self, ws: &mut WebSocketHandler<T>)
     where T: KiteTickerHandler {
         // Subscribe to a list of tokens on opening the websocket connection
-        ws.subscribe(vec![53511431]);
-        ws.set_mode("full", vec![53511431]);
+        ws.subscribe_with_mode(Mode::Full, vec![53511431]).unwrap();
         println!("Fellow on_open callback");
     }
 
-    fn on_ticks<T>(&mut self, ws: &mut WebSocketHandler<T>, tick: Vec<json::Value>)
+    fn on_ticks<T>(&mut self, _ws: &mut WebSocketHandler<T>, ticks: Vec<Tick>)
     where T: KiteTickerHandler {
-        println!("{:?}", tick);
-        println!("Fellow on_ticks callback");
+        self.count += 1;
+        println!("{:?}", ticks);
+        println!("Fellow on_ticks callback #{}", self.count);
     }
 
-    fn on_close<T>(&mut self, ws: &mut WebSocketHandler<T>)
+    fn on_close<T>(&mut self, _ws: &mut WebSocketHandler<T>)
     where T: KiteTickerHandler {
         println!("Fellow on_close callback");
     }
 
-    fn on_error<T>(&mut self, ws: &mut WebSocketHandler<T>)
+    fn on_error<T>(&mut self, _ws: &mut WebSocketHandler<T>)
     where T: KiteTickerHandler {
         println!("Fellow on_error callback");
     }
@@ -44,7 +41,8 @@ fn main() {
         count: 0
     };
 
-    ticker.connect(custom_handler, None);
+    ticker.connect(custom_handler, None).unwrap();
 
-    loop {}
+    // Blocks until the connection is closed
+    ticker.join().unwrap();
 }
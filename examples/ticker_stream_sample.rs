@@ -0,0 +1,20 @@
+extern crate kiteconnect;
+
+use kiteconnect::ticker::{Backpressure, KiteTicker, TickerEvent};
+
+fn main() {
+    // Assumes you have generated the access token beforehand.
+    let mut ticker = KiteTicker::new("<API-KEY>", "<ACCESS-TOKEN>");
+
+    // Keep at most 1000 events around and only the latest tick per instrument
+    let (_handle, stream) = ticker.stream(1000, Backpressure::Conflate, None).unwrap();
+
+    for event in stream {
+        match event {
+            TickerEvent::Tick(tick) => println!("{:?}", tick),
+            TickerEvent::OrderUpdate(order) => println!("Order update {:?}", order),
+            TickerEvent::Disconnected => break,
+            event => println!("{:?}", event),
+        }
+    }
+}
@@ -0,0 +1,9 @@
+//! Arbitrary frames must decode or fail, never panic
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use kiteconnect::ticker::parse_binary;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = parse_binary(data);
+});
@@ -0,0 +1,21 @@
+//! Decoded ticks re-encode to a frame that decodes back to itself.
+//!
+//! Arbitrary input can carry packet shapes the ticker never sends for a
+//! segment, such as an index layout for an equity token, so the first
+//! encoding may differ from the input. From then on it must be stable.
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use kiteconnect::ticker::{encode_binary, parse_binary};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(ticks) = parse_binary(data) {
+        if ticks.len() > u16::MAX as usize {
+            return;
+        }
+        let encoded = encode_binary(&ticks);
+        let decoded = parse_binary(&encoded).unwrap();
+        assert_eq!(decoded.len(), ticks.len());
+        assert_eq!(encode_binary(&decoded), encoded);
+    }
+});
@@ -0,0 +1,307 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::mpsc;
+
+use crate::ticker::{KiteTickerHandler, Mode, Tick, WebSocketHandler};
+
+/// Value of a tick an alert watches
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Metric {
+    LastPrice,
+    /// Percentage change from the previous close
+    Change,
+    /// Cumulative day volume
+    Volume,
+    /// Open interest
+    Oi,
+    /// Percentage change of open interest since the open, see
+    /// `AlertEngine::set_oi_open`
+    OiChange,
+}
+
+impl Metric {
+    /// Value of the metric in `tick`, if the tick carries it
+    fn value(&self, tick: &Tick, oi_open: Option<u32>) -> Option<f64> {
+        match self {
+            Metric::LastPrice => Some(tick.last_price),
+            Metric::Change if tick.ohlc.close != 0.0 => Some(tick.change),
+            Metric::Volume if tick.mode >= Mode::Quote && tick.tradable => Some(tick.volume as f64),
+            Metric::Oi if tick.mode == Mode::Full => Some(tick.oi as f64),
+            Metric::OiChange if tick.mode == Mode::Full => {
+                let open = oi_open.filter(|oi| *oi > 0)? as f64;
+                Some((tick.oi as f64 - open) * 100_f64 / open)
+            },
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Metric {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Metric::LastPrice => "last_price",
+            Metric::Change => "change",
+            Metric::Volume => "volume",
+            Metric::Oi => "oi",
+            Metric::OiChange => "oi_change",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Above,
+    Below,
+}
+
+/// A threshold on a metric, e.g. `Condition::above(Metric::LastPrice, 2500.0)`.
+///
+/// An alert fires when its condition becomes true and re-arms only once the
+/// value has moved back past the threshold by `hysteresis`, so a price
+/// oscillating around the threshold triggers once.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Condition {
+    pub metric: Metric,
+    pub direction: Direction,
+    pub threshold: f64,
+    pub hysteresis: f64,
+}
+
+impl Condition {
+    /// Met when `metric` rises above `threshold`
+    pub fn above(metric: Metric, threshold: f64) -> Condition {
+        Condition { metric, direction: Direction::Above, threshold, hysteresis: 0.0 }
+    }
+
+    /// Met when `metric` falls below `threshold`
+    pub fn below(metric: Metric, threshold: f64) -> Condition {
+        Condition { metric, direction: Direction::Below, threshold, hysteresis: 0.0 }
+    }
+
+    /// Distance the value has to move back past the threshold to re-arm
+    pub fn with_hysteresis(self, hysteresis: f64) -> Condition {
+        Condition { hysteresis: hysteresis.abs(), ..self }
+    }
+
+    fn is_met(&self, value: f64) -> bool {
+        match self.direction {
+            Direction::Above => value > self.threshold,
+            Direction::Below => value < self.threshold,
+        }
+    }
+
+    fn is_reset(&self, value: f64) -> bool {
+        match self.direction {
+            Direction::Above => value <= self.threshold - self.hysteresis,
+            Direction::Below => value >= self.threshold + self.hysteresis,
+        }
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let direction = match self.direction {
+            Direction::Above => ">",
+            Direction::Below => "<",
+        };
+        write!(f, "{} {} {}", self.metric, direction, self.threshold)
+    }
+}
+
+/// Identifies an alert registered with `AlertEngine::add`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct AlertId(u64);
+
+/// A triggered alert
+#[derive(Debug, Clone, PartialEq)]
+pub struct AlertEvent {
+    pub id: AlertId,
+    pub instrument_token: u32,
+    pub condition: Condition,
+    /// Value of the metric that met the condition
+    pub value: f64,
+    pub tick: Tick,
+}
+
+struct Alert {
+    id: AlertId,
+    condition: Condition,
+    armed: bool,
+}
+
+/// Evaluates registered conditions against ticks and emits an `AlertEvent`
+/// each time one is met. Metrics a tick doesn't carry, like open interest
+/// in quote mode, leave the alert untouched.
+///
+/// It implements `KiteTickerHandler`, so it can be passed to
+/// `KiteTicker::connect` directly or fed from any handler with `update`.
+pub struct AlertEngine {
+    alerts: HashMap<u32, Vec<Alert>>,
+    oi_open: HashMap<u32, u32>,
+    next_id: u64,
+    emit: Box<dyn FnMut(AlertEvent) + Send>,
+}
+
+impl AlertEngine {
+    /// Calls `emit` with every triggered alert
+    pub fn new<F>(emit: F) -> AlertEngine
+    where F: FnMut(AlertEvent) + Send + 'static {
+        AlertEngine {
+            alerts: HashMap::new(),
+            oi_open: HashMap::new(),
+            next_id: 0,
+            emit: Box::new(emit),
+        }
+    }
+
+    /// Sends triggered alerts through a channel instead of a callback
+    pub fn channel() -> (AlertEngine, mpsc::Receiver<AlertEvent>) {
+        let (sender, receiver) = mpsc::channel();
+        let engine = AlertEngine::new(move |event| {
+            // Alerts are dropped once the receiver is gone
+            let _ = sender.send(event);
+        });
+        (engine, receiver)
+    }
+
+    /// Watches `instrument_token` for `condition`
+    pub fn add(&mut self, instrument_token: u32, condition: Condition) -> AlertId {
+        let id = AlertId(self.next_id);
+        self.next_id += 1;
+        self.alerts.entry(instrument_token).or_default().push(Alert { id, condition, armed: true });
+        id
+    }
+
+    /// Stops watching an alert. Returns whether it was registered.
+    pub fn remove(&mut self, id: AlertId) -> bool {
+        for alerts in self.alerts.values_mut() {
+            if let Some(index) = alerts.iter().position(|alert| alert.id == id) {
+                alerts.remove(index);
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Tokens with at least one alert, e.g. to subscribe to
+    pub fn tokens(&self) -> Vec<u32> {
+        self.alerts.iter()
+            .filter(|(_, alerts)| !alerts.is_empty())
+            .map(|(token, _)| *token)
+            .collect()
+    }
+
+    /// Open interest `Metric::OiChange` is measured from. Defaults to the
+    /// open interest of the first full mode tick of the instrument.
+    pub fn set_oi_open(&mut self, instrument_token: u32, oi: u32) {
+        self.oi_open.insert(instrument_token, oi);
+    }
+
+    /// Evaluates the alerts of the tick's instrument
+    pub fn update(&mut self, tick: &Tick) {
+        let alerts = match self.alerts.get_mut(&tick.instrument_token) {
+            Some(alerts) => alerts,
+            None => return,
+        };
+        if tick.mode == Mode::Full && tick.oi > 0 {
+            self.oi_open.entry(tick.instrument_token).or_insert(tick.oi);
+        }
+        let oi_open = self.oi_open.get(&tick.instrument_token).cloned();
+
+        for alert in alerts.iter_mut() {
+            let value = match alert.condition.metric.value(tick, oi_open) {
+                Some(value) => value,
+                None => continue,
+            };
+
+            if alert.armed && alert.condition.is_met(value) {
+                alert.armed = false;
+                (self.emit)(AlertEvent {
+                    id: alert.id,
+                    instrument_token: tick.instrument_token,
+                    condition: alert.condition,
+                    value,
+                    tick: tick.clone(),
+                });
+            } else if !alert.armed && alert.condition.is_reset(value) {
+                alert.armed = true;
+            }
+        }
+    }
+}
+
+impl KiteTickerHandler for AlertEngine {
+    fn on_ticks<T>(&mut self, _ws: &mut WebSocketHandler<T>, ticks: Vec<Tick>)
+    where T: KiteTickerHandler {
+        for tick in ticks.iter() {
+            self.update(tick);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ticker::Ohlc;
+
+    fn tick(last_price: f64) -> Tick {
+        Tick {
+            mode: Mode::Quote,
+            instrument_token: 408065,
+            last_price,
+            ohlc: Ohlc { open: 2500.0, high: 2600.0, low: 2400.0, close: 2500.0 },
+            change: (last_price - 2500.0) * 100_f64 / 2500.0,
+            ..Tick::default()
+        }
+    }
+
+    #[test]
+    fn test_hysteresis() {
+        let (mut engine, alerts) = AlertEngine::channel();
+        let id = engine.add(408065, Condition::above(Metric::LastPrice, 2550.0).with_hysteresis(10.0));
+        let change = engine.add(408065, Condition::below(Metric::Change, -3.0));
+
+        engine.update(&tick(2549.0));
+        engine.update(&tick(2551.0));
+        let event = alerts.try_recv().unwrap();
+        assert_eq!((event.id, event.value), (id, 2551.0));
+
+        // Oscillating within the hysteresis band doesn't re-trigger
+        engine.update(&tick(2545.0));
+        engine.update(&tick(2552.0));
+        assert!(alerts.try_recv().is_err());
+
+        engine.update(&tick(2540.0));
+        engine.update(&tick(2560.0));
+        assert_eq!(alerts.try_recv().unwrap().id, id);
+
+        engine.update(&tick(2400.0));
+        assert_eq!(alerts.try_recv().unwrap().id, change);
+
+        // Other instruments and removed alerts are ignored
+        engine.update(&Tick { instrument_token: 884737, ..tick(2600.0) });
+        assert!(engine.remove(id));
+        assert!(!engine.remove(id));
+        engine.update(&tick(2600.0));
+        assert!(alerts.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_oi_change() {
+        let (mut engine, alerts) = AlertEngine::channel();
+        engine.add(408065, Condition::above(Metric::OiChange, 10.0));
+
+        let full = |oi| Tick { mode: Mode::Full, oi, ..tick(2500.0) };
+        engine.update(&full(1000));
+        engine.update(&full(1100));
+        // Ticks without open interest are skipped
+        engine.update(&Tick { oi: 5000, ..tick(2500.0) });
+        assert!(alerts.try_recv().is_err());
+
+        engine.update(&full(1101));
+        let event = alerts.try_recv().unwrap();
+        assert!((event.value - 10.1).abs() < 1e-9);
+        assert_eq!(event.condition.to_string(), "oi_change > 10");
+        assert_eq!(engine.tokens(), vec![408065]);
+    }
+}
@@ -0,0 +1,68 @@
+//! Holds a single ticker connection and re-serves its ticks to local
+//! clients over websocket and server-sent events.
+//!
+//! ```text
+//! KITE_API_KEY=.. KITE_ACCESS_TOKEN=.. kite-bridge --ws 127.0.0.1:8765 --sse 127.0.0.1:8766
+//! ```
+#![allow(clippy::result_large_err)]
+extern crate kiteconnect;
+
+use std::env;
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+use kiteconnect::ticker::{KiteTicker, TickBridge};
+
+const USAGE: &str = "Usage: kite-bridge [--ws ADDRESS] [--sse ADDRESS] [--uri URI]
+
+Reads the credentials from KITE_API_KEY and KITE_ACCESS_TOKEN.
+
+  --ws ADDRESS   websocket listen address (default 127.0.0.1:8765)
+  --sse ADDRESS  server-sent events listen address (default 127.0.0.1:8766)
+  --uri URI      upstream ticker (default ws.kite.trade)";
+
+/// Delay between reconnects once the upstream connection drops
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+fn fail(message: &str) -> ! {
+    eprintln!("{}\n\n{}", message, USAGE);
+    process::exit(2);
+}
+
+fn main() {
+    let mut ws_address = "127.0.0.1:8765".to_string();
+    let mut sse_address = "127.0.0.1:8766".to_string();
+    let mut uri = None;
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().unwrap_or_else(|| fail(&format!("Missing value for {}", arg)));
+        match arg.as_str() {
+            "--ws" => ws_address = value(),
+            "--sse" => sse_address = value(),
+            "--uri" => uri = Some(value()),
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            },
+            _ => fail(&format!("Unknown argument {}", arg)),
+        }
+    }
+
+    let api_key = env::var("KITE_API_KEY").unwrap_or_else(|_| fail("KITE_API_KEY is not set"));
+    let access_token = env::var("KITE_ACCESS_TOKEN").unwrap_or_else(|_| fail("KITE_ACCESS_TOKEN is not set"));
+
+    let bridge = TickBridge::new();
+    let ws = bridge.listen_ws(ws_address.as_str()).unwrap_or_else(|err| fail(&format!("Unable to listen on {}: {}", ws_address, err)));
+    let sse = bridge.listen_sse(sse_address.as_str()).unwrap_or_else(|err| fail(&format!("Unable to listen on {}: {}", sse_address, err)));
+    println!("Serving ticks on ws://{} and http://{}/events", ws, sse);
+
+    let mut ticker = KiteTicker::new(&api_key, &access_token);
+    loop {
+        let result = ticker.connect_dyn(Box::new(bridge.clone()), uri.as_deref())
+            .and_then(|_| ticker.join());
+        eprintln!("Ticker disconnected ({:?}), reconnecting in {:?}", result, RECONNECT_DELAY);
+        thread::sleep(RECONNECT_DELAY);
+    }
+}
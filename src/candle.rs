@@ -0,0 +1,408 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::mpsc;
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Duration, FixedOffset, NaiveTime, TimeZone};
+use log::debug;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+
+use crate::ticker::{ist, KiteTickerHandler, Tick, WebSocketHandler};
+
+/// Candle intervals supported by the historical data API
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Interval {
+    Minute,
+    ThreeMinute,
+    FiveMinute,
+    TenMinute,
+    FifteenMinute,
+    ThirtyMinute,
+    SixtyMinute,
+    Day,
+}
+
+impl Interval {
+    /// Value used by the historical data API
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Interval::Minute => "minute",
+            Interval::ThreeMinute => "3minute",
+            Interval::FiveMinute => "5minute",
+            Interval::TenMinute => "10minute",
+            Interval::FifteenMinute => "15minute",
+            Interval::ThirtyMinute => "30minute",
+            Interval::SixtyMinute => "60minute",
+            Interval::Day => "day",
+        }
+    }
+
+    /// Length of a single bar
+    pub fn duration(&self) -> Duration {
+        match *self {
+            Interval::Minute => Duration::minutes(1),
+            Interval::ThreeMinute => Duration::minutes(3),
+            Interval::FiveMinute => Duration::minutes(5),
+            Interval::TenMinute => Duration::minutes(10),
+            Interval::FifteenMinute => Duration::minutes(15),
+            Interval::ThirtyMinute => Duration::minutes(30),
+            Interval::SixtyMinute => Duration::minutes(60),
+            Interval::Day => Duration::days(1),
+        }
+    }
+
+    /// Start of the bar `time` falls in. Intraday bars are aligned to the
+    /// session open in IST, day bars start at midnight IST like historical
+    /// day candles.
+    pub fn bar_start(&self, time: DateTime<FixedOffset>, session_open: NaiveTime) -> DateTime<FixedOffset> {
+        let time = time.with_timezone(&ist());
+        let date = time.date_naive();
+        if *self == Interval::Day {
+            return ist().from_local_datetime(&date.and_time(NaiveTime::MIN)).unwrap();
+        }
+
+        let open = ist().from_local_datetime(&date.and_time(session_open)).unwrap();
+        let length = self.duration().num_seconds();
+        let bars = (time - open).num_seconds().div_euclid(length);
+        open + Duration::seconds(bars * length)
+    }
+}
+
+impl fmt::Display for Interval {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Interval {
+    type Err = anyhow::Error;
+
+    fn from_str(interval: &str) -> Result<Interval> {
+        match interval {
+            "minute" => Ok(Interval::Minute),
+            "3minute" => Ok(Interval::ThreeMinute),
+            "5minute" => Ok(Interval::FiveMinute),
+            "10minute" => Ok(Interval::TenMinute),
+            "15minute" => Ok(Interval::FifteenMinute),
+            "30minute" => Ok(Interval::ThirtyMinute),
+            "60minute" => Ok(Interval::SixtyMinute),
+            "day" => Ok(Interval::Day),
+            _ => Err(anyhow!("Unknown candle interval {:?}", interval)),
+        }
+    }
+}
+
+/// OHLCV bar, as returned by the historical data API and built from ticks
+/// by `CandleAggregator`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Candle {
+    /// Start of the bar in IST
+    pub timestamp: DateTime<FixedOffset>,
+    pub open: f64,
+    pub high: f64,
+    pub low: f64,
+    pub close: f64,
+    pub volume: u64,
+    /// Open interest at the close of the bar, if requested or streamed
+    pub oi: Option<u64>,
+}
+
+impl Candle {
+    fn new(timestamp: DateTime<FixedOffset>, price: f64) -> Candle {
+        Candle {
+            timestamp,
+            open: price,
+            high: price,
+            low: price,
+            close: price,
+            volume: 0,
+            oi: None,
+        }
+    }
+
+    /// Parses the candles of a `historical_data` response, given either the
+    /// whole response or its `data` object
+    pub fn from_historical(response: &JsonValue) -> Result<Vec<Candle>> {
+        let candles = match response.get("data") {
+            Some(data) => &data["candles"],
+            None => &response["candles"],
+        };
+        let candles = candles.as_array().ok_or_else(|| anyhow!("No candles in the response"))?;
+
+        candles.iter().map(|candle| {
+            let number = |index: usize| candle[index].as_f64()
+                .ok_or_else(|| anyhow!("Invalid candle {}", candle));
+            let timestamp = candle[0].as_str().ok_or_else(|| anyhow!("Invalid candle {}", candle))?;
+
+            Ok(Candle {
+                timestamp: DateTime::parse_from_str(timestamp, "%Y-%m-%dT%H:%M:%S%z")
+                    .with_context(|| format!("Invalid candle timestamp {:?}", timestamp))?,
+                open: number(1)?,
+                high: number(2)?,
+                low: number(3)?,
+                close: number(4)?,
+                volume: number(5)? as u64,
+                oi: candle.get(6).and_then(|oi| oi.as_f64()).map(|oi| oi as u64),
+            })
+        }).collect()
+    }
+}
+
+/// A bar closed by `CandleAggregator`
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompletedCandle {
+    pub instrument_token: u32,
+    pub interval: Interval,
+    pub candle: Candle,
+}
+
+/// Builds OHLCV bars of several intervals from ticks. Bar volume is the
+/// increase of the cumulative day `volume` between ticks, so the ticks
+/// preceding the first one seen for an instrument don't count. A bar is
+/// emitted once a tick of a later bar arrives or on `flush`. A late tick for
+/// a bar already emitted only adds its volume to the bar being built.
+///
+/// It implements `KiteTickerHandler`, so it can be passed to
+/// `KiteTicker::connect` directly or fed from any handler with `update`.
+pub struct CandleAggregator {
+    intervals: Vec<Interval>,
+    session_open: NaiveTime,
+    bars: HashMap<(u32, Interval), Candle>,
+    volumes: HashMap<u32, u32>,
+    emit: Box<dyn FnMut(CompletedCandle) + Send>,
+}
+
+impl CandleAggregator {
+    /// Calls `emit` with every completed bar
+    pub fn new<F>(intervals: Vec<Interval>, emit: F) -> CandleAggregator
+    where F: FnMut(CompletedCandle) + Send + 'static {
+        CandleAggregator {
+            intervals,
+            session_open: NaiveTime::from_hms_opt(9, 15, 0).unwrap(),
+            bars: HashMap::new(),
+            volumes: HashMap::new(),
+            emit: Box::new(emit),
+        }
+    }
+
+    /// Sends completed bars through a channel instead of a callback
+    pub fn channel(intervals: Vec<Interval>) -> (CandleAggregator, mpsc::Receiver<CompletedCandle>) {
+        let (sender, receiver) = mpsc::channel();
+        let aggregator = CandleAggregator::new(intervals, move |candle| {
+            // Bars are dropped once the receiver is gone
+            let _ = sender.send(candle);
+        });
+        (aggregator, receiver)
+    }
+
+    /// Aligns intraday bars to `session_open` IST instead of the equity
+    /// market open at 09:15, e.g. 09:00 for commodities
+    pub fn set_session_open(&mut self, session_open: NaiveTime) {
+        self.session_open = session_open;
+    }
+
+    /// Adds a tick, timestamped by the exchange. Ticks without a timestamp,
+    /// such as ltp mode ticks, are placed at the current time.
+    pub fn update(&mut self, tick: &Tick) {
+        let time = tick.exchange_timestamp
+            .or(tick.last_trade_time)
+            .unwrap_or_else(|| chrono::Utc::now().with_timezone(&ist()));
+        self.update_at(time, tick);
+    }
+
+    /// Adds a tick at the given time
+    pub fn update_at(&mut self, time: DateTime<FixedOffset>, tick: &Tick) {
+        let token = tick.instrument_token;
+        let traded = match self.volumes.insert(token, tick.volume) {
+            // A lower cumulative volume means a new session has started
+            Some(previous) if tick.volume >= previous => (tick.volume - previous) as u64,
+            Some(_) => tick.volume as u64,
+            None => 0,
+        };
+
+        for index in 0..self.intervals.len() {
+            let interval = self.intervals[index];
+            let start = interval.bar_start(time, self.session_open);
+            let key = (token, interval);
+
+            let completed = match self.bars.get_mut(&key) {
+                Some(bar) if start < bar.timestamp => {
+                    // Its bar is closed, the volume traded since the last
+                    // tick goes to the bar being built
+                    debug!("Ignoring the price of late tick of {} at {}", token, time);
+                    bar.volume += traded;
+                    continue;
+                },
+                Some(bar) if start > bar.timestamp => self.bars.remove(&key),
+                _ => None,
+            };
+            if let Some(candle) = completed {
+                (self.emit)(CompletedCandle { instrument_token: token, interval, candle });
+            }
+
+            let bar = self.bars.entry(key).or_insert_with(|| Candle::new(start, tick.last_price));
+            bar.high = bar.high.max(tick.last_price);
+            bar.low = bar.low.min(tick.last_price);
+            bar.close = tick.last_price;
+            bar.volume += traded;
+            if tick.oi > 0 {
+                bar.oi = Some(tick.oi as u64);
+            }
+        }
+    }
+
+    /// Emits the bars that end at or before `now`, for instruments that
+    /// haven't ticked since. Call it periodically to close bars on time.
+    pub fn flush(&mut self, now: DateTime<FixedOffset>) {
+        let mut ended: Vec<(u32, Interval)> = self.bars.iter()
+            .filter(|(key, bar)| bar.timestamp + key.1.duration() <= now)
+            .map(|(key, _)| *key)
+            .collect();
+        ended.sort_by_key(|key| (self.bars[key].timestamp, key.1, key.0));
+
+        for key in ended {
+            if let Some(candle) = self.bars.remove(&key) {
+                (self.emit)(CompletedCandle { instrument_token: key.0, interval: key.1, candle });
+            }
+        }
+    }
+
+    /// Emits every bar still being built, complete or not
+    pub fn finish(&mut self) {
+        let mut keys: Vec<(u32, Interval)> = self.bars.keys().cloned().collect();
+        keys.sort_by_key(|key| (self.bars[key].timestamp, key.1, key.0));
+        for key in keys {
+            if let Some(candle) = self.bars.remove(&key) {
+                (self.emit)(CompletedCandle { instrument_token: key.0, interval: key.1, candle });
+            }
+        }
+    }
+
+    /// Bar currently being built for `instrument_token`
+    pub fn current(&self, instrument_token: u32, interval: Interval) -> Option<&Candle> {
+        self.bars.get(&(instrument_token, interval))
+    }
+}
+
+impl KiteTickerHandler for CandleAggregator {
+    fn on_ticks<T>(&mut self, _ws: &mut WebSocketHandler<T>, ticks: Vec<Tick>)
+    where T: KiteTickerHandler {
+        for tick in ticks.iter() {
+            self.update(tick);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn at(hour: u32, minute: u32, second: u32) -> DateTime<FixedOffset> {
+        ist().with_ymd_and_hms(2020, 1, 1, hour, minute, second).unwrap()
+    }
+
+    fn tick(time: DateTime<FixedOffset>, last_price: f64, volume: u32) -> Tick {
+        Tick {
+            instrument_token: 408065,
+            last_price,
+            volume,
+            exchange_timestamp: Some(time),
+            ..Tick::default()
+        }
+    }
+
+    #[test]
+    fn test_bar_alignment() {
+        let open = NaiveTime::from_hms_opt(9, 15, 0).unwrap();
+        assert_eq!(Interval::Minute.bar_start(at(9, 15, 59), open), at(9, 15, 0));
+        assert_eq!(Interval::ThreeMinute.bar_start(at(9, 20, 0), open), at(9, 18, 0));
+        assert_eq!(Interval::SixtyMinute.bar_start(at(15, 29, 0), open), at(15, 15, 0));
+        // Pre-open ticks fall in bars counted back from the open
+        assert_eq!(Interval::FiveMinute.bar_start(at(9, 8, 0), open), at(9, 5, 0));
+        assert_eq!(Interval::Day.bar_start(at(15, 29, 0), open), at(0, 0, 0));
+        // Times in other zones are aligned in IST
+        let utc = at(9, 16, 30).with_timezone(&FixedOffset::east_opt(0).unwrap());
+        assert_eq!(Interval::Minute.bar_start(utc, open), at(9, 16, 0));
+
+        assert_eq!("5minute".parse::<Interval>().unwrap(), Interval::FiveMinute);
+        assert!("2minute".parse::<Interval>().is_err());
+    }
+
+    #[test]
+    fn test_aggregate_ticks() {
+        let (mut aggregator, candles) = CandleAggregator::channel(vec![Interval::Minute, Interval::FiveMinute]);
+        aggregator.update(&tick(at(9, 15, 10), 100.0, 1000));
+        aggregator.update(&tick(at(9, 15, 40), 102.0, 1050));
+        aggregator.update(&tick(at(9, 15, 50), 99.0, 1060));
+        assert!(candles.try_recv().is_err());
+
+        aggregator.update(&tick(at(9, 16, 5), 101.0, 1100));
+        let completed = candles.try_recv().unwrap();
+        assert_eq!(completed.interval, Interval::Minute);
+        assert_eq!(completed.candle, Candle {
+            timestamp: at(9, 15, 0),
+            open: 100.0,
+            high: 102.0,
+            low: 99.0,
+            close: 99.0,
+            volume: 60,
+            oi: None,
+        });
+
+        // Late ticks don't reopen closed bars
+        aggregator.update(&tick(at(9, 15, 59), 50.0, 1100));
+        assert_eq!(aggregator.current(408065, Interval::Minute).unwrap().low, 101.0);
+
+        aggregator.flush(at(9, 17, 0));
+        assert_eq!(candles.try_recv().unwrap().candle.timestamp, at(9, 16, 0));
+        assert!(candles.try_recv().is_err());
+
+        // Late ticks still count in the longer bars they fall in
+        aggregator.finish();
+        let five = candles.try_recv().unwrap();
+        assert_eq!(five.interval, Interval::FiveMinute);
+        assert_eq!((five.candle.low, five.candle.close, five.candle.volume), (50.0, 50.0, 100));
+    }
+
+    #[test]
+    fn test_late_tick_volume() {
+        let (mut aggregator, candles) = CandleAggregator::channel(vec![Interval::Minute, Interval::FiveMinute]);
+        aggregator.update(&tick(at(9, 15, 10), 100.0, 1000));
+        aggregator.update(&tick(at(9, 15, 40), 100.0, 1020));
+        aggregator.update(&tick(at(9, 16, 5), 101.0, 1050));
+        assert_eq!(candles.try_recv().unwrap().candle.volume, 20);
+
+        // Late for the minute bars, on time for the five minute bar
+        aggregator.update(&tick(at(9, 15, 50), 90.0, 1070));
+        let minute = aggregator.current(408065, Interval::Minute).unwrap();
+        assert_eq!((minute.low, minute.volume), (101.0, 50));
+        let five = aggregator.current(408065, Interval::FiveMinute).unwrap();
+        assert_eq!((five.low, five.volume), (90.0, 70));
+
+        aggregator.finish();
+        let volumes: Vec<u64> = candles.try_iter().map(|completed| completed.candle.volume).collect();
+        assert_eq!(volumes, vec![70, 50]);
+    }
+
+    #[test]
+    fn test_from_historical() {
+        let response = json!({
+            "status": "success",
+            "data": {
+                "candles": [
+                    ["2017-12-15T09:15:00+0530", 1704.5, 1705, 1699.25, 1702.8, 2499],
+                    ["2017-12-15T09:16:00+0530", 1702, 1702, 1698.15, 1698.15, 1271, 3000]
+                ]
+            }
+        });
+        let candles = Candle::from_historical(&response).unwrap();
+        assert_eq!(candles.len(), 2);
+        assert_eq!(candles[0].timestamp, ist().with_ymd_and_hms(2017, 12, 15, 9, 15, 0).unwrap());
+        assert_eq!((candles[0].high, candles[0].volume, candles[0].oi), (1705.0, 2499, None));
+        assert_eq!(candles[1].oi, Some(3000));
+
+        assert!(Candle::from_historical(&json!({"data": {}})).is_err());
+    }
+}
@@ -13,18 +13,30 @@ use anyhow::{anyhow, Context, Result};
 use mockito;
 
 use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use arc_swap::ArcSwapOption;
 
 use crypto::digest::Digest;
 use crypto::sha2::Sha256;
 use csv::ReaderBuilder;
 
+use log::debug;
 use reqwest::header::{HeaderMap, AUTHORIZATION, USER_AGENT};
+use reqwest::StatusCode;
+
+use crate::candle::{Candle, Interval};
+use crate::instruments::InstrumentIndex;
+use crate::session::{AccessToken, Session, SessionStore};
+use crate::ticker::{KiteTicker, TickerPool};
 
 #[cfg(not(test))]
-const URL: &'static str = "https://api.kite.trade";
+const URL: &str = "https://api.kite.trade";
 
 #[cfg(test)]
-const URL: &'static str = mockito::SERVER_URL;
+#[allow(deprecated)]
+const URL: &str = mockito::SERVER_URL;
 
 #[allow(unused_variables)]
 trait RequestHandler {
@@ -36,18 +48,46 @@ trait RequestHandler {
     ) -> Result<reqwest::Response>;
 }
 
+/// Clones share the access token, so a token set or renewed through one of
+/// them is used by all.
+#[derive(Clone)]
 pub struct KiteConnect {
     api_key: String,
-    access_token: String,
+    access_token: AccessToken,
     session_expiry_hook: Option<fn() -> ()>,
+    renewal: Arc<ArcSwapOption<Renewal>>,
+}
+
+/// Error for requests rejected because the access token expired or was
+/// invalidated, and could not be renewed
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenException {
+    pub message: String,
+}
+
+impl fmt::Display for TokenException {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "TokenException: {}", self.message)
+    }
+}
+
+impl std::error::Error for TokenException {}
+
+/// Credentials used to renew an expired access token
+struct Renewal {
+    api_secret: String,
+    /// Also held while renewing so clones don't renew the same token twice
+    refresh_token: Mutex<String>,
+    store: Option<Arc<dyn SessionStore>>,
 }
 
 impl Default for KiteConnect {
     fn default() -> Self {
         KiteConnect {
             api_key: "<API-KEY>".to_string(),
-            access_token: "<ACCESS-TOKEN>".to_string(),
-            session_expiry_hook: None
+            access_token: AccessToken::new("<ACCESS-TOKEN>"),
+            session_expiry_hook: None,
+            renewal: Arc::new(ArcSwapOption::empty()),
         }
     }
 }
@@ -70,7 +110,7 @@ impl KiteConnect {
     pub fn new(api_key: &str, access_token: &str) -> Self {
         Self {
             api_key: api_key.to_string(),
-            access_token: access_token.to_string(),
+            access_token: AccessToken::new(access_token),
             ..Default::default()
         }
     }
@@ -92,7 +132,90 @@ impl KiteConnect {
 
     /// Sets an access token for this instance
     pub fn set_access_token(&mut self, access_token: &str) {
-        self.access_token = access_token.to_string();
+        self.access_token.set(access_token);
+    }
+
+    /// Returns the access token of this instance
+    pub fn access_token(&self) -> String {
+        self.access_token.get().to_string()
+    }
+
+    /// Renews the access token with `refresh_token` when a request fails
+    /// with a `TokenException`, then replays the request. Renewed sessions
+    /// are saved to `store`. Applies to this client and all its clones.
+    ///
+    /// Tickers from `ticker` and `ticker_pool` pick up a renewed token the
+    /// next time they connect; a live connection keeps the token it was
+    /// opened with until it drops.
+    pub fn set_auto_renewal(
+        &mut self,
+        refresh_token: &str,
+        api_secret: &str,
+        store: Option<Arc<dyn SessionStore>>
+    ) {
+        self.renewal.store(Some(Arc::new(Renewal {
+            api_secret: api_secret.to_string(),
+            refresh_token: Mutex::new(refresh_token.to_string()),
+            store,
+        })));
+    }
+
+    /// Ticker that connects, and reconnects, with the current access token
+    /// of this client
+    pub fn ticker(&self) -> KiteTicker {
+        KiteTicker::with_access_token(&self.api_key, self.access_token.clone())
+    }
+
+    /// Ticker pool that reconnects with the current access token of this
+    /// client
+    pub fn ticker_pool(&self, connections: usize) -> TickerPool {
+        TickerPool::with_access_token(&self.api_key, self.access_token.clone(), connections)
+    }
+
+    /// Renews the access token unless a clone already replaced `expired`.
+    /// Returns false if auto renewal isn't set up.
+    fn renew(&self, expired: &str) -> Result<bool> {
+        let renewal = match self.renewal.load_full() {
+            Some(renewal) => renewal,
+            None => return Ok(false),
+        };
+        let mut refresh_token = renewal.refresh_token.lock().unwrap_or_else(|err| err.into_inner());
+        if self.access_token.get().as_str() != expired {
+            return Ok(true);
+        }
+
+        let response = self.renew_access_token(&refresh_token, &renewal.api_secret)?;
+        if let Some(renewed) = response["data"]["refresh_token"].as_str().filter(|token| !token.is_empty()) {
+            *refresh_token = renewed.to_string();
+        }
+        if let Some(ref store) = renewal.store {
+            if let Err(err) = self.save_session(store.as_ref(), &response, &refresh_token) {
+                debug!("Unable to save the renewed session: {:?}", err);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Saves the session of a renewal response, or updates the stored one
+    /// if the response doesn't carry a full session
+    fn save_session(&self, store: &dyn SessionStore, response: &JsonValue, refresh_token: &str) -> Result<()> {
+        let session = match Session::from_response(response) {
+            Ok(session) => session,
+            Err(_) => {
+                let session = store.load(&self.api_key)?
+                    .ok_or_else(|| anyhow!("No stored session for {}", self.api_key))?;
+                Session {
+                    access_token: self.access_token(),
+                    login_time: chrono::Utc::now().with_timezone(&crate::ticker::ist()),
+                    ..session
+                }
+            }
+        };
+        store.save(&Session {
+            api_key: self.api_key.clone(),
+            refresh_token: refresh_token.to_string(),
+            ..session
+        })
     }
 
     /// Returns the login url
@@ -125,13 +248,49 @@ impl KiteConnect {
 
         if resp.status().is_success() {
             let jsn: JsonValue = resp.json()?;
-            self.set_access_token(jsn["data"]["access_token"].as_str().unwrap());
+            let access_token = jsn["data"]["access_token"].as_str()
+                .ok_or_else(|| anyhow!("No access token in the session response"))?;
+            self.set_access_token(access_token);
             Ok(jsn)
         } else {
             Err(anyhow!(resp.text()?))
         }
     }
 
+    /// Request for access token, returning the typed session
+    pub fn create_session(&mut self, request_token: &str, api_secret: &str) -> Result<Session> {
+        let response = self.generate_session(request_token, api_secret)?;
+        Session::from_response(&response)
+    }
+
+    /// Restores a client from the session stored for `api_key`, if it is
+    /// still valid for the current trading day. Auto renewal isn't set up,
+    /// see `restore_with_renewal`.
+    pub fn restore(api_key: &str, store: &dyn SessionStore) -> Result<Option<KiteConnect>> {
+        Ok(store.load(api_key)?
+            .filter(Session::is_valid)
+            .map(|session| KiteConnect::new(api_key, &session.access_token)))
+    }
+
+    /// Like `restore`, also setting up auto renewal with the stored refresh
+    /// token and saving renewed sessions back to `store`. Sessions past
+    /// their expiry are restored too if they carry a refresh token.
+    pub fn restore_with_renewal(
+        api_key: &str,
+        api_secret: &str,
+        store: Arc<dyn SessionStore>
+    ) -> Result<Option<KiteConnect>> {
+        let session = match store.load(api_key)? {
+            Some(session) if session.is_valid() || !session.refresh_token.is_empty() => session,
+            _ => return Ok(None),
+        };
+        let mut kiteconnect = KiteConnect::new(api_key, &session.access_token);
+        if !session.refresh_token.is_empty() {
+            kiteconnect.set_auto_renewal(&session.refresh_token, api_secret, Some(store));
+        }
+        Ok(Some(kiteconnect))
+    }
+
     /// Invalidates the access token
     pub fn invalidate_access_token(&self, access_token: &str) -> Result<reqwest::Response> {
         let url = self.build_url("/session/token", None);
@@ -141,32 +300,37 @@ impl KiteConnect {
         self.send_request(url, "DELETE", Some(data))
     }
 
-    /// Request for new access token
+    /// Request for new access token with the `refresh_token` of a session.
+    /// The new token is used by this client and its clones. Before 0.3.0
+    /// this took the access token, which the api doesn't accept.
     pub fn renew_access_token(
-        &mut self,
-        access_token: &str,
+        &self,
+        refresh_token: &str,
         api_secret: &str
     ) -> Result<JsonValue> {
-        // Create a hex digest from api key, request token, api secret
+        // Create a hex digest from api key, refresh token, api secret
         let mut sha = Sha256::new();
         sha.input_str(
-            format!("{}{}{}", self.api_key, access_token, api_secret).as_str()
+            format!("{}{}{}", self.api_key, refresh_token, api_secret).as_str()
         );
         let checksum = sha.result_str();
 
         let api_key: &str = &self.api_key.clone();
         let mut data = HashMap::new();
         data.insert("api_key", api_key);
-        data.insert("access_token", access_token);
+        data.insert("refresh_token", refresh_token);
         data.insert("checksum", checksum.as_str());
 
         let url = self.build_url("/session/refresh_token", None);
 
-        let mut resp = self.send_request(url, "POST", Some(data))?;
+        // Sent directly so a rejected refresh isn't renewed again
+        let mut resp = self.send(url, "POST", &Some(data), &self.access_token.get())?;
 
         if resp.status().is_success() {
             let jsn: JsonValue = resp.json()?;
-            self.set_access_token(jsn["access_token"].as_str().unwrap());
+            let access_token = jsn["data"]["access_token"].as_str()
+                .ok_or_else(|| anyhow!("No access token in the renewal response"))?;
+            self.access_token.set(access_token);
             Ok(jsn)
         } else {
             Err(anyhow!(resp.text()?))
@@ -185,12 +349,10 @@ impl KiteConnect {
     /// Return the account balance and cash margin details for
     /// a particular segment
     pub fn margins(&self, segment: Option<String>) -> Result<JsonValue> {
-        let url: reqwest::Url;
-        if segment.is_some() {
-            url = self.build_url(format!("/user/margins/{}", segment.unwrap().as_str()).as_str(), None)
-        } else {
-            url = self.build_url("/user/margins", None);
-        }
+        let url = match segment {
+            Some(segment) => self.build_url(format!("/user/margins/{}", segment.as_str()).as_str(), None),
+            None => self.build_url("/user/margins", None),
+        };
 
         let mut resp = self.send_request(url, "GET", None)?;
         self._raise_or_return_json(&mut resp)
@@ -221,6 +383,7 @@ impl KiteConnect {
     }
 
     /// Place an order
+    #[allow(clippy::too_many_arguments)]
     pub fn place_order(
         &self,
         exchange: &str,
@@ -245,16 +408,16 @@ impl KiteConnect {
         params.insert("transaction_type", transaction_type);
         params.insert("quantity", quantity);
         params.insert("variety", variety);
-        if price.is_some() { params.insert("price", price.unwrap()); }
-        if product.is_some() { params.insert("product", product.unwrap()); }
-        if order_type.is_some() { params.insert("order_type", order_type.unwrap()); }
-        if validity.is_some() { params.insert("validity", validity.unwrap()); }
-        if disclosed_quantity.is_some() { params.insert("disclosed_quantity", disclosed_quantity.unwrap()); }
-        if trigger_price.is_some() { params.insert("trigger_price", trigger_price.unwrap()); }
-        if squareoff.is_some() { params.insert("squareoff", squareoff.unwrap()); }
-        if stoploss.is_some() { params.insert("stoploss", stoploss.unwrap()); }
-        if trailing_stoploss.is_some() { params.insert("trailing_stoploss", trailing_stoploss.unwrap()); }
-        if tag.is_some() { params.insert("tag", tag.unwrap()); }
+        if let Some(price) = price { params.insert("price", price); }
+        if let Some(product) = product { params.insert("product", product); }
+        if let Some(order_type) = order_type { params.insert("order_type", order_type); }
+        if let Some(validity) = validity { params.insert("validity", validity); }
+        if let Some(disclosed_quantity) = disclosed_quantity { params.insert("disclosed_quantity", disclosed_quantity); }
+        if let Some(trigger_price) = trigger_price { params.insert("trigger_price", trigger_price); }
+        if let Some(squareoff) = squareoff { params.insert("squareoff", squareoff); }
+        if let Some(stoploss) = stoploss { params.insert("stoploss", stoploss); }
+        if let Some(trailing_stoploss) = trailing_stoploss { params.insert("trailing_stoploss", trailing_stoploss); }
+        if let Some(tag) = tag { params.insert("tag", tag); }
 
         let url = self.build_url(format!("/orders/{}", variety).as_str(), None);
 
@@ -263,6 +426,7 @@ impl KiteConnect {
     }
 
     /// Modify an open order
+    #[allow(clippy::too_many_arguments)]
     pub fn modify_order(
         &self,
         order_id: &str,
@@ -282,17 +446,17 @@ impl KiteConnect {
         let mut params = HashMap::new();
         params.insert("order_id", order_id);
         params.insert("variety", variety);
-        if parent_order_id.is_some() { params.insert("parent_order_id", parent_order_id.unwrap()); }
-        if exchange.is_some() { params.insert("exchange", exchange.unwrap()); }
-        if tradingsymbol.is_some() { params.insert("tradingsymbol", tradingsymbol.unwrap()); }
-        if transaction_type.is_some() { params.insert("transaction_type", transaction_type.unwrap()); }
-        if quantity.is_some() { params.insert("quantity", quantity.unwrap()); }
-        if price.is_some() { params.insert("price", price.unwrap()); }
-        if order_type.is_some() { params.insert("order_type", order_type.unwrap()); }
-        if product.is_some() { params.insert("product", product.unwrap()); }
-        if trigger_price.is_some() { params.insert("trigger_price", trigger_price.unwrap()); }
-        if validity.is_some() { params.insert("validity", validity.unwrap()); }
-        if disclosed_quantity.is_some() { params.insert("disclosed_quantity", disclosed_quantity.unwrap()); }
+        if let Some(parent_order_id) = parent_order_id { params.insert("parent_order_id", parent_order_id); }
+        if let Some(exchange) = exchange { params.insert("exchange", exchange); }
+        if let Some(tradingsymbol) = tradingsymbol { params.insert("tradingsymbol", tradingsymbol); }
+        if let Some(transaction_type) = transaction_type { params.insert("transaction_type", transaction_type); }
+        if let Some(quantity) = quantity { params.insert("quantity", quantity); }
+        if let Some(price) = price { params.insert("price", price); }
+        if let Some(order_type) = order_type { params.insert("order_type", order_type); }
+        if let Some(product) = product { params.insert("product", product); }
+        if let Some(trigger_price) = trigger_price { params.insert("trigger_price", trigger_price); }
+        if let Some(validity) = validity { params.insert("validity", validity); }
+        if let Some(disclosed_quantity) = disclosed_quantity { params.insert("disclosed_quantity", disclosed_quantity); }
 
         let url = self.build_url(format!("/orders/{}/{}", variety, order_id).as_str(), None);
 
@@ -310,7 +474,7 @@ impl KiteConnect {
         let mut params = HashMap::new();
         params.insert("order_id", order_id);
         params.insert("variety", variety);
-        if parent_order_id.is_some() { params.insert("parent_order_id", parent_order_id.unwrap()); }
+        if let Some(parent_order_id) = parent_order_id { params.insert("parent_order_id", parent_order_id); }
         let url = self.build_url(format!("/orders/{}/{}", variety, order_id).as_str(), None);
 
         let mut resp = self.send_request(url, "DELETE", Some(params))?;
@@ -337,8 +501,7 @@ impl KiteConnect {
 
     /// Get the list of order history
     pub fn order_history(&self, order_id: &str) -> Result<JsonValue> {
-        let mut params: Vec<(&str, &str)> = Vec::new();
-        params.push(("order_id", order_id));
+        let params: Vec<(&str, &str)> = vec![("order_id", order_id)];
 
         let url = self.build_url("/orders", Some(params));
 
@@ -363,6 +526,7 @@ impl KiteConnect {
     }
 
     /// Modify an open position product type
+    #[allow(clippy::too_many_arguments)]
     pub fn convert_position(
         &self,
         exchange: &str,
@@ -390,12 +554,10 @@ impl KiteConnect {
 
     /// Get all mutual fund orders or individual order info
     pub fn mf_orders(&self, order_id: Option<&str>) -> Result<JsonValue> {
-        let url: reqwest::Url;
-        if order_id.is_some() {
-            url = self.build_url(format!("/mf/orders/{}", order_id.unwrap()).as_str(), None);
-        } else {
-            url = self.build_url("/mf/orders", None);
-        }
+        let url = match order_id {
+            Some(order_id) => self.build_url(format!("/mf/orders/{}", order_id).as_str(), None),
+            None => self.build_url("/mf/orders", None),
+        };
 
         let mut resp = self.send_request(url, "GET", None)?;
         self._raise_or_return_json(&mut resp)
@@ -413,9 +575,9 @@ impl KiteConnect {
         let mut params = HashMap::new();
         params.insert("tradingsymbol", tradingsymbol);
         params.insert("transaction_type", transaction_type);
-        if quantity.is_some() { params.insert("quantity", quantity.unwrap()); }
-        if amount.is_some() { params.insert("amount", amount.unwrap()); }
-        if tag.is_some() { params.insert("tag", tag.unwrap()); }
+        if let Some(quantity) = quantity { params.insert("quantity", quantity); }
+        if let Some(amount) = amount { params.insert("amount", amount); }
+        if let Some(tag) = tag { params.insert("tag", tag); }
 
         let url = self.build_url("/mf/orders", None);
 
@@ -433,18 +595,17 @@ impl KiteConnect {
 
     /// Get list of mutual fund SIP's or individual SIP info
     pub fn mf_sips(&self, sip_id: Option<&str>) -> Result<JsonValue> {
-        let url: reqwest::Url;
-        if sip_id.is_some() {
-            url = self.build_url(format!("/mf/sips/{}", sip_id.unwrap()).as_str(), None);
-        } else {
-            url = self.build_url("/mf/sips", None);
-        }
+        let url = match sip_id {
+            Some(sip_id) => self.build_url(format!("/mf/sips/{}", sip_id).as_str(), None),
+            None => self.build_url("/mf/sips", None),
+        };
 
         let mut resp = self.send_request(url, "GET", None)?;
         self._raise_or_return_json(&mut resp)
     }
 
     /// Place a mutual fund SIP
+    #[allow(clippy::too_many_arguments)]
     pub fn place_mf_sip(
         &self,
         tradingsymbol: &str,
@@ -460,9 +621,9 @@ impl KiteConnect {
         params.insert("amount", amount);
         params.insert("instalments", instalments);
         params.insert("frequency", frequency);
-        if initial_amount.is_some() { params.insert("initial_amount", initial_amount.unwrap()); }
-        if instalment_day.is_some() { params.insert("instalment_day", instalment_day.unwrap()); }
-        if tag.is_some() { params.insert("tag", tag.unwrap()); }
+        if let Some(initial_amount) = initial_amount { params.insert("initial_amount", initial_amount); }
+        if let Some(instalment_day) = instalment_day { params.insert("instalment_day", instalment_day); }
+        if let Some(tag) = tag { params.insert("tag", tag); }
 
         let url = self.build_url("/mf/sips", None);
 
@@ -486,7 +647,7 @@ impl KiteConnect {
         params.insert("status", status);
         params.insert("instalments", instalments);
         params.insert("frequency", frequency);
-        if instalment_day.is_some() { params.insert("instalment_day", instalment_day.unwrap()); }
+        if let Some(instalment_day) = instalment_day { params.insert("instalment_day", instalment_day); }
 
         let url = self.build_url(format!("/mf/sips/{}", sip_id).as_str(), None);
 
@@ -545,15 +706,7 @@ impl KiteConnect {
 
     /// Retrieve the list of market instruments available to trade
     pub fn instruments(&self, exchange: Option<&str>) -> Result<JsonValue> {
-        let url: reqwest::Url;
-        if exchange.is_some() {
-            url = self.build_url(format!("/instruments/{}", exchange.unwrap()).as_str(), None);
-        } else {
-            url = self.build_url("/instruments", None);
-        }
-
-        let mut resp: reqwest::Response = self.send_request(url, "GET", None).unwrap();
-        let content: String = resp.text().unwrap();
+        let content: String = self.instruments_csv(exchange)?;
         let mut csv_reader = ReaderBuilder::new().from_reader(content.as_bytes());
         let mut instruments: Vec<JsonValue> = Vec::new();
         for record in csv_reader.records() {
@@ -577,6 +730,24 @@ impl KiteConnect {
         Ok(json!(instruments))
     }
 
+    /// Retrieve the instruments as an index that resolves
+    /// `EXCHANGE:TRADINGSYMBOL` strings to instrument tokens
+    pub fn instrument_index(&self, exchange: Option<&str>) -> Result<InstrumentIndex> {
+        let content: String = self.instruments_csv(exchange)?;
+        InstrumentIndex::from_csv(content.as_bytes())
+    }
+
+    /// Raw CSV dump of the instruments, optionally for one exchange
+    fn instruments_csv(&self, exchange: Option<&str>) -> Result<String> {
+        let url = match exchange {
+            Some(exchange) => self.build_url(format!("/instruments/{}", exchange).as_str(), None),
+            None => self.build_url("/instruments", None),
+        };
+
+        let mut resp: reqwest::Response = self.send_request(url, "GET", None)?;
+        resp.text().context("Unable to read the instruments")
+    }
+
     /// Retrieve quote for list of instruments
     pub fn quote(&self, instruments: Vec<&str>) -> Result<JsonValue> {
         let params: Vec<_> = instruments.into_iter().map(|i| ("i", i)).collect();
@@ -627,7 +798,7 @@ impl KiteConnect {
         params.insert("to", to_date);
         params.insert("interval", interval);
         params.insert("continuos", continuos);
-        let params = params.into_iter().map(|(k, v)| (k, v)).collect();
+        let params = params.into_iter().collect();
         let url = self.build_url(
             format!("/instruments/historical/{}/{}", instrument_token, interval).as_str(),
             Some(params),
@@ -637,6 +808,19 @@ impl KiteConnect {
         self._raise_or_return_json(&mut resp)
     }
 
+    /// Retrieve historical data of an instrument as typed candles
+    pub fn historical_candles(
+        &self,
+        instrument_token: &str,
+        from_date: &str,
+        to_date: &str,
+        interval: Interval,
+        continuos: &str,
+    ) -> Result<Vec<Candle>> {
+        let data = self.historical_data(instrument_token, from_date, to_date, interval.as_str(), continuos)?;
+        Candle::from_historical(&data)
+    }
+
     pub fn trigger_range(&self, transaction_type: &str, instruments: Vec<&str>) -> Result<JsonValue> {
         let params: Vec<_> = instruments.into_iter().map(|i| ("i", i)).collect();
         let url = self.build_url(format!("/instruments/trigger_range/{}", transaction_type).as_str(), Some(params));
@@ -648,25 +832,61 @@ impl KiteConnect {
 
 /// Implement the request handler for kiteconnect struct
 impl RequestHandler for KiteConnect {
-    // Generic request builder
+    // Generic request builder. Requests failing with an expired token are
+    // replayed once the token is renewed, if auto renewal is set up.
     fn send_request(
         &self,
         url: reqwest::Url,
         method: &str,
         data: Option<HashMap<&str, &str>>,
+    ) -> Result<reqwest::Response> {
+        let access_token = self.access_token.get();
+        let mut resp = self.send(url.clone(), method, &data, &access_token)?;
+        if resp.status() != StatusCode::FORBIDDEN
+            || (self.session_expiry_hook.is_none() && self.renewal.load().is_none()) {
+            return Ok(resp);
+        }
+
+        // Telling an expired token apart from other permission errors takes
+        // the body, so those fail with it just like `_raise_or_return_json`
+        let body = resp.text()?;
+        let error: JsonValue = serde_json::from_str(&body).unwrap_or_default();
+        if error["error_type"] != "TokenException" {
+            return Err(anyhow!(body));
+        }
+        let message = error["message"].as_str().unwrap_or_default().to_string();
+        match self.renew(&access_token) {
+            Ok(true) => return self.send(url, method, &data, &self.access_token.get()),
+            Ok(false) => {},
+            Err(err) => debug!("Unable to renew the access token: {:?}", err),
+        }
+        if let Some(hook) = self.session_expiry_hook {
+            hook();
+        }
+        Err(TokenException { message }.into())
+    }
+}
+
+impl KiteConnect {
+    fn send(
+        &self,
+        url: reqwest::Url,
+        method: &str,
+        data: &Option<HashMap<&str, &str>>,
+        access_token: &str,
     ) -> Result<reqwest::Response> {
         let mut headers = HeaderMap::new();
         headers.insert("XKiteVersion", "3".parse().unwrap());
-        headers.insert(AUTHORIZATION, format!("token {}:{}", self.api_key, self.access_token).parse().unwrap());
+        headers.insert(AUTHORIZATION, format!("token {}:{}", self.api_key, access_token).parse().unwrap());
         headers.insert(USER_AGENT, "Rust".parse().unwrap());
 
         let client = reqwest::Client::new();
 
         match method {
             "GET" => Ok(client.get(url).headers(headers).send()?),
-            "POST" => Ok(client.post(url).headers(headers).form(&data).send()?),
-            "DELETE" => Ok(client.delete(url).headers(headers).json(&data).send()?),
-            "PUT" => Ok(client.put(url).headers(headers).form(&data).send()?),
+            "POST" => Ok(client.post(url).headers(headers).form(data).send()?),
+            "DELETE" => Ok(client.delete(url).headers(headers).json(data).send()?),
+            "PUT" => Ok(client.put(url).headers(headers).form(data).send()?),
             _ => Err(anyhow!("Unknown method!")),
         }
     }
@@ -678,6 +898,8 @@ impl RequestHandler for KiteConnect {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use crate::session::MemorySessionStore;
 
     #[test]
     fn test_build_url() {
@@ -685,8 +907,7 @@ mod tests {
         let url = kiteconnect.build_url("/my-holdings", None);
         assert_eq!(url.as_str(), format!("{}/my-holdings", URL).as_str());
 
-        let mut params: Vec<(&str, &str)> = Vec::new();
-        params.push(("one", "1"));
+        let params: Vec<(&str, &str)> = vec![("one", "1")];
         let url = kiteconnect.build_url("/my-holdings", Some(params));
         assert_eq!(url.as_str(), format!("{}/my-holdings?one=1", URL).as_str());
     }
@@ -694,12 +915,13 @@ mod tests {
     #[test]
     fn test_set_access_token() {
         let mut kiteconnect = KiteConnect::new("key", "token");
-        assert_eq!(kiteconnect.access_token, "token");
+        assert_eq!(kiteconnect.access_token(), "token");
         kiteconnect.set_access_token("my_token");
-        assert_eq!(kiteconnect.access_token, "my_token");
+        assert_eq!(kiteconnect.access_token(), "my_token");
     }
 
     #[test]
+    #[allow(unpredictable_function_pointer_comparisons)]
     fn test_session_expiry_hook() {
         let mut kiteconnect = KiteConnect::new("key", "token");
         assert_eq!(kiteconnect.session_expiry_hook, None);
@@ -710,6 +932,153 @@ mod tests {
         assert_ne!(kiteconnect.session_expiry_hook, None);
     }
 
+    #[test]
+    fn test_create_session() {
+        let mut kiteconnect = KiteConnect::new("API_KEY", "");
+
+        let _mock = mockito::mock("POST", "/session/token")
+            .with_body_from_file("mocks/session.json")
+            .create();
+
+        let session = kiteconnect.create_session("REQUEST_TOKEN", "API_SECRET").unwrap();
+        assert_eq!(session.user_id, "AB1234");
+        assert_eq!(kiteconnect.access_token(), "ACCESS_TOKEN");
+
+        // Stale sessions are not restored
+        let store = MemorySessionStore::new();
+        store.save(&session).unwrap();
+        assert!(KiteConnect::restore("API_KEY", &store).unwrap().is_none());
+
+        let today = Session { login_time: chrono::Utc::now().with_timezone(&crate::ticker::ist()), ..session };
+        store.save(&today).unwrap();
+        let restored = KiteConnect::restore("API_KEY", &store).unwrap().unwrap();
+        assert_eq!(restored.access_token(), "ACCESS_TOKEN");
+        assert!(KiteConnect::restore("OTHER_KEY", &store).unwrap().is_none());
+    }
+
+    const TOKEN_EXCEPTION: &str = r#"{"status": "error", "message": "Incorrect `api_key` or `access_token`.", "error_type": "TokenException"}"#;
+
+    #[test]
+    fn test_auto_renewal() {
+        let _expired = mockito::mock("GET", "/orders")
+            .match_header("authorization", "token API_KEY:EXPIRED")
+            .with_status(403)
+            .with_body(TOKEN_EXCEPTION)
+            .create();
+        let _orders = mockito::mock("GET", "/orders")
+            .match_header("authorization", "token API_KEY:ACCESS_TOKEN")
+            .with_body_from_file("mocks/orders.json")
+            .create();
+        let refresh = mockito::mock("POST", "/session/refresh_token")
+            .match_body(mockito::Matcher::UrlEncoded("refresh_token".to_string(), "OLD_REFRESH_TOKEN".to_string()))
+            .with_body_from_file("mocks/session.json")
+            .expect(1)
+            .create();
+
+        let store = Arc::new(MemorySessionStore::new());
+        let mut kiteconnect = KiteConnect::new("API_KEY", "EXPIRED");
+        // Clones made before renewal is set up share it too
+        let clone = kiteconnect.clone();
+        kiteconnect.set_auto_renewal("OLD_REFRESH_TOKEN", "API_SECRET", Some(store.clone() as Arc<dyn SessionStore>));
+
+        let orders = clone.orders().unwrap();
+        assert_eq!(orders["status"], "success");
+        assert_eq!(kiteconnect.access_token(), "ACCESS_TOKEN");
+
+        // The other client uses the renewed token without renewing again
+        kiteconnect.orders().unwrap();
+        refresh.assert();
+
+        let session = store.load("API_KEY").unwrap().unwrap();
+        assert_eq!((session.access_token.as_str(), session.refresh_token.as_str()), ("ACCESS_TOKEN", "REFRESH_TOKEN"));
+    }
+
+    #[test]
+    fn test_expired_without_renewal() {
+        static EXPIRED: AtomicBool = AtomicBool::new(false);
+        fn expired() {
+            EXPIRED.store(true, Ordering::SeqCst);
+        }
+
+        let _expired = mockito::mock("GET", "/orders")
+            .with_status(403)
+            .with_body(TOKEN_EXCEPTION)
+            .create();
+
+        let mut kiteconnect = KiteConnect::new("API_KEY", "EXPIRED");
+        kiteconnect.set_session_expiry_hook(expired);
+        let err = kiteconnect.orders().unwrap_err();
+        let exception = err.downcast_ref::<TokenException>().unwrap();
+        assert_eq!(exception.message, "Incorrect `api_key` or `access_token`.");
+        assert!(EXPIRED.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_other_forbidden_responses() {
+        static EXPIRED: AtomicBool = AtomicBool::new(false);
+        fn expired() {
+            EXPIRED.store(true, Ordering::SeqCst);
+        }
+
+        let body = r#"{"status": "error", "message": "Insufficient permission for that call.", "error_type": "PermissionException"}"#;
+        let _forbidden = mockito::mock("GET", "/orders")
+            .with_status(403)
+            .with_body(body)
+            .create();
+        let profile = mockito::mock("GET", "/user/profile").expect(0).create();
+
+        // Without a hook or renewal the response is left to the caller
+        let kiteconnect = KiteConnect::new("API_KEY", "ACCESS_TOKEN");
+        let url = kiteconnect.build_url("/orders", None);
+        let mut resp = kiteconnect.send_request(url, "GET", None).unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        assert_eq!(resp.text().unwrap(), body);
+
+        let mut kiteconnect = KiteConnect::new("API_KEY", "ACCESS_TOKEN");
+        kiteconnect.set_session_expiry_hook(expired);
+        kiteconnect.set_auto_renewal("REFRESH_TOKEN", "API_SECRET", None);
+        let err = kiteconnect.orders().unwrap_err();
+        assert_eq!(err.to_string(), body);
+        assert!(err.downcast_ref::<TokenException>().is_none());
+        assert!(!EXPIRED.load(Ordering::SeqCst));
+        profile.assert();
+    }
+
+    #[test]
+    fn test_restore_with_renewal() {
+        let _expired = mockito::mock("GET", "/orders")
+            .match_header("authorization", "token API_KEY:EXPIRED")
+            .with_status(403)
+            .with_body(TOKEN_EXCEPTION)
+            .create();
+        let _orders = mockito::mock("GET", "/orders")
+            .match_header("authorization", "token API_KEY:ACCESS_TOKEN")
+            .with_body_from_file("mocks/orders.json")
+            .create();
+        let _refresh = mockito::mock("POST", "/session/refresh_token")
+            .match_body(mockito::Matcher::UrlEncoded("refresh_token".to_string(), "STORED_REFRESH_TOKEN".to_string()))
+            .with_body_from_file("mocks/session.json")
+            .create();
+
+        let response: JsonValue = serde_json::from_str(&std::fs::read_to_string("mocks/session.json").unwrap()).unwrap();
+        let session = Session::from_response(&response).unwrap();
+        let store: Arc<dyn SessionStore> = Arc::new(MemorySessionStore::new());
+        store.save(&Session {
+            access_token: "EXPIRED".to_string(),
+            refresh_token: "STORED_REFRESH_TOKEN".to_string(),
+            ..session.clone()
+        }).unwrap();
+
+        // Expired, but renewable with the stored refresh token
+        let kiteconnect = KiteConnect::restore_with_renewal("API_KEY", "API_SECRET", store.clone()).unwrap().unwrap();
+        assert!(KiteConnect::restore("API_KEY", store.as_ref()).unwrap().is_none());
+        assert_eq!(kiteconnect.orders().unwrap()["status"], "success");
+        assert_eq!(store.load("API_KEY").unwrap().unwrap().access_token, "ACCESS_TOKEN");
+
+        store.save(&Session { refresh_token: String::new(), ..session }).unwrap();
+        assert!(KiteConnect::restore_with_renewal("API_KEY", "API_SECRET", store).unwrap().is_none());
+    }
+
     #[test]
     fn test_login_url() {
         let kiteconnect = KiteConnect::new("key", "token");
@@ -844,6 +1213,24 @@ mod tests {
         assert!(data.is_object());
     }
 
+    #[test]
+    fn test_historical_candles() {
+        let kiteconnect = KiteConnect::new("API_KEY", "ACCESS_TOKEN");
+
+        let _mock = mockito::mock(
+            "GET", mockito::Matcher::Regex(r"^/instruments/historical/5633/minute".to_string())
+        )
+        .with_body_from_file("mocks/historical_minute.json")
+        .create();
+
+        let candles = kiteconnect.historical_candles(
+            "5633", "2017-12-15 09:15:00", "2017-12-15 09:20:00", Interval::Minute, "0"
+        ).unwrap();
+        assert_eq!(candles.len(), 3);
+        assert_eq!(candles[0].timestamp.to_rfc3339(), "2017-12-15T09:15:00+05:30");
+        assert_eq!(candles[2].volume, 831);
+    }
+
     #[test]
     fn test_trigger_range() {
         let kiteconnect = KiteConnect::new("API_KEY", "ACCESS_TOKEN");
@@ -874,6 +1261,20 @@ mod tests {
         assert_eq!(data[0]["instrument_token"].as_str(), Some("408065"));
     }
 
+    #[test]
+    fn test_instrument_index() {
+        let kiteconnect = KiteConnect::new("API_KEY", "ACCESS_TOKEN");
+
+        let _mock = mockito::mock(
+            "GET", mockito::Matcher::Regex(r"^/instruments".to_string())
+        )
+        .with_body_from_file("mocks/instruments.csv")
+        .create();
+
+        let index = kiteconnect.instrument_index(Some("NSE")).unwrap();
+        assert_eq!(index.token("NSE:INFY"), Some(408065));
+    }
+
     #[test]
     fn test_mf_instruments() {
         let kiteconnect = KiteConnect::new("API_KEY", "ACCESS_TOKEN");
@@ -0,0 +1,285 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Read;
+use std::sync::Arc;
+use anyhow::{anyhow, Context, Result};
+use chrono::NaiveDate;
+use csv::{ReaderBuilder, Trim};
+use serde::de::{self, Deserialize, Deserializer};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::ticker::{Tick, TickerHandle, TickerHandler};
+
+/// A row of the instruments dump
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Instrument {
+    pub instrument_token: u32,
+    #[serde(deserialize_with = "empty_as_zero")]
+    pub exchange_token: u32,
+    pub tradingsymbol: String,
+    pub name: String,
+    #[serde(deserialize_with = "empty_as_zero")]
+    pub last_price: f64,
+    #[serde(deserialize_with = "expiry")]
+    pub expiry: Option<NaiveDate>,
+    #[serde(deserialize_with = "empty_as_zero")]
+    pub strike: f64,
+    #[serde(deserialize_with = "empty_as_zero")]
+    pub tick_size: f64,
+    #[serde(deserialize_with = "empty_as_zero")]
+    pub lot_size: u32,
+    pub instrument_type: String,
+    pub segment: String,
+    pub exchange: String,
+}
+
+impl Instrument {
+    /// `EXCHANGE:TRADINGSYMBOL` as accepted by the quote APIs
+    pub fn symbol(&self) -> String {
+        format!("{}:{}", self.exchange, self.tradingsymbol)
+    }
+}
+
+/// Reads a numeric column, treating an empty field as zero
+fn empty_as_zero<'de, D, T>(deserializer: D) -> std::result::Result<T, D::Error>
+where D: Deserializer<'de>, T: Deserialize<'de> + Default {
+    Ok(Option::<T>::deserialize(deserializer)?.unwrap_or_default())
+}
+
+/// Reads an optional `%Y-%m-%d` expiry, treating an empty field as none
+fn expiry<'de, D>(deserializer: D) -> std::result::Result<Option<NaiveDate>, D::Error>
+where D: Deserializer<'de> {
+    match Option::<String>::deserialize(deserializer)? {
+        Some(ref expiry) if !expiry.is_empty() => NaiveDate::parse_from_str(expiry, "%Y-%m-%d")
+            .map(Some)
+            .map_err(|_| de::Error::custom(format!("Invalid expiry {:?}", expiry))),
+        _ => Ok(None),
+    }
+}
+
+/// Instruments looked up by `EXCHANGE:TRADINGSYMBOL` or by instrument token
+#[derive(Debug, Clone, Default)]
+pub struct InstrumentIndex {
+    instruments: Vec<Instrument>,
+    symbols: HashMap<String, usize>,
+    tokens: HashMap<u32, usize>,
+}
+
+impl InstrumentIndex {
+    pub fn new(instruments: Vec<Instrument>) -> InstrumentIndex {
+        let mut index = InstrumentIndex::default();
+        for instrument in instruments {
+            index.insert(instrument);
+        }
+        index
+    }
+
+    /// Reads the CSV returned by the instruments API. Columns are matched by
+    /// their header, so reordered or additional columns are fine.
+    pub fn from_csv<R>(reader: R) -> Result<InstrumentIndex> where R: Read {
+        let mut csv_reader = ReaderBuilder::new().trim(Trim::All).from_reader(reader);
+        let mut index = InstrumentIndex::default();
+        for instrument in csv_reader.deserialize::<Instrument>() {
+            index.insert(instrument.context("Unable to read the instruments")?);
+        }
+        Ok(index)
+    }
+
+    /// Adds an instrument, replacing any earlier one with the same token
+    pub fn insert(&mut self, instrument: Instrument) {
+        if let Some(&position) = self.tokens.get(&instrument.instrument_token) {
+            self.symbols.remove(&self.instruments[position].symbol());
+            self.symbols.insert(instrument.symbol(), position);
+            self.instruments[position] = instrument;
+            return;
+        }
+        let position = self.instruments.len();
+        self.symbols.insert(instrument.symbol(), position);
+        self.tokens.insert(instrument.instrument_token, position);
+        self.instruments.push(instrument);
+    }
+
+    /// Instrument for an `EXCHANGE:TRADINGSYMBOL` string
+    pub fn get(&self, symbol: &str) -> Option<&Instrument> {
+        self.symbols.get(symbol).map(|&position| &self.instruments[position])
+    }
+
+    /// Instrument with the given token
+    pub fn by_token(&self, instrument_token: u32) -> Option<&Instrument> {
+        self.tokens.get(&instrument_token).map(|&position| &self.instruments[position])
+    }
+
+    /// Token of an `EXCHANGE:TRADINGSYMBOL` string
+    pub fn token(&self, symbol: &str) -> Option<u32> {
+        self.get(symbol).map(|instrument| instrument.instrument_token)
+    }
+
+    /// Tokens of the given `EXCHANGE:TRADINGSYMBOL` strings, in order. Fails
+    /// naming every symbol that is not in the index.
+    pub fn resolve(&self, symbols: &[&str]) -> Result<Vec<u32>> {
+        let mut tokens = Vec::with_capacity(symbols.len());
+        let mut unknown = Vec::new();
+        for symbol in symbols {
+            match self.token(symbol) {
+                Some(token) => tokens.push(token),
+                None => unknown.push(*symbol),
+            }
+        }
+        if !unknown.is_empty() {
+            return Err(anyhow!("Unknown instruments {}", unknown.join(", ")));
+        }
+        Ok(tokens)
+    }
+
+    /// Tags a tick with its exchange and trading symbol for logging
+    pub fn annotate<'a>(&'a self, tick: &'a Tick) -> AnnotatedTick<'a> {
+        AnnotatedTick { instrument: self.by_token(tick.instrument_token), tick }
+    }
+
+    pub fn len(&self) -> usize {
+        self.instruments.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.instruments.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Instrument> {
+        self.instruments.iter()
+    }
+}
+
+impl std::iter::FromIterator<Instrument> for InstrumentIndex {
+    fn from_iter<I>(iter: I) -> InstrumentIndex where I: IntoIterator<Item = Instrument> {
+        InstrumentIndex::new(iter.into_iter().collect())
+    }
+}
+
+/// A tick together with the instrument it was resolved to. Displays as
+/// `NSE:INFY 1234.5`, falling back to the token for unknown instruments.
+#[derive(Debug, Clone, Copy)]
+pub struct AnnotatedTick<'a> {
+    pub instrument: Option<&'a Instrument>,
+    pub tick: &'a Tick,
+}
+
+impl<'a> AnnotatedTick<'a> {
+    pub fn exchange(&self) -> Option<&'a str> {
+        self.instrument.map(|instrument| instrument.exchange.as_str())
+    }
+
+    pub fn tradingsymbol(&self) -> Option<&'a str> {
+        self.instrument.map(|instrument| instrument.tradingsymbol.as_str())
+    }
+}
+
+impl<'a> fmt::Display for AnnotatedTick<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.instrument {
+            Some(instrument) => write!(f, "{}:{}", instrument.exchange, instrument.tradingsymbol)?,
+            None => write!(f, "{}", self.tick.instrument_token)?,
+        }
+        write!(f, " {}", self.tick.last_price)
+    }
+}
+
+/// Handler that passes every batch of ticks to `on_ticks` already resolved
+/// against an index. Other events are only logged, so combine it with the
+/// rest of the handlers through `FanOut`.
+pub struct Annotated<F> {
+    index: Arc<InstrumentIndex>,
+    on_ticks: F,
+}
+
+impl<F> Annotated<F> where F: FnMut(&TickerHandle, &[AnnotatedTick]) + Send {
+    pub fn new(index: Arc<InstrumentIndex>, on_ticks: F) -> Annotated<F> {
+        Annotated { index, on_ticks }
+    }
+}
+
+impl<F> TickerHandler for Annotated<F> where F: FnMut(&TickerHandle, &[AnnotatedTick]) + Send {
+    fn on_ticks(&mut self, ticker: &TickerHandle, ticks: &[Tick]) {
+        let index = &self.index;
+        let annotated: Vec<AnnotatedTick> = ticks.iter().map(|tick| index.annotate(tick)).collect();
+        (self.on_ticks)(ticker, &annotated);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+
+    fn index() -> InstrumentIndex {
+        InstrumentIndex::from_csv(File::open("mocks/instruments.csv").unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_from_csv() {
+        let index = index();
+        assert_eq!(index.len(), 4);
+
+        let infy = index.get("NSE:INFY").unwrap();
+        assert_eq!((infy.instrument_token, infy.lot_size, infy.expiry), (408065, 1, None));
+        assert_eq!(infy.name, "INFOSYS");
+
+        let option = index.by_token(5720578).unwrap();
+        assert_eq!(option.symbol(), "NFO:NIFTY159500CE");
+        assert_eq!(option.strike, 9500.0);
+        assert_eq!(option.expiry, NaiveDate::from_ymd_opt(2015, 12, 31));
+    }
+
+    #[test]
+    fn test_resolve_and_annotate() {
+        let index = index();
+        assert_eq!(index.resolve(&["NFO:NIFTY15DECFUT", "NSE:INFY"]).unwrap(), vec![5720322, 408065]);
+
+        let err = index.resolve(&["NSE:INFY", "NSE:TCS", "BSE:INFY"]).unwrap_err();
+        assert_eq!(err.to_string(), "Unknown instruments NSE:TCS, BSE:INFY");
+
+        let tick = Tick { instrument_token: 408065, last_price: 1234.5, ..Tick::default() };
+        let annotated = index.annotate(&tick);
+        assert_eq!((annotated.exchange(), annotated.tradingsymbol()), (Some("NSE"), Some("INFY")));
+        assert_eq!(annotated.to_string(), "NSE:INFY 1234.5");
+
+        let unknown = Tick { instrument_token: 1, last_price: 10.0, ..Tick::default() };
+        assert_eq!(index.annotate(&unknown).to_string(), "1 10");
+    }
+
+    #[test]
+    fn test_columns_by_header() {
+        let csv = "exchange, tradingsymbol, name, expiry, strike, instrument_token, exchange_token, \
+                   segment, lot_size, tick_size, last_price, instrument_type, extra\n\
+                   NFO, NIFTY15DECFUT, , 2015-12-31, , 5720322, 22345, NFO-FUT, 75, 0.05, 0, FUT, x\n";
+        let index = InstrumentIndex::from_csv(csv.as_bytes()).unwrap();
+        let future = index.get("NFO:NIFTY15DECFUT").unwrap();
+        assert_eq!((future.instrument_token, future.lot_size, future.strike), (5720322, 75, 0.0));
+        assert_eq!(future.expiry, NaiveDate::from_ymd_opt(2015, 12, 31));
+
+        let csv = "instrument_token, exchange_token, tradingsymbol, name, last_price, expiry, strike, \
+                   tick_size, lot_size, instrument_type, segment, exchange\n\
+                   408065, 1594, INFY, INFOSYS, 0, 31-12-2015, 0, 0.05, 1, EQ, NSE, NSE\n";
+        let err = InstrumentIndex::from_csv(csv.as_bytes()).unwrap_err();
+        assert!(format!("{:#}", err).contains("Invalid expiry \"31-12-2015\""), "{:#}", err);
+    }
+
+    #[test]
+    fn test_annotated_handler() {
+        use std::sync::Mutex;
+        use crate::ticker::Subscriptions;
+
+        let handle = TickerHandle::new(None, Arc::new(Mutex::new(Subscriptions::default())));
+        let mut labels = Vec::new();
+        {
+            let mut handler = Annotated::new(Arc::new(index()), |_: &TickerHandle, ticks: &[AnnotatedTick]| {
+                labels.extend(ticks.iter().map(|tick| tick.to_string()));
+            });
+            let ticks = [
+                Tick { instrument_token: 408065, last_price: 1234.5, ..Tick::default() },
+                Tick { instrument_token: 1, last_price: 10.0, ..Tick::default() },
+            ];
+            handler.on_ticks(&handle, &ticks);
+        }
+        assert_eq!(labels, vec!["NSE:INFY 1234.5", "1 10"]);
+    }
+}
@@ -51,7 +51,7 @@
 //! extern crate kiteconnect;
 //! extern crate serde_json as json;
 //! 
-//! use kiteconnect::ticker::{KiteTicker, KiteTickerHandler, WebSocketHandler};
+//! use kiteconnect::ticker::{KiteTicker, KiteTickerHandler, Tick, WebSocketHandler};
 //! 
 //! #[derive(Debug)]
 //! struct CustomHandler {
@@ -65,9 +65,9 @@
 //!         ws.subscribe(vec![123456]);
 //!         println!("Fellow on_open callback");
 //!     }
-//!     fn on_ticks<T>(&mut self, ws: &mut WebSocketHandler<T>, tick: Vec<json::Value>)
+//!     fn on_ticks<T>(&mut self, ws: &mut WebSocketHandler<T>, ticks: Vec<Tick>)
 //!     where T: KiteTickerHandler {
-//!         println!("{:?}", tick);
+//!         println!("{:?}", ticks);
 //!         println!("Fellow on_ticks callback");
 //!     }
 //! 
@@ -89,9 +89,10 @@
 //!         count: 0
 //!     };
 //! 
-//!     ticker.connect(custom_handler, None);
+//!     ticker.connect(custom_handler, None).unwrap();
 //! 
-//!     loop {}
+//!     // Blocks until the connection is closed
+//!     ticker.join().unwrap();
 //! # }
 //! ```
 //!
@@ -102,5 +103,18 @@ extern crate ws;
 extern crate url;
 extern crate byteorder;
 
+pub mod alert;
+pub mod candle;
 pub mod connect;
+pub mod instruments;
+pub mod login;
+pub mod session;
 pub mod ticker;
+
+/// Empty scratch directory for a test, unique to this process
+#[cfg(test)]
+pub(crate) fn test_directory(name: &str) -> std::path::PathBuf {
+    let path = std::env::temp_dir().join(format!("kiteconnect-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&path);
+    path
+}
@@ -0,0 +1,290 @@
+use std::io::{self, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+use anyhow::{anyhow, bail, Context, Result};
+use log::debug;
+use url::Url;
+
+use crate::connect::KiteConnect;
+use crate::session::Session;
+use crate::ticker;
+
+/// How long `LoginHelper` waits for the redirect unless told otherwise
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(300);
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Runs the Kite Connect login flow without copying the request token by
+/// hand. It listens on the redirect url registered for the app, sends the
+/// user to `login_url`, and exchanges the request token from the redirect
+/// for an access token.
+///
+/// The redirect url must be a plain `http` url on this machine, such as
+/// `http://127.0.0.1:5000/callback`.
+pub struct LoginHelper {
+    api_key: String,
+    api_secret: String,
+    listener: TcpListener,
+    path: String,
+    timeout: Duration,
+    #[cfg(feature = "browser")]
+    open_browser: bool,
+}
+
+impl LoginHelper {
+    pub fn new(api_key: &str, api_secret: &str, redirect_url: &str) -> Result<LoginHelper> {
+        let url = Url::parse(redirect_url).with_context(|| format!("Invalid redirect url {}", redirect_url))?;
+        if url.scheme() != "http" {
+            bail!("Redirect url {} must use http to be served locally", redirect_url);
+        }
+        let host = url.host_str().ok_or_else(|| anyhow!("Redirect url {} has no host", redirect_url))?;
+        let port = url.port_or_known_default().unwrap_or(80);
+
+        let listener = TcpListener::bind((host, port))
+            .with_context(|| format!("Unable to listen on {}:{}", host, port))?;
+        listener.set_nonblocking(true)?;
+
+        Ok(LoginHelper {
+            api_key: api_key.to_string(),
+            api_secret: api_secret.to_string(),
+            listener,
+            path: url.path().to_string(),
+            timeout: DEFAULT_TIMEOUT,
+            #[cfg(feature = "browser")]
+            open_browser: true,
+        })
+    }
+
+    /// Address the redirect listener is bound to
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// How long to wait for the redirect, five minutes by default
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Whether `login` opens the login url in the default browser
+    #[cfg(feature = "browser")]
+    pub fn set_open_browser(&mut self, open_browser: bool) {
+        self.open_browser = open_browser;
+    }
+
+    pub fn login_url(&self) -> String {
+        KiteConnect::new(&self.api_key, "").login_url()
+    }
+
+    /// Prints and, with the `browser` feature, opens the login url, then
+    /// returns a client holding the access token of the new session
+    pub fn login(&self) -> Result<KiteConnect> {
+        let request_token = self.wait_for_login()?;
+        let mut kiteconnect = KiteConnect::new(&self.api_key, "");
+        kiteconnect.generate_session(&request_token, &self.api_secret)?;
+        Ok(kiteconnect)
+    }
+
+    /// Like `login`, also returning the session so it can be stored
+    pub fn login_session(&self) -> Result<(KiteConnect, Session)> {
+        let request_token = self.wait_for_login()?;
+        let mut kiteconnect = KiteConnect::new(&self.api_key, "");
+        let session = kiteconnect.create_session(&request_token, &self.api_secret)?;
+        Ok((kiteconnect, session))
+    }
+
+    /// Shows the login url and waits for the redirect's request token
+    fn wait_for_login(&self) -> Result<String> {
+        let login_url = self.login_url();
+        println!("Log in to Kite at {}", login_url);
+        #[cfg(feature = "browser")]
+        {
+            if self.open_browser {
+                if let Err(err) = webbrowser::open(&login_url) {
+                    debug!("Unable to open a browser: {}", err);
+                }
+            }
+        }
+
+        self.request_token()
+    }
+
+    /// Waits for the redirect and returns its request token. Fails if the
+    /// login redirect reports a status other than success or the timeout
+    /// passes; other requests to the callback are answered with 400 and
+    /// skipped.
+    pub fn request_token(&self) -> Result<String> {
+        let deadline = Instant::now() + self.timeout;
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    if let Some(request_token) = self.callback(stream)? {
+                        return Ok(request_token);
+                    }
+                },
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    if Instant::now() >= deadline {
+                        bail!("No login redirect received within {:?}", self.timeout);
+                    }
+                    thread::sleep(POLL_INTERVAL);
+                },
+                Err(err) => return Err(err).context("Unable to accept the login redirect"),
+            }
+        }
+    }
+
+    /// Handles a single request to the listener, returning the request
+    /// token if it was the login redirect
+    fn callback(&self, stream: TcpStream) -> Result<Option<String>> {
+        let target = match read_request(&stream) {
+            Ok(target) => target,
+            Err(err) => {
+                debug!("Ignoring unreadable request: {}", err);
+                return Ok(None);
+            }
+        };
+        let url = match Url::parse("http://localhost").and_then(|base| base.join(&target)) {
+            Ok(url) if url.path() == self.path => url,
+            _ => {
+                let _ = respond(stream, "404 Not Found", "Not found");
+                return Ok(None);
+            }
+        };
+
+        let param = |name: &str| url.query_pairs()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.into_owned())
+            .unwrap_or_default();
+        let status = param("status");
+        let request_token = param("request_token");
+
+        // Only a failure reported by a login redirect ends the login,
+        // anything else malformed is rejected and the helper keeps waiting
+        // for the real redirect
+        let is_login = param("action") == "login" && param("type") == "login";
+        if is_login && !status.is_empty() && status != "success" {
+            let _ = respond(stream, "400 Bad Request", "Login failed, see the terminal for details.");
+            bail!("Login redirect returned status {:?}", status);
+        }
+        if status != "success" || request_token.is_empty()
+            || !request_token.chars().all(|c| c.is_ascii_alphanumeric()) {
+            debug!("Ignoring redirect with status {:?} and request token {:?}", status, request_token);
+            let _ = respond(stream, "400 Bad Request", "Invalid login redirect, waiting for another one.");
+            return Ok(None);
+        }
+
+        let _ = respond(stream, "200 OK", "Logged in, you can close this window.");
+        Ok(Some(request_token))
+    }
+}
+
+/// Reads the request line and headers, returning the request target
+fn read_request(stream: &TcpStream) -> io::Result<String> {
+    stream.set_nonblocking(false)?;
+    let request = ticker::read_request(stream, REQUEST_TIMEOUT)?;
+
+    match request.split_whitespace().collect::<Vec<_>>().as_slice() {
+        ["GET", target, _] => Ok(target.to_string()),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unexpected request {:?}", request.trim()))),
+    }
+}
+
+fn respond(mut stream: TcpStream, status: &str, body: &str) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, body.len(), body
+    )?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn get(address: SocketAddr, target: &str) -> thread::JoinHandle<String> {
+        let target = target.to_string();
+        thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).unwrap();
+            write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", target).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        })
+    }
+
+    fn helper() -> LoginHelper {
+        let mut helper = LoginHelper::new("API_KEY", "API_SECRET", "http://127.0.0.1:0/callback").unwrap();
+        helper.set_timeout(Duration::from_secs(5));
+        #[cfg(feature = "browser")]
+        helper.set_open_browser(false);
+        helper
+    }
+
+    #[test]
+    fn test_login() {
+        let mock = mockito::mock("POST", "/session/token")
+            .with_body(r#"{"status": "success", "data": {"user_id": "AB1234", "access_token": "ACCESS_TOKEN"}}"#)
+            .create();
+
+        let helper = helper();
+        let address = helper.local_addr().unwrap();
+        let redirect = thread::spawn(move || {
+            // Requests other than the redirect are answered and skipped
+            assert!(get(address, "/favicon.ico").join().unwrap().starts_with("HTTP/1.1 404"));
+            get(address, "/callback?action=login&type=login&status=success&request_token=abc123").join().unwrap()
+        });
+
+        let kiteconnect = helper.login().unwrap();
+        assert_eq!(kiteconnect.access_token(), "ACCESS_TOKEN");
+        assert!(redirect.join().unwrap().starts_with("HTTP/1.1 200 OK"));
+        drop(mock);
+
+        let _mock = mockito::mock("POST", "/session/token")
+            .with_body_from_file("mocks/session.json")
+            .create();
+        let redirect = get(address, "/callback?status=success&request_token=abc123");
+        let (kiteconnect, session) = helper.login_session().unwrap();
+        assert_eq!(kiteconnect.access_token(), "ACCESS_TOKEN");
+        assert_eq!(session.user_id, "AB1234");
+        assert!(redirect.join().unwrap().starts_with("HTTP/1.1 200 OK"));
+    }
+
+    #[test]
+    fn test_invalid_redirect() {
+        assert!(LoginHelper::new("API_KEY", "API_SECRET", "https://127.0.0.1:0/callback").is_err());
+
+        let helper = helper();
+        let redirect = get(helper.local_addr().unwrap(), "/callback?action=login&type=login&status=cancelled");
+        let err = helper.request_token().unwrap_err();
+        assert_eq!(err.to_string(), "Login redirect returned status \"cancelled\"");
+        assert!(redirect.join().unwrap().starts_with("HTTP/1.1 400"));
+
+        // Malformed redirects are rejected without ending the login
+        let address = helper.local_addr().unwrap();
+        let redirects = thread::spawn(move || {
+            // Overlong requests are dropped once the line limit is reached
+            let mut stream = TcpStream::connect(address).unwrap();
+            let _ = stream.write_all(&[b'A'; 16 * 1024]);
+            let _ = stream.read_to_string(&mut String::new());
+
+            let responses = vec![
+                get(address, "/callback?status=cancelled&request_token=abc123").join().unwrap(),
+                get(address, "/callback?status=success").join().unwrap(),
+                get(address, "/callback?request_token=abc123").join().unwrap(),
+                get(address, "/callback?status=success&request_token=abc%20123").join().unwrap(),
+            ];
+            let last = get(address, "/callback?status=success&request_token=abc123").join().unwrap();
+            (responses, last)
+        });
+        assert_eq!(helper.request_token().unwrap(), "abc123");
+        let (responses, last) = redirects.join().unwrap();
+        assert!(responses.iter().all(|response| response.starts_with("HTTP/1.1 400")), "{:?}", responses);
+        assert!(last.starts_with("HTTP/1.1 200 OK"));
+
+        let mut helper = helper;
+        helper.set_timeout(Duration::from_millis(100));
+        assert!(helper.request_token().unwrap_err().to_string().starts_with("No login redirect"));
+    }
+}
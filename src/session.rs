@@ -0,0 +1,339 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use anyhow::{anyhow, Context, Result};
+use arc_swap::ArcSwap;
+use chrono::{DateTime, Duration, FixedOffset, NaiveDateTime, NaiveTime, TimeZone, Utc};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+
+use crate::ticker::ist;
+
+/// Access tokens are flushed every morning at this time, IST
+fn token_expiry() -> NaiveTime {
+    NaiveTime::from_hms_opt(6, 0, 0).unwrap()
+}
+
+/// Account details returned along with a session
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Profile {
+    pub user_name: String,
+    pub user_shortname: String,
+    pub email: String,
+    pub user_type: String,
+    pub broker: String,
+    pub exchanges: Vec<String>,
+    pub products: Vec<String>,
+    pub order_types: Vec<String>,
+    pub avatar_url: Option<String>,
+}
+
+/// A login session, as returned by `KiteConnect::create_session`.
+/// `refresh_token` is only issued to apps allowed to renew sessions and is
+/// empty otherwise. `Debug` leaves out the tokens so sessions can be logged.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct Session {
+    pub user_id: String,
+    pub api_key: String,
+    pub access_token: String,
+    pub refresh_token: String,
+    pub public_token: String,
+    pub enctoken: String,
+    /// Login time in IST
+    pub login_time: DateTime<FixedOffset>,
+    pub profile: Profile,
+}
+
+impl Session {
+    /// Parses a `generate_session` response, given either the whole
+    /// response or its `data` object
+    pub fn from_response(response: &JsonValue) -> Result<Session> {
+        let data = response.get("data").unwrap_or(response);
+        let text = |name: &str| data[name].as_str().unwrap_or_default().to_string();
+        let required = |name: &str| data[name].as_str()
+            .filter(|value| !value.is_empty())
+            .map(str::to_string)
+            .ok_or_else(|| anyhow!("No {} in the session response", name));
+        let list = |name: &str| data[name].as_array()
+            .map(|values| values.iter().filter_map(JsonValue::as_str).map(str::to_string).collect())
+            .unwrap_or_default();
+
+        let login_time = required("login_time")?;
+        let login_time = NaiveDateTime::parse_from_str(&login_time, "%Y-%m-%d %H:%M:%S")
+            .with_context(|| format!("Invalid login time {:?}", login_time))?;
+
+        Ok(Session {
+            user_id: required("user_id")?,
+            api_key: text("api_key"),
+            access_token: required("access_token")?,
+            refresh_token: text("refresh_token"),
+            public_token: text("public_token"),
+            enctoken: text("enctoken"),
+            login_time: ist().from_local_datetime(&login_time).unwrap(),
+            profile: Profile {
+                user_name: text("user_name"),
+                user_shortname: text("user_shortname"),
+                email: text("email"),
+                user_type: text("user_type"),
+                broker: text("broker"),
+                exchanges: list("exchanges"),
+                products: list("products"),
+                order_types: list("order_types"),
+                avatar_url: data["avatar_url"].as_str().map(str::to_string),
+            },
+        })
+    }
+
+    /// When the access token stops working: 06:00 IST after the login
+    pub fn expires_at(&self) -> DateTime<FixedOffset> {
+        let login_time = self.login_time.with_timezone(&ist());
+        let mut day = login_time.date_naive();
+        if login_time.time() >= token_expiry() {
+            day += Duration::days(1);
+        }
+        ist().from_local_datetime(&day.and_time(token_expiry())).unwrap()
+    }
+
+    pub fn is_valid_at(&self, time: DateTime<FixedOffset>) -> bool {
+        time < self.expires_at()
+    }
+
+    /// Whether the access token is still valid for the current trading day
+    pub fn is_valid(&self) -> bool {
+        self.is_valid_at(Utc::now().with_timezone(&ist()))
+    }
+}
+
+impl fmt::Debug for Session {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let redact = |token: &str| if token.is_empty() { "" } else { "<redacted>" };
+        f.debug_struct("Session")
+            .field("user_id", &self.user_id)
+            .field("api_key", &self.api_key)
+            .field("access_token", &redact(&self.access_token))
+            .field("refresh_token", &redact(&self.refresh_token))
+            .field("public_token", &self.public_token)
+            .field("enctoken", &redact(&self.enctoken))
+            .field("login_time", &self.login_time)
+            .field("profile", &self.profile)
+            .finish()
+    }
+}
+
+/// Access token shared by a `KiteConnect`, its clones and the tickers
+/// created from it, so a renewed token is picked up by all of them
+#[derive(Debug, Clone)]
+pub(crate) struct AccessToken(Arc<ArcSwap<String>>);
+
+impl AccessToken {
+    pub(crate) fn new(access_token: &str) -> AccessToken {
+        AccessToken(Arc::new(ArcSwap::from_pointee(access_token.to_string())))
+    }
+
+    pub(crate) fn get(&self) -> Arc<String> {
+        self.0.load_full()
+    }
+
+    pub(crate) fn set(&self, access_token: &str) {
+        self.0.store(Arc::new(access_token.to_string()));
+    }
+}
+
+/// Persists sessions between runs, keyed by api key
+pub trait SessionStore: Send + Sync {
+    fn load(&self, api_key: &str) -> Result<Option<Session>>;
+
+    fn save(&self, session: &Session) -> Result<()>;
+
+    fn remove(&self, api_key: &str) -> Result<()>;
+}
+
+impl<S> SessionStore for Arc<S> where S: SessionStore + ?Sized {
+    fn load(&self, api_key: &str) -> Result<Option<Session>> {
+        (**self).load(api_key)
+    }
+
+    fn save(&self, session: &Session) -> Result<()> {
+        (**self).save(session)
+    }
+
+    fn remove(&self, api_key: &str) -> Result<()> {
+        (**self).remove(api_key)
+    }
+}
+
+/// Keeps one JSON file per api key in a directory. Files are only readable
+/// by the owner since they hold live tokens.
+#[derive(Debug, Clone)]
+pub struct FileSessionStore {
+    directory: PathBuf,
+}
+
+impl FileSessionStore {
+    pub fn new<P: AsRef<Path>>(directory: P) -> FileSessionStore {
+        FileSessionStore { directory: directory.as_ref().to_path_buf() }
+    }
+
+    /// File of an api key. Keys are alphanumeric, anything else could
+    /// escape the directory and is rejected.
+    fn path(&self, api_key: &str) -> Result<PathBuf> {
+        if api_key.is_empty() || !api_key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+            return Err(anyhow!("Invalid api key {:?}", api_key));
+        }
+        Ok(self.directory.join(format!("{}.json", api_key)))
+    }
+}
+
+impl SessionStore for FileSessionStore {
+    fn load(&self, api_key: &str) -> Result<Option<Session>> {
+        let path = self.path(api_key)?;
+        match fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data)
+                .map(Some)
+                .with_context(|| format!("Invalid session in {}", path.display())),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err).with_context(|| format!("Unable to read {}", path.display())),
+        }
+    }
+
+    fn save(&self, session: &Session) -> Result<()> {
+        let path = self.path(&session.api_key)?;
+        fs::create_dir_all(&self.directory)
+            .with_context(|| format!("Unable to create {}", self.directory.display()))?;
+        // Write aside and rename so readers never see a partial session
+        let partial = path.with_extension("json.partial");
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options.open(&partial)
+            .with_context(|| format!("Unable to write {}", partial.display()))?;
+        file.write_all(&serde_json::to_vec_pretty(session)?)?;
+        file.sync_all()?;
+        fs::rename(&partial, &path).with_context(|| format!("Unable to write {}", path.display()))
+    }
+
+    fn remove(&self, api_key: &str) -> Result<()> {
+        match fs::remove_file(self.path(api_key)?) {
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result.with_context(|| format!("Unable to remove the session of {}", api_key)),
+        }
+    }
+}
+
+/// Keeps sessions for the lifetime of the process
+#[derive(Debug, Default)]
+pub struct MemorySessionStore {
+    sessions: Mutex<HashMap<String, Session>>,
+}
+
+impl MemorySessionStore {
+    pub fn new() -> MemorySessionStore {
+        MemorySessionStore::default()
+    }
+
+    fn sessions(&self) -> std::sync::MutexGuard<'_, HashMap<String, Session>> {
+        self.sessions.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+impl SessionStore for MemorySessionStore {
+    fn load(&self, api_key: &str) -> Result<Option<Session>> {
+        Ok(self.sessions().get(api_key).cloned())
+    }
+
+    fn save(&self, session: &Session) -> Result<()> {
+        self.sessions().insert(session.api_key.clone(), session.clone());
+        Ok(())
+    }
+
+    fn remove(&self, api_key: &str) -> Result<()> {
+        self.sessions().remove(api_key);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session() -> Session {
+        let response: JsonValue = serde_json::from_str(&fs::read_to_string("mocks/session.json").unwrap()).unwrap();
+        Session::from_response(&response).unwrap()
+    }
+
+    #[test]
+    fn test_from_response() {
+        let session = session();
+        assert_eq!((session.user_id.as_str(), session.api_key.as_str()), ("AB1234", "API_KEY"));
+        assert_eq!(session.refresh_token, "REFRESH_TOKEN");
+        assert_eq!(session.login_time.to_rfc3339(), "2021-01-01T16:15:14+05:30");
+        assert_eq!(session.profile.exchanges.len(), 8);
+        assert_eq!(session.profile.avatar_url.as_deref(), Some("abc"));
+
+        assert!(Session::from_response(&serde_json::json!({"data": {"user_id": "AB1234"}})).is_err());
+    }
+
+    #[test]
+    fn test_debug_redacts_tokens() {
+        let debug = format!("{:?}", session());
+        for token in &["ACCESS_TOKEN", "REFRESH_TOKEN", "ENCTOKEN"] {
+            assert!(!debug.contains(token), "{}", debug);
+        }
+        assert!(debug.contains("access_token: \"<redacted>\""));
+        assert!(debug.contains("AB1234") && debug.contains("PUBLIC_TOKEN"));
+    }
+
+    #[test]
+    fn test_expiry() {
+        let session = session();
+        assert_eq!(session.expires_at().to_rfc3339(), "2021-01-02T06:00:00+05:30");
+        assert!(session.is_valid_at(ist().with_ymd_and_hms(2021, 1, 2, 5, 59, 59).unwrap()));
+        assert!(!session.is_valid_at(ist().with_ymd_and_hms(2021, 1, 2, 6, 0, 0).unwrap()));
+
+        // Early morning logins only last until 06:00 the same day
+        let early = Session { login_time: ist().with_ymd_and_hms(2021, 1, 1, 5, 0, 0).unwrap(), ..session };
+        assert_eq!(early.expires_at().to_rfc3339(), "2021-01-01T06:00:00+05:30");
+    }
+
+    #[test]
+    fn test_stores() {
+        let directory = crate::test_directory("sessions");
+        let stores: Vec<Box<dyn SessionStore>> = vec![
+            Box::new(MemorySessionStore::new()),
+            Box::new(FileSessionStore::new(&directory)),
+        ];
+
+        for store in stores {
+            assert_eq!(store.load("API_KEY").unwrap(), None);
+            store.save(&session()).unwrap();
+            assert_eq!(store.load("API_KEY").unwrap(), Some(session()));
+            store.remove("API_KEY").unwrap();
+            store.remove("API_KEY").unwrap();
+            assert_eq!(store.load("API_KEY").unwrap(), None);
+        }
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let store = FileSessionStore::new(&directory);
+            store.save(&session()).unwrap();
+            let mode = fs::metadata(directory.join("API_KEY.json")).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        let store = FileSessionStore::new(&directory);
+        for api_key in &["../API_KEY", "a/b", "a\\b", "..", ""] {
+            assert!(store.load(api_key).is_err(), "{:?}", api_key);
+            assert!(store.remove(api_key).is_err(), "{:?}", api_key);
+            assert!(store.save(&Session { api_key: api_key.to_string(), ..session() }).is_err(), "{:?}", api_key);
+        }
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
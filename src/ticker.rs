@@ -5,22 +5,65 @@
 //         unstable_features,
 //         unused_import_braces, unused_qualifications)]
 //
-use std::thread;
-use std::io::{Cursor, Seek, SeekFrom};
+#![allow(clippy::result_large_err)]
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 use std::sync::{Arc, Mutex};
-use std::collections::HashMap;
 use log::debug;
+use openssl::ssl::{SslConnector, SslMethod, SslStream};
 use ws::{
-    Handler, Handshake, Message, Sender, CloseCode, Result, Error,
+    Handler, Handshake, Message, Sender, CloseCode, Result, Error, ErrorKind,
     Request, Factory, WebSocket
 };
-use byteorder::{BigEndian, ReadBytesExt};
-use url;
-use serde_json::{json, Value as JsonValue};
+use ws::util::TcpStream;
+use url::Url;
+use serde_json::Value as JsonValue;
+
+use crate::instruments::InstrumentIndex;
+use crate::session::AccessToken;
+
+mod bridge;
+mod conflate;
+mod depth;
+mod endpoint;
+mod export;
+mod handle;
+mod handler;
+mod metrics;
+mod mock;
+mod pool;
+mod recorder;
+mod snapshot;
+mod stream;
+mod subscriptions;
+mod tick;
+#[cfg(feature = "async")]
+mod async_ticker;
+
+pub use self::bridge::TickBridge;
+pub use self::conflate::Conflated;
+pub use self::depth::{DepthMetrics, Side};
+pub use self::export::{export_columns, ExportFormat, TickExporter};
+pub use self::handle::TickerHandle;
+pub use self::handler::{FanOut, TickerHandler};
+pub use self::metrics::{ConnectionMetrics, ConnectionSnapshot, HistogramSnapshot, MetricsServer, TickerMetrics};
+pub(crate) use self::metrics::read_request;
+pub use self::pool::{TickerPool, MAX_CONNECTIONS};
+pub use self::snapshot::MarketSnapshot;
+pub use self::recorder::{RecordedFrame, ReplaySpeed, TickReader, TickRecorder, TickReplayer};
+pub use self::subscriptions::{Mode, Subscriptions, MAX_TOKENS_PER_CONNECTION};
+pub use self::stream::{Backpressure, TickerEvent, TickerStream};
+pub use self::tick::{encode_binary, ist, parse_binary, Depth, DepthItem, Ohlc, Tick};
+pub use self::mock::MockTickerServer;
+#[cfg(feature = "async")]
+pub use self::async_ticker::{AsyncKiteTicker, AsyncTickerHandle, AsyncTickerStream};
+use self::endpoint::Endpoint;
+use self::handler::DynHandler;
+use self::stream::ChannelHandler;
 
 /// KiteTickerHandler lets the user write the business logic inside
 /// the corresponding callbacks which are basically proxied from the
-/// Handler callbacks
+/// Handler callbacks. See `TickerHandler` for an object safe alternative.
 pub trait KiteTickerHandler {
 
     fn on_open<T>(&mut self, _ws: &mut WebSocketHandler<T>)
@@ -28,9 +71,19 @@ pub trait KiteTickerHandler {
         debug!("Connection opened");
     }
 
-    fn on_ticks<T>(&mut self, _ws: &mut WebSocketHandler<T>, tick: Vec<JsonValue>)
+    fn on_ticks<T>(&mut self, _ws: &mut WebSocketHandler<T>, ticks: Vec<Tick>)
     where T: KiteTickerHandler {
-        debug!("{:?}", tick);
+        debug!("{:?}", ticks);
+    }
+
+    fn on_order_update<T>(&mut self, _ws: &mut WebSocketHandler<T>, order: JsonValue)
+    where T: KiteTickerHandler {
+        debug!("Order update {:?}", order);
+    }
+
+    fn on_message<T>(&mut self, _ws: &mut WebSocketHandler<T>, message: JsonValue)
+    where T: KiteTickerHandler {
+        debug!("Message {:?}", message);
     }
 
     fn on_close<T>(&mut self, _ws: &mut WebSocketHandler<T>)
@@ -46,7 +99,26 @@ pub trait KiteTickerHandler {
 
 
 struct WebSocketFactory<T> where T: KiteTickerHandler {
-    handler: Arc<Mutex<Box<T>>>
+    handler: Arc<Mutex<Box<T>>>,
+    subscriptions: Arc<Mutex<Subscriptions>>,
+    recorder: Option<Arc<Mutex<TickRecorder>>>,
+    snapshot: Option<MarketSnapshot>,
+    metrics: Option<ConnectionMetrics>,
+    endpoint: Arc<Endpoint>
+}
+
+impl<T> WebSocketFactory<T> where T: KiteTickerHandler {
+    fn handler(&self, ws: Sender) -> WebSocketHandler<T> {
+        WebSocketHandler {
+            ticker: TickerHandle::new(Some(ws), self.subscriptions.clone()),
+            handler: self.handler.clone(),
+            recorder: self.recorder.clone(),
+            snapshot: self.snapshot.clone(),
+            metrics: self.metrics.clone(),
+            endpoint: Some(self.endpoint.clone()),
+            last_error: None
+        }
+    }
 }
 
 
@@ -56,125 +128,124 @@ impl<T> Factory for WebSocketFactory<T> where T: KiteTickerHandler {
     type Handler = WebSocketHandler<T>;
 
     fn connection_made(&mut self, ws: Sender) -> WebSocketHandler<T> {
-        WebSocketHandler {
-            ws: Some(ws),
-            handler: self.handler.clone(),
-            subscribed_tokens: HashMap::new()
-        }
+        self.handler(ws)
     }
 
     fn client_connected(&mut self, ws: Sender) -> WebSocketHandler<T> {
-        WebSocketHandler {
-            ws: Some(ws),
-            handler: self.handler.clone(),
-            subscribed_tokens: HashMap::new()
-        }
+        self.handler(ws)
     }
 }
 
 
 pub struct WebSocketHandler<T> where T: KiteTickerHandler {
     handler: Arc<Mutex<Box<T>>>,
-    ws: Option<Sender>,
-    subscribed_tokens: HashMap<u32, String>
+    ticker: TickerHandle,
+    recorder: Option<Arc<Mutex<TickRecorder>>>,
+    snapshot: Option<MarketSnapshot>,
+    metrics: Option<ConnectionMetrics>,
+    endpoint: Option<Arc<Endpoint>>,
+    last_error: Option<String>
 }
 
 
 impl<T> WebSocketHandler<T> where T: KiteTickerHandler {
     /// Subscribe to a list of instrument_tokens
     pub fn subscribe(&mut self, instrument_tokens: Vec<u32>) -> Result<()> {
-        let data = json!({
-            "a": "subscribe",
-            "v": instrument_tokens
-        });
-
-        for token in &instrument_tokens {
-            self.subscribed_tokens.insert(*token, "quote".to_string());
-        }
-
-        match self.ws {
-            Some(ref s) => {
-                s.send(data.to_string())?;
-                Ok(())
-            },
-            None => {
-                Ok(debug!("Sender not bound to the instance"))
-            }
-        }
+        self.ticker.subscribe(instrument_tokens)
     }
 
     /// Unsubscribe the given list of instrument_tokens
     pub fn unsubscribe(&mut self, instrument_tokens: Vec<u32>) -> Result<()> {
-        let data = json!({
-            "a": "unsubscribe",
-            "v": instrument_tokens
-        });
+        self.ticker.unsubscribe(instrument_tokens)
+    }
 
-        for token in &instrument_tokens {
-            self.subscribed_tokens.remove(token);
-        }
+    /// Subscribe to `EXCHANGE:TRADINGSYMBOL` strings resolved through `index`
+    pub fn subscribe_symbols(&mut self, index: &InstrumentIndex, symbols: &[&str]) -> Result<()> {
+        self.ticker.subscribe_symbols(index, symbols)
+    }
 
-        match self.ws {
-            Some(ref s) => {
-                s.send(data.to_string())?;
-                Ok(())
-            },
-            None => {
-                Ok(debug!("Sender not bound to the instance"))
-            }
-        }
+    /// Unsubscribe the given `EXCHANGE:TRADINGSYMBOL` strings
+    pub fn unsubscribe_symbols(&mut self, index: &InstrumentIndex, symbols: &[&str]) -> Result<()> {
+        self.ticker.unsubscribe_symbols(index, symbols)
+    }
+
+    /// Set streaming mode for the given `EXCHANGE:TRADINGSYMBOL` strings
+    pub fn set_mode_symbols(&mut self, mode: Mode, index: &InstrumentIndex, symbols: &[&str]) -> Result<()> {
+        self.ticker.set_mode_symbols(mode, index, symbols)
     }
 
     /// Resubscribe to all current subscribed tokens
     pub fn resubscribe(&mut self) -> Result<()> {
-        let mut modes: HashMap<String, Vec<u32>> = HashMap::new();
-
-        for (token, mode) in self.subscribed_tokens.iter() {
-            modes.entry(mode.clone()).or_insert(vec![]).push(token.clone());
-        }
+        self.ticker.resubscribe()
+    }
 
-        for (mode, tokens) in modes.iter() {
-            debug!("Resubscribing and set mode: {} - {:?}", mode, tokens);
-            self.subscribe(tokens.clone())?;
-            self.set_mode(mode.as_str(), tokens.clone())?;
-        }
-        Ok(())
+    /// Set streaming mode for the given list of subscribed tokens.
+    pub fn set_mode(&mut self, mode: Mode, instrument_tokens: Vec<u32>) -> Result<()> {
+        self.ticker.set_mode(mode, instrument_tokens)
     }
 
-    /// Set streaming mode for the given list of tokens.
-    pub fn set_mode(&mut self, mode: &str, instrument_tokens: Vec<u32>) -> Result<()> {
-        let data = json!({
-            "a": "mode",
-            "v": [mode.to_string(), instrument_tokens]
-        });
+    /// Subscribe to a list of instrument_tokens and set their mode
+    pub fn subscribe_with_mode(&mut self, mode: Mode, instrument_tokens: Vec<u32>) -> Result<()> {
+        self.ticker.subscribe_with_mode(mode, instrument_tokens)
+    }
 
-        for token in &instrument_tokens {
-            *self.subscribed_tokens.entry(*token).or_insert("".to_string()) = mode.to_string();
-        }
+    /// Returns a handle to this connection which can be moved to other threads
+    pub fn handle(&self) -> TickerHandle {
+        self.ticker.clone()
+    }
 
-        match self.ws {
-            Some(ref s) => {
-                s.send(data.to_string())?;
-                Ok(())
-            }
-            None => {
-                Ok(debug!("Sender not bound to the instance"))
-            }
-        }
+    /// Returns the last websocket error seen on this connection
+    pub fn last_error(&self) -> Option<&str> {
+        self.last_error.as_deref()
     }
 }
 
+/// Builds the websocket url for the given host. Connects to Kite's ticker
+/// over TLS unless the uri carries an explicit `ws://` or `wss://` scheme.
+/// The path and query of the uri are kept.
+pub(crate) fn socket_url(
+    uri: Option<&str>,
+    api_key: &str,
+    access_token: &str
+) -> std::result::Result<Url, url::ParseError> {
+    let uri = uri.unwrap_or("ws.kite.trade");
+    let scheme = if uri.starts_with("ws://") || uri.starts_with("wss://") { "" } else { "wss://" };
+    let url = Url::parse(format!("{}{}", scheme, uri).as_str())?;
+    Ok(endpoint::authorize(&url, api_key, access_token))
+}
+
 /// Implements the Handler trait on KiteTicker which provides all the
 /// callbacks methods ws-rs library
 impl<T> Handler for WebSocketHandler<T> where T: KiteTickerHandler {
 
-    fn build_request(&mut self, url: &url::Url) -> Result<Request> {
+    fn build_request(&mut self, url: &Url) -> Result<Request> {
+        // Through a proxy `url` points at the local tunnel
+        let url = self.endpoint.as_ref().map_or(url, |endpoint| &endpoint.url);
         let mut req = Request::from_url(url)?;
         req.headers_mut().push(("X-Kite-Version".into(), "3".into()));
+        if let Some(ref endpoint) = self.endpoint {
+            for (name, value) in endpoint.headers.iter() {
+                req.headers_mut().push((name.clone(), value.as_bytes().to_vec()));
+            }
+        }
         Ok(req)
     }
 
+    fn upgrade_ssl_client(&mut self, stream: TcpStream, url: &Url) -> Result<SslStream<TcpStream>> {
+        let url = self.endpoint.as_ref().map_or(url, |endpoint| &endpoint.url);
+        let domain = url.domain().ok_or_else(|| {
+            Error::new(ErrorKind::Protocol, format!("Unable to parse domain from {}. Needed for SSL.", url))
+        })?;
+        let connector = SslConnector::builder(SslMethod::tls())
+            .map_err(|err| Error::new(ErrorKind::Internal, format!("Failed to upgrade client to SSL: {}", err)))?
+            .build();
+        connector.connect(domain, stream).map_err(Error::from)
+    }
+
     fn on_open(&mut self, _shake: Handshake) -> Result<()> {
+        if let Some(ref metrics) = self.metrics {
+            metrics.connected();
+        }
         let cloned_handler = self.handler.clone();
         cloned_handler.lock().unwrap().on_open(self);
         debug!("Connection opened!");
@@ -182,144 +253,56 @@ impl<T> Handler for WebSocketHandler<T> where T: KiteTickerHandler {
     }
 
     fn on_message(&mut self, msg: Message) -> Result<()> {
-        if msg.is_binary() && msg.len() > 2 {
-            let mut reader = Cursor::new(msg.clone().into_data());
-            let number_of_packets = reader.read_i16::<BigEndian>().unwrap();
-
-            let mut tick_data: Vec<JsonValue> = Vec::new();
-            let mut packet_length: i16;
-
-            let mut j: u64 = 2;
-            for _ in 0..number_of_packets {
-                packet_length = reader.read_i16::<BigEndian>().unwrap();
-
-                let instrument_token = reader.read_i32::<BigEndian>().unwrap();
-                let segment = instrument_token & 0xFF;
-                let mut divisor: f64 = 100.0;
-                if segment == 3 {  // cds
-                    divisor = 10000000.0;
-                }
-
-                let mut tradable = true;
-                if segment == 9 {  // indices
-                    tradable = false;
-                }
-
-                match packet_length {
-                    // LTP
-                    8 => {
-                        tick_data.push(json!({
-                            "tradable": tradable,
-                            "mode": "ltp",
-                            "instrument_token": instrument_token,
-                            "last_price": reader.read_i32::<BigEndian>().unwrap() as f64 / divisor,
-                        }));
-                    },
-
-                    // Index quote/full
-                    28 | 32 => {
-                        let mut data: JsonValue  = json!({
-                            "tradable": tradable,
-                            "mode": if packet_length == 28 {"quote"} else {"full"},
-                            "instrument_token": instrument_token,
-                            "last_price": reader.read_i32::<BigEndian>().unwrap() as f64 / divisor,
-                            "ohlc": {
-                                "high": reader.read_i32::<BigEndian>().unwrap() as f64 / divisor,
-                                "low": reader.read_i32::<BigEndian>().unwrap() as f64 / divisor,
-                                "open": reader.read_i32::<BigEndian>().unwrap() as f64 / divisor,
-                                "close": reader.read_i32::<BigEndian>().unwrap() as f64 / divisor,
-                            },
-                            "change": 0
-                        });
-
-                        if data["ohlc"]["close"] != 0 {
-                            let last_price: f64 = data["last_price"].as_f64().unwrap();
-                            let ohlc_close: f64 = data["ohlc"]["close"].as_f64().unwrap();
-                            data["change"] = json!((last_price - ohlc_close) * 100 as f64 / ohlc_close);
-                        }
-
-                        if packet_length == 32 {  // timestamp incase of full
-                            data["timestamp"] = json!(reader.read_i32::<BigEndian>().unwrap() as f64 / divisor);
-                        }
-
-                        tick_data.push(data);
-                    },
-
-                    // Quote/Full
-                    44 | 184 => {
-                        let mut data: JsonValue = json!({
-                            "tradable": tradable,
-                            "mode": if packet_length == 44 {"quote"} else {"full"},
-                            "instrument_token": instrument_token,
-                            "last_price": reader.read_i32::<BigEndian>().unwrap() as f64 / divisor,
-                            "last_quantity": reader.read_i32::<BigEndian>().unwrap() as f64,
-                            "average_price": reader.read_i32::<BigEndian>().unwrap() as f64 / divisor,
-                            "volume": reader.read_i32::<BigEndian>().unwrap() as f64,
-                            "buy_quantity": reader.read_i32::<BigEndian>().unwrap() as f64,
-                            "sell_quantity": reader.read_i32::<BigEndian>().unwrap() as f64,
-                            "ohlc": {
-                                "open": reader.read_i32::<BigEndian>().unwrap() as f64 / divisor,
-                                "high": reader.read_i32::<BigEndian>().unwrap() as f64 / divisor,
-                                "low": reader.read_i32::<BigEndian>().unwrap() as f64 / divisor,
-                                "close": reader.read_i32::<BigEndian>().unwrap() as f64 / divisor
-                            }
-                        });
-
-                        if data["ohlc"]["close"] != 0 {
-                            let last_price: f64 = data["last_price"].as_f64().unwrap();
-                            let ohlc_close: f64 = data["ohlc"]["close"].as_f64().unwrap();
-                            data["change"] = json!((last_price - ohlc_close) * 100 as f64 / ohlc_close);
-                        }
-
-                        if packet_length == 184 {
-                            data["last_trade_time"] = json!(reader.read_i32::<BigEndian>().unwrap() as f64);
-                            data["oi"] = json!(reader.read_i32::<BigEndian>().unwrap() as f64);
-                            data["oi_day_high"] = json!(reader.read_i32::<BigEndian>().unwrap() as f64);
-                            data["oi_day_low"] = json!(reader.read_i32::<BigEndian>().unwrap() as f64);
-                            data["timestamp"] = json!(reader.read_i32::<BigEndian>().unwrap() as f64);
-
-                            // XXX We have already read 64 bytes now, Remaining 184-64/12 = 10
-                            let mut buy_depth_data: Vec<JsonValue> = Vec::with_capacity(5);
-                            let mut sell_depth_data: Vec<JsonValue> = Vec::with_capacity(5);
-                            for index in 0..10 {
-                                let depth_data = json!({
-                                    "quantity": reader.read_i32::<BigEndian>().unwrap() as f64,
-                                    "price": reader.read_i32::<BigEndian>().unwrap() as f64 / divisor,
-                                    "orders": reader.read_i16::<BigEndian>().unwrap() as f64
-                                });
-
-                                if index < 5 {
-                                    buy_depth_data.push(depth_data);
-                                } else {
-                                    sell_depth_data.push(depth_data);
-                                }
-
-                                // Dont care 2 bytes padding
-                                reader.read_i16::<BigEndian>().unwrap();
-                            }
-                            data["sell"] = json!(sell_depth_data);
-                            data["buy"] = json!(buy_depth_data);
-                        }
-
-                        tick_data.push(data);
-                    }
+        let received = recorder::now();
+        if let Some(ref recorder) = self.recorder {
+            let mut recorder = recorder.lock().unwrap_or_else(|err| err.into_inner());
+            if let Err(err) = recorder.record(received, &msg) {
+                debug!("Unable to record frame: {}", err);
+            }
+        }
+        if let Some(ref metrics) = self.metrics {
+            metrics.frame(msg.len());
+        }
 
-                    _ => {
-                        debug!("undefined packet length received: {}", packet_length)
-                    }
+        if msg.is_binary() && msg.len() > 2 {
+            let started = Instant::now();
+            let tick_data = parse_binary(&msg.into_data()).inspect_err(|_| {
+                if let Some(ref metrics) = self.metrics {
+                    metrics.error();
                 }
-
-                // Place reader in the position after the packet length
-                reader.seek(SeekFrom::Start(j+2+packet_length as u64))?;
-
-                j = j+2+packet_length as u64;
+            })?;
+            if let Some(ref metrics) = self.metrics {
+                metrics.ticks(started.elapsed(), received, tick_data.iter().map(|tick| tick.exchange_timestamp));
+            }
+            if let Some(ref snapshot) = self.snapshot {
+                snapshot.update(&tick_data);
             }
 
+            let started = Instant::now();
             let cloned_handler = self.handler.clone();
             cloned_handler.lock().unwrap().on_ticks(self, tick_data);
+            if let Some(ref metrics) = self.metrics {
+                metrics.callback(started.elapsed());
+            }
         } else if msg.is_text() {
-            // TODO: Handle text messages
-            println!("text message received {:?}", msg)
+            let text = msg.into_text()?;
+            match serde_json::from_str::<JsonValue>(&text) {
+                Ok(message) => {
+                    let started = Instant::now();
+                    let cloned_handler = self.handler.clone();
+                    let mut handler = cloned_handler.lock().unwrap();
+                    if message["type"] == "order" {
+                        handler.on_order_update(self, message["data"].clone());
+                    } else {
+                        handler.on_message(self, message);
+                    }
+                    if let Some(ref metrics) = self.metrics {
+                        metrics.message();
+                        metrics.callback(started.elapsed());
+                    }
+                },
+                Err(err) => debug!("Unable to parse text message {:?}: {}", text, err)
+            }
         }
 
         Ok(())
@@ -332,6 +315,10 @@ impl<T> Handler for WebSocketHandler<T> where T: KiteTickerHandler {
     }
 
     fn on_error(&mut self, err: Error) {
+        self.last_error = Some(err.to_string());
+        if let Some(ref metrics) = self.metrics {
+            metrics.error();
+        }
         let cloned_handler = self.handler.clone();
         cloned_handler.lock().unwrap().on_error(self);
         debug!("Error {:?}", err);
@@ -341,9 +328,15 @@ impl<T> Handler for WebSocketHandler<T> where T: KiteTickerHandler {
 
 
 pub struct KiteTicker {
-    sender: Option<Sender>,
+    handle: Option<TickerHandle>,
+    thread: Option<JoinHandle<Result<()>>>,
+    recorder: Option<Arc<Mutex<TickRecorder>>>,
+    snapshot: Option<MarketSnapshot>,
+    metrics: Option<ConnectionMetrics>,
+    headers: Vec<(String, String)>,
+    proxy: Option<Url>,
     api_key: String,
-    access_token: String,
+    access_token: AccessToken,
 }
 
 
@@ -352,41 +345,200 @@ impl KiteTicker {
 
     /// Constructor
     pub fn new(api_key: &str, access_token: &str) -> KiteTicker {
+        KiteTicker::with_access_token(api_key, AccessToken::new(access_token))
+    }
+
+    /// Ticker that connects with the current token of a `KiteConnect`
+    pub(crate) fn with_access_token(api_key: &str, access_token: AccessToken) -> KiteTicker {
         KiteTicker {
-            sender: None,
+            handle: None,
+            thread: None,
+            recorder: None,
+            snapshot: None,
+            metrics: None,
+            headers: Vec::new(),
+            proxy: None,
             api_key: api_key.to_string(),
-            access_token: access_token.to_string(),
+            access_token,
         }
     }
 
-    /// Creates a websocket and delegates to it to child thread. Also sets the
-    /// broadcaster so that other methods can easily send message on this socket
-    pub fn connect<F>(&mut self, handler: F, uri: Option<&str>) -> Result<()>
+    /// Creates a websocket and delegates to it to child thread. Returns a
+    /// handle bound to the broadcaster so that subscriptions can be managed
+    /// from any thread. A previous connection of this ticker is shut down first.
+    ///
+    /// `uri` defaults to Kite's ticker and may be a host or a full `ws://` or
+    /// `wss://` url.
+    pub fn connect<F>(&mut self, handler: F, uri: Option<&str>) -> Result<TickerHandle>
+        where F: KiteTickerHandler + Send + 'static {
+        let url = socket_url(uri, &self.api_key, &self.access_token.get())
+            .map_err(|err| Error::new(ErrorKind::Internal, format!("Invalid ticker url: {}", err)))?;
+        self.connect_to(handler, url)
+    }
+
+    /// Connects like `connect` to a websocket gateway at `url`, keeping its
+    /// scheme, port, path and query. The credentials are added to the query.
+    pub fn connect_url<F>(&mut self, handler: F, url: &Url) -> Result<TickerHandle>
         where F: KiteTickerHandler + Send + 'static {
+        let url = endpoint::authorize(url, &self.api_key, &self.access_token.get());
+        self.connect_to(handler, url)
+    }
+
+    fn connect_to<F>(&mut self, handler: F, url: Url) -> Result<TickerHandle>
+        where F: KiteTickerHandler + Send + 'static {
+        self.shutdown(None);
+
+        let subscriptions = Arc::new(Mutex::new(Subscriptions::default()));
         let factory = WebSocketFactory {
-            handler: Arc::new(Mutex::new(Box::new(handler)))
+            handler: Arc::new(Mutex::new(Box::new(handler))),
+            subscriptions: subscriptions.clone(),
+            recorder: self.recorder.clone(),
+            snapshot: self.snapshot.clone(),
+            metrics: self.metrics.clone(),
+            endpoint: Arc::new(Endpoint { url: url.clone(), headers: self.headers.clone() })
+        };
+        let mut ws = WebSocket::new(factory)?;
+        let handle = TickerHandle::new(Some(ws.broadcaster()), subscriptions);
+        let url = match self.proxy {
+            Some(ref proxy) => endpoint::tunnel(proxy, &url)?,
+            None => url
         };
-        let mut ws = WebSocket::new(factory).unwrap();
-        let sender = ws.broadcaster();
-        let socket_url = format!(
-            "wss://{}?api_key={}&access_token={}",
-            match uri {
-                Some(uri) => uri,
-                None => "ws.kite.trade"
-            },
-            self.api_key,
-            self.access_token
-        );
-        let url = url::Url::parse(socket_url.as_str()).unwrap();
-
-        ws.connect(url.clone()).unwrap();
-        thread::spawn(|| ws.run().unwrap());
-
-        self.sender = Some(sender);
 
+        ws.connect(url)?;
+        self.thread = Some(thread::spawn(move || ws.run().map(|_| ())));
+        self.handle = Some(handle.clone());
+
+        Ok(handle)
+    }
+
+    /// Connects like `connect` but delivers every callback as a `TickerEvent`
+    /// through a bounded buffer of `capacity` events. `backpressure` decides
+    /// what happens when the consumer falls behind.
+    pub fn stream(
+        &mut self,
+        capacity: usize,
+        backpressure: Backpressure,
+        uri: Option<&str>
+    ) -> Result<(TickerHandle, TickerStream)> {
+        let (sender, stream) = stream::channel(capacity, backpressure);
+        let handle = self.connect(ChannelHandler { sender }, uri)?;
+        Ok((handle, stream))
+    }
+
+    /// Connects like `connect` with a handler chosen at runtime, such as a
+    /// `FanOut` of several handlers
+    pub fn connect_dyn(&mut self, handler: Box<dyn TickerHandler>, uri: Option<&str>) -> Result<TickerHandle> {
+        self.connect(DynHandler(handler), uri)
+    }
+
+    /// Adds a header to the handshake of the next connections, e.g. for
+    /// authenticating with a websocket gateway
+    pub fn set_header(&mut self, name: &str, value: &str) {
+        self.headers.push((name.to_string(), value.to_string()));
+    }
+
+    /// Connects through an HTTP proxy, given as `http://[user:password@]host:port`,
+    /// using CONNECT
+    pub fn set_proxy(&mut self, proxy: &str) -> Result<()> {
+        let proxy = Url::parse(proxy)
+            .map_err(|err| Error::new(ErrorKind::Internal, format!("Invalid proxy url: {}", err)))?;
+        if proxy.scheme() != "http" || proxy.host_str().is_none() {
+            return Err(Error::new(ErrorKind::Internal, format!("Unsupported proxy {}", proxy)));
+        }
+        self.proxy = Some(proxy);
         Ok(())
     }
 
+    /// Records every frame received from the next connection on, before it
+    /// is decoded and handed to the handler
+    pub fn set_recorder(&mut self, recorder: TickRecorder) {
+        self.recorder = Some(Arc::new(Mutex::new(recorder)));
+    }
+
+    /// Keeps `snapshot` up to date with every tick from the next connection
+    /// on, before the ticks are handed to the handler
+    pub fn set_snapshot(&mut self, snapshot: MarketSnapshot) {
+        self.snapshot = Some(snapshot);
+    }
+
+    /// Counts frames, ticks, parse and callback time and tick latency of
+    /// the next connections in `metrics`
+    pub fn set_metrics(&mut self, metrics: ConnectionMetrics) {
+        self.metrics = Some(metrics);
+    }
+
+    /// Returns the handle of the current connection, if connected
+    pub fn handle(&self) -> Option<TickerHandle> {
+        self.handle.clone()
+    }
+
+    /// Whether the websocket thread is still running
+    pub fn is_running(&self) -> bool {
+        self.thread.as_ref().is_some_and(|thread| !thread.is_finished())
+    }
+
+    /// Sends a close frame to the server. Use `join` to wait for the
+    /// websocket thread to finish the close handshake and exit.
+    pub fn close(&self) -> Result<()> {
+        match self.handle {
+            Some(ref handle) => handle.close(),
+            None => Ok(())
+        }
+    }
+
+    /// Blocks until the websocket thread exits and returns the result of its
+    /// event loop. Returns immediately if the ticker was never connected.
+    pub fn join(&mut self) -> Result<()> {
+        match self.thread.take() {
+            Some(thread) => thread.join().unwrap_or_else(|_| {
+                Err(Error::new(ErrorKind::Internal, "Ticker thread panicked"))
+            }),
+            None => Ok(())
+        }
+    }
+
+    /// Stops the event loop of a running connection and waits for the thread,
+    /// giving up after `timeout` if one is given. The thread is never joined
+    /// from itself, as when a handler drops its own ticker.
+    fn shutdown(&mut self, timeout: Option<Duration>) {
+        let thread = match self.thread.take() {
+            Some(thread) => thread,
+            None => return
+        };
+        if let Some(ref handle) = self.handle {
+            // Fails only when the event loop has already exited
+            let _ = handle.shutdown();
+        }
+        if thread.thread().id() == thread::current().id() {
+            return;
+        }
+        if let Some(timeout) = timeout {
+            let deadline = Instant::now() + timeout;
+            while !thread.is_finished() && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(10));
+            }
+            if !thread.is_finished() {
+                debug!("Ticker thread did not exit within {:?}, leaving it behind", timeout);
+                return;
+            }
+        }
+        self.thread = Some(thread);
+        if let Err(err) = self.join() {
+            debug!("Ticker exited with error {:?}", err);
+        }
+    }
+
+}
+
+/// How long dropping a ticker waits for its websocket thread
+const DROP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Dropping a connected ticker shuts the websocket down and waits a bounded
+/// time for its thread, so handles obtained from it stop working as well.
+impl Drop for KiteTicker {
+    fn drop(&mut self) {
+        self.shutdown(Some(DROP_TIMEOUT));
+    }
 }
 
 #[cfg(test)]
@@ -411,14 +563,168 @@ mod tests {
     fn test_kite_ticker() {
         thread::spawn(move || {
             listen("127.0.0.1:3012", |out| {
-                Server { out: out }
+                Server { out }
             }).unwrap()
         });
 
         struct MyHandler;
         impl KiteTickerHandler for MyHandler {}
         let mut kiteticker = KiteTicker::new("<API-KEY>", "<ACCESS-TOKEN>");
-        kiteticker.connect(MyHandler{}, Some("127.0.0.1:3012"));
-        kiteticker.sender.unwrap().send("PING");
+        kiteticker.connect(MyHandler{}, Some("127.0.0.1:3012")).unwrap();
+        kiteticker.handle().unwrap().sender.unwrap().send("PING").unwrap();
+    }
+
+    #[test]
+    fn test_close_and_join() {
+        let server = WebSocket::new(|out: Sender| {
+            move |msg: Message| out.send(msg)
+        }).unwrap().bind("127.0.0.1:0").unwrap();
+        let address = server.local_addr().unwrap();
+        thread::spawn(move || server.run());
+
+        struct MyHandler {
+            opened: std::sync::mpsc::Sender<()>
+        }
+        impl KiteTickerHandler for MyHandler {
+            fn on_open<T>(&mut self, _ws: &mut WebSocketHandler<T>)
+            where T: KiteTickerHandler {
+                self.opened.send(()).unwrap();
+            }
+        }
+
+        let (opened, on_open) = std::sync::mpsc::channel();
+        let mut kiteticker = KiteTicker::new("<API-KEY>", "<ACCESS-TOKEN>");
+        let uri = format!("ws://{}", address);
+        kiteticker.connect(MyHandler { opened }, Some(uri.as_str())).unwrap();
+
+        on_open.recv_timeout(std::time::Duration::from_secs(5)).unwrap();
+        assert!(kiteticker.is_running());
+        kiteticker.close().unwrap();
+        kiteticker.join().unwrap();
+        assert!(!kiteticker.is_running());
+    }
+
+    #[test]
+    fn test_drop_from_handler() {
+        use std::sync::mpsc;
+
+        let server = WebSocket::new(|out: Sender| {
+            move |msg: Message| out.send(msg)
+        }).unwrap().bind("127.0.0.1:0").unwrap();
+        let address = server.local_addr().unwrap();
+        thread::spawn(move || server.run());
+
+        // Receives its own ticker once connected and drops it on the socket thread
+        struct MyHandler {
+            ticker: mpsc::Receiver<KiteTicker>,
+            dropped: mpsc::Sender<()>,
+        }
+        impl KiteTickerHandler for MyHandler {
+            fn on_open<T>(&mut self, _ws: &mut WebSocketHandler<T>)
+            where T: KiteTickerHandler {
+                drop(self.ticker.recv().unwrap());
+                self.dropped.send(()).unwrap();
+            }
+        }
+
+        let (send_ticker, ticker) = mpsc::channel();
+        let (dropped, on_dropped) = mpsc::channel();
+        let mut kiteticker = KiteTicker::new("<API-KEY>", "<ACCESS-TOKEN>");
+        let uri = format!("ws://{}", address);
+        kiteticker.connect(MyHandler { ticker, dropped }, Some(uri.as_str())).unwrap();
+        send_ticker.send(kiteticker).unwrap();
+
+        on_dropped.recv_timeout(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn test_connect_url_through_proxy() {
+        use std::io::{self, Read, Write};
+        use std::net::{Shutdown, TcpListener, TcpStream};
+        use std::sync::mpsc;
+        use std::time::Duration;
+
+        // Gateway reporting the handshake it receives
+        struct Gateway {
+            requests: mpsc::Sender<(String, Option<Vec<u8>>)>,
+        }
+        impl Handler for Gateway {
+            fn on_request(&mut self, req: &Request) -> Result<ws::Response> {
+                let token = req.header("x-gateway-token").cloned();
+                self.requests.send((req.resource().to_string(), token)).unwrap();
+                ws::Response::from_request(req)
+            }
+        }
+        let (requests, handshakes) = mpsc::channel();
+        let gateway = WebSocket::new(move |_| Gateway { requests: requests.clone() })
+            .unwrap().bind("127.0.0.1:0").unwrap();
+        let gateway_address = gateway.local_addr().unwrap();
+        thread::spawn(move || gateway.run());
+
+        // Proxy relaying a single CONNECT tunnel
+        let proxy = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy_address = proxy.local_addr().unwrap();
+        let (connects, connected) = mpsc::channel();
+        thread::spawn(move || {
+            let (mut client, _) = proxy.accept().unwrap();
+            let mut request = Vec::new();
+            let mut byte = [0u8; 1];
+            while !request.ends_with(b"\r\n\r\n") {
+                client.read_exact(&mut byte).unwrap();
+                request.push(byte[0]);
+            }
+            let request = String::from_utf8(request).unwrap();
+            let target = request.split_whitespace().nth(1).unwrap().to_string();
+            connects.send(target.clone()).unwrap();
+
+            let upstream = TcpStream::connect(target).unwrap();
+            client.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n").unwrap();
+            let (mut client_reader, mut upstream_writer) = (client.try_clone().unwrap(), upstream.try_clone().unwrap());
+            thread::spawn(move || {
+                let _ = io::copy(&mut client_reader, &mut upstream_writer);
+                let _ = upstream_writer.shutdown(Shutdown::Write);
+            });
+            let (mut upstream_reader, mut client_writer) = (upstream, client);
+            let _ = io::copy(&mut upstream_reader, &mut client_writer);
+            let _ = client_writer.shutdown(Shutdown::Both);
+        });
+
+        struct MyHandler {
+            opened: mpsc::Sender<()>
+        }
+        impl KiteTickerHandler for MyHandler {
+            fn on_open<T>(&mut self, _ws: &mut WebSocketHandler<T>)
+            where T: KiteTickerHandler {
+                self.opened.send(()).unwrap();
+            }
+        }
+
+        let (opened, on_open) = mpsc::channel();
+        let mut kiteticker = KiteTicker::new("KEY", "TOKEN");
+        kiteticker.set_header("X-Gateway-Token", "secret");
+        kiteticker.set_proxy(&format!("http://{}", proxy_address)).unwrap();
+        assert!(kiteticker.set_proxy("socks5://127.0.0.1:1080").is_err());
+        let url = Url::parse(&format!("ws://{}/kite/feed?region=in", gateway_address)).unwrap();
+        kiteticker.connect_url(MyHandler { opened }, &url).unwrap();
+
+        let timeout = Duration::from_secs(5);
+        assert_eq!(connected.recv_timeout(timeout).unwrap(), gateway_address.to_string());
+        let (resource, token) = handshakes.recv_timeout(timeout).unwrap();
+        assert_eq!(resource, "/kite/feed?region=in&api_key=KEY&access_token=TOKEN");
+        assert_eq!(token, Some(b"secret".to_vec()));
+        on_open.recv_timeout(timeout).unwrap();
+
+        kiteticker.close().unwrap();
+        kiteticker.join().unwrap();
+    }
+
+    #[test]
+    fn test_ticker_follows_renewed_token() {
+        let mut kiteconnect = crate::connect::KiteConnect::new("KEY", "EXPIRED");
+        let ticker = kiteconnect.ticker();
+        kiteconnect.set_access_token("RENEWED");
+
+        let url = socket_url(None, &ticker.api_key, &ticker.access_token.get()).unwrap();
+        assert_eq!(url.query(), Some("api_key=KEY&access_token=RENEWED"));
     }
 }
@@ -0,0 +1,283 @@
+use std::collections::{HashMap, VecDeque};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use anyhow::{anyhow, Result};
+use futures::stream::SplitStream;
+use futures::{SinkExt, Stream, StreamExt};
+use log::debug;
+use serde_json::{json, Value as JsonValue};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::http::HeaderValue;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+
+use super::tick::parse_binary;
+use super::{socket_url, Mode, Subscriptions, TickerEvent};
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Ticker running on the caller's tokio runtime instead of a dedicated
+/// thread. Ticks and connection events are exposed as a `futures::Stream`.
+pub struct AsyncKiteTicker {
+    api_key: String,
+    access_token: String,
+}
+
+impl AsyncKiteTicker {
+
+    /// Constructor
+    pub fn new(api_key: &str, access_token: &str) -> AsyncKiteTicker {
+        AsyncKiteTicker {
+            api_key: api_key.to_string(),
+            access_token: access_token.to_string(),
+        }
+    }
+
+    /// Connects to the ticker and returns a handle to manage subscriptions
+    /// along with the stream of events. Must be called from within a tokio
+    /// runtime since the socket writer is spawned as a task.
+    pub async fn connect(&self, uri: Option<&str>) -> Result<(AsyncTickerHandle, AsyncTickerStream)> {
+        let url = socket_url(uri, &self.api_key, &self.access_token)?;
+        let mut request = url.as_str().into_client_request()?;
+        request.headers_mut().insert("X-Kite-Version", HeaderValue::from_static("3"));
+
+        let (socket, _) = connect_async(request).await?;
+        let (mut sink, stream) = socket.split();
+        let (commands, mut receiver) = mpsc::channel::<Message>(64);
+
+        tokio::spawn(async move {
+            while let Some(message) = receiver.recv().await {
+                let closing = matches!(message, Message::Close(_));
+                if let Err(err) = sink.send(message).await {
+                    debug!("Unable to write to the ticker socket: {}", err);
+                    break;
+                }
+                if closing {
+                    break;
+                }
+            }
+        });
+
+        let handle = AsyncTickerHandle {
+            commands,
+            subscriptions: Arc::new(Mutex::new(Subscriptions::default())),
+        };
+        let stream = AsyncTickerStream {
+            socket: stream,
+            pending: vec![TickerEvent::Connected].into(),
+            finished: false,
+        };
+        Ok((handle, stream))
+    }
+}
+
+/// Cloneable handle to change subscriptions of an `AsyncKiteTicker`
+#[derive(Clone)]
+pub struct AsyncTickerHandle {
+    commands: mpsc::Sender<Message>,
+    subscriptions: Arc<Mutex<Subscriptions>>,
+}
+
+impl AsyncTickerHandle {
+    async fn send(&self, data: JsonValue) -> Result<()> {
+        self.commands.send(Message::text(data.to_string())).await
+            .map_err(|_| anyhow!("Ticker connection is closed"))
+    }
+
+    /// Subscribe to a list of instrument_tokens. Fails without sending
+    /// anything if the connection would exceed `MAX_TOKENS_PER_CONNECTION`.
+    /// Tokens are only registered once the request is queued.
+    pub async fn subscribe(&self, instrument_tokens: Vec<u32>) -> Result<()> {
+        self.subscriptions.lock().unwrap().check_subscribe(&instrument_tokens)
+            .map_err(|err| anyhow!("{}", err))?;
+        self.send(json!({"a": "subscribe", "v": instrument_tokens})).await?;
+        self.subscriptions.lock().unwrap().subscribe(&instrument_tokens)
+            .map_err(|err| anyhow!("{}", err))
+    }
+
+    /// Unsubscribe the given list of instrument_tokens
+    pub async fn unsubscribe(&self, instrument_tokens: Vec<u32>) -> Result<()> {
+        self.send(json!({"a": "unsubscribe", "v": instrument_tokens})).await?;
+        self.subscriptions.lock().unwrap().unsubscribe(&instrument_tokens);
+        Ok(())
+    }
+
+    /// Set streaming mode for the given list of subscribed tokens.
+    pub async fn set_mode(&self, mode: Mode, instrument_tokens: Vec<u32>) -> Result<()> {
+        self.subscriptions.lock().unwrap().check_subscribed(&instrument_tokens)
+            .map_err(|err| anyhow!("{}", err))?;
+        self.send(json!({"a": "mode", "v": [mode.as_str(), instrument_tokens]})).await?;
+        self.subscriptions.lock().unwrap().set_mode(mode, &instrument_tokens)
+            .map_err(|err| anyhow!("{}", err))
+    }
+
+    /// Subscribe to a list of instrument_tokens and set their mode. Both
+    /// messages are queued together so they reach the socket back to back.
+    pub async fn subscribe_with_mode(&self, mode: Mode, instrument_tokens: Vec<u32>) -> Result<()> {
+        self.subscriptions.lock().unwrap().check_subscribe(&instrument_tokens)
+            .map_err(|err| anyhow!("{}", err))?;
+
+        let permits = self.commands.reserve_many(2).await
+            .map_err(|_| anyhow!("Ticker connection is closed"))?;
+        {
+            let mut subscriptions = self.subscriptions.lock().unwrap();
+            subscriptions.subscribe(&instrument_tokens).map_err(|err| anyhow!("{}", err))?;
+            subscriptions.set_mode(mode, &instrument_tokens).map_err(|err| anyhow!("{}", err))?;
+        }
+        let messages = [
+            json!({"a": "subscribe", "v": instrument_tokens}),
+            json!({"a": "mode", "v": [mode.as_str(), instrument_tokens]}),
+        ];
+        for (permit, data) in permits.zip(messages.iter()) {
+            permit.send(Message::text(data.to_string()));
+        }
+        Ok(())
+    }
+
+    /// Resubscribe to all current subscribed tokens
+    pub async fn resubscribe(&self) -> Result<()> {
+        let modes: HashMap<Option<Mode>, Vec<u32>> = self.subscriptions.lock().unwrap().by_mode();
+
+        for (mode, tokens) in modes {
+            self.send(json!({"a": "subscribe", "v": tokens})).await?;
+            if let Some(mode) = mode {
+                self.send(json!({"a": "mode", "v": [mode.as_str(), tokens]})).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the subscribed instrument_tokens along with their mode
+    pub fn subscriptions(&self) -> HashMap<u32, Option<Mode>> {
+        self.subscriptions.lock().unwrap().tokens().clone()
+    }
+
+    /// Sends a close frame. The stream ends once the server acknowledges it.
+    pub async fn close(&self) -> Result<()> {
+        self.commands.send(Message::Close(None)).await
+            .map_err(|_| anyhow!("Ticker connection is closed"))
+    }
+}
+
+/// Stream of `TickerEvent` read from an `AsyncKiteTicker` connection. Ends
+/// after yielding `TickerEvent::Disconnected`.
+pub struct AsyncTickerStream {
+    socket: SplitStream<Socket>,
+    pending: VecDeque<TickerEvent>,
+    finished: bool,
+}
+
+impl Stream for AsyncTickerStream {
+    type Item = TickerEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<TickerEvent>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(event) = this.pending.pop_front() {
+                return Poll::Ready(Some(event));
+            }
+            if this.finished {
+                return Poll::Ready(None);
+            }
+
+            let message = match this.socket.poll_next_unpin(cx) {
+                Poll::Ready(message) => message,
+                Poll::Pending => return Poll::Pending,
+            };
+            match message {
+                Some(Ok(Message::Binary(data))) => {
+                    if data.len() > 2 {
+                        match parse_binary(&data) {
+                            Ok(ticks) => this.pending.extend(ticks.into_iter().map(TickerEvent::Tick)),
+                            Err(err) => this.pending.push_back(TickerEvent::Error(err.to_string())),
+                        }
+                    }
+                },
+                Some(Ok(Message::Text(text))) => {
+                    match serde_json::from_str::<JsonValue>(&text) {
+                        Ok(message) => this.pending.push_back(TickerEvent::from_message(message)),
+                        Err(err) => debug!("Unable to parse text message {:?}: {}", text, err),
+                    }
+                },
+                Some(Ok(Message::Close(_))) | None => {
+                    this.pending.push_back(TickerEvent::Disconnected);
+                    this.finished = true;
+                },
+                Some(Ok(_)) => {},
+                Some(Err(err)) => {
+                    this.pending.push_back(TickerEvent::Error(err.to_string()));
+                    this.pending.push_back(TickerEvent::Disconnected);
+                    this.finished = true;
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ticker::Tick;
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::accept_async;
+
+    #[tokio::test]
+    async fn test_async_ticker() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut socket = accept_async(stream).await.unwrap();
+
+            let subscribe = socket.next().await.unwrap().unwrap();
+            assert_eq!(subscribe.into_text().unwrap().as_str(), r#"{"a":"subscribe","v":[408065]}"#);
+
+            // Single LTP packet for token 408065 at 1234.50
+            let mut frame = vec![0, 1, 0, 8];
+            frame.extend_from_slice(&408065_i32.to_be_bytes());
+            frame.extend_from_slice(&123450_i32.to_be_bytes());
+            socket.send(Message::binary(frame)).await.unwrap();
+            socket.send(Message::text(r#"{"type":"order","data":{"order_id":"1"}}"#)).await.unwrap();
+            socket.close(None).await.unwrap();
+        });
+
+        let ticker = AsyncKiteTicker::new("<API-KEY>", "<ACCESS-TOKEN>");
+        let uri = format!("ws://{}", address);
+        let (handle, stream) = ticker.connect(Some(uri.as_str())).await.unwrap();
+        handle.subscribe(vec![408065]).await.unwrap();
+
+        let events: Vec<TickerEvent> = stream.collect().await;
+        server.await.unwrap();
+
+        assert_eq!(events[0], TickerEvent::Connected);
+        assert_eq!(events[1], TickerEvent::Tick(Tick {
+            instrument_token: 408065,
+            last_price: 1234.5,
+            ..Tick::default()
+        }));
+        assert_eq!(events[2], TickerEvent::OrderUpdate(json!({"order_id": "1"})));
+        assert_eq!(events.last(), Some(&TickerEvent::Disconnected));
+    }
+
+    #[tokio::test]
+    async fn test_failed_send_keeps_registry() {
+        let (commands, receiver) = mpsc::channel(4);
+        let handle = AsyncTickerHandle {
+            commands,
+            subscriptions: Arc::new(Mutex::new(Subscriptions::default())),
+        };
+        handle.subscribe(vec![1]).await.unwrap();
+        drop(receiver);
+
+        assert!(handle.subscribe(vec![2]).await.is_err());
+        assert!(handle.subscribe_with_mode(Mode::Full, vec![3]).await.is_err());
+        assert!(handle.set_mode(Mode::Full, vec![1]).await.is_err());
+        assert!(handle.unsubscribe(vec![1]).await.is_err());
+        assert_eq!(handle.subscriptions(), vec![(1, None)].into_iter().collect());
+    }
+}
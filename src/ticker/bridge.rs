@@ -0,0 +1,553 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+use log::debug;
+use serde_json::{json, Value as JsonValue};
+use url::Url;
+use ws::{CloseCode, Handler, Message, Sender, WebSocket};
+
+use super::metrics::{read_request, REQUEST_TIMEOUT};
+use super::{Mode, Tick, TickerHandle, TickerHandler, MAX_TOKENS_PER_CONNECTION};
+
+/// Messages buffered for a server-sent events client before it is dropped
+const SSE_BUFFER: usize = 1024;
+
+/// Interval of keep alive comments on idle server-sent event streams
+const SSE_KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+/// Event stream connections served at once, further ones get a 503
+const MAX_SSE_CLIENTS: usize = 64;
+
+enum Sink {
+    Ws(Sender),
+    Sse(SyncSender<String>),
+}
+
+impl Sink {
+    /// Queues a message, false once the client is gone or too slow
+    fn send(&self, message: &str) -> bool {
+        match self {
+            Sink::Ws(out) => out.send(message).is_ok(),
+            Sink::Sse(out) => match out.try_send(message.to_string()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    debug!("Dropping server-sent events client that fell behind");
+                    false
+                },
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        }
+    }
+}
+
+struct Client {
+    sink: Sink,
+    tokens: HashMap<u32, Mode>,
+}
+
+#[derive(Default)]
+struct State {
+    ticker: Option<TickerHandle>,
+    clients: HashMap<u64, Client>,
+    next_id: u64,
+    /// Instruments subscribed upstream along with their mode
+    upstream: HashMap<u32, Mode>,
+}
+
+impl State {
+    /// Union of the clients' instruments with the highest mode asked for
+    fn desired(&self) -> HashMap<u32, Mode> {
+        let mut desired: HashMap<u32, Mode> = HashMap::new();
+        for client in self.clients.values() {
+            for (token, mode) in client.tokens.iter() {
+                let wanted = desired.entry(*token).or_insert(*mode);
+                *wanted = (*wanted).max(*mode);
+            }
+        }
+        desired
+    }
+
+    /// Checks that adding `tokens` keeps the upstream connection within
+    /// `MAX_TOKENS_PER_CONNECTION`
+    fn check_limit(&self, tokens: &[u32]) -> ws::Result<()> {
+        let desired = self.desired();
+        let mut new_tokens: Vec<u32> = tokens.iter()
+            .filter(|token| !desired.contains_key(token))
+            .cloned()
+            .collect();
+        new_tokens.sort_unstable();
+        new_tokens.dedup();
+        if desired.len() + new_tokens.len() > MAX_TOKENS_PER_CONNECTION {
+            return Err(ws::Error::new(
+                ws::ErrorKind::Capacity,
+                format!("Subscribing to {} more instruments exceeds the limit of {}", new_tokens.len(), MAX_TOKENS_PER_CONNECTION)
+            ));
+        }
+        Ok(())
+    }
+
+    /// Brings the upstream subscriptions in line with what the clients want,
+    /// upgrading modes as needed. `upstream` records each change once it has
+    /// been sent, so a failure part way leaves it matching the feed.
+    fn sync(&mut self) -> ws::Result<()> {
+        let desired = self.desired();
+        let ticker = match self.ticker {
+            Some(ref ticker) => ticker.clone(),
+            None => {
+                // Sent by `on_open` once connected
+                self.upstream = desired;
+                return Ok(());
+            }
+        };
+
+        let removed: Vec<u32> = self.upstream.keys()
+            .filter(|token| !desired.contains_key(token))
+            .cloned()
+            .collect();
+        let mut added: HashMap<Mode, Vec<u32>> = HashMap::new();
+        let mut changed: HashMap<Mode, Vec<u32>> = HashMap::new();
+        for (token, mode) in desired.iter() {
+            match self.upstream.get(token) {
+                None => added.entry(*mode).or_default().push(*token),
+                Some(current) if current != mode => changed.entry(*mode).or_default().push(*token),
+                _ => {}
+            }
+        }
+
+        if !removed.is_empty() {
+            ticker.unsubscribe(removed.clone())?;
+            for token in removed {
+                self.upstream.remove(&token);
+            }
+        }
+        for (mode, tokens) in added {
+            ticker.subscribe_with_mode(mode, tokens.clone())?;
+            self.upstream.extend(tokens.into_iter().map(|token| (token, mode)));
+        }
+        for (mode, tokens) in changed {
+            ticker.set_mode(mode, tokens.clone())?;
+            self.upstream.extend(tokens.into_iter().map(|token| (token, mode)));
+        }
+        Ok(())
+    }
+
+    fn register(&mut self, sink: Sink) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.clients.insert(id, Client { sink, tokens: HashMap::new() });
+        id
+    }
+
+    fn remove(&mut self, id: u64) {
+        if self.clients.remove(&id).is_some() {
+            if let Err(err) = self.sync() {
+                debug!("Unable to drop subscriptions of client {}: {}", id, err);
+            }
+        }
+    }
+}
+
+/// Re-serves the ticks of a single upstream connection to many local
+/// clients over websocket and server-sent events. Each client subscribes to
+/// its own instruments, the upstream connection carries the union of them.
+///
+/// Websocket clients speak the Kite protocol: `{"a": "subscribe", "v": [..]}`,
+/// `unsubscribe` and `{"a": "mode", "v": ["full", [..]]}`. Event stream
+/// clients pick theirs in the url, as in `/events?tokens=408065,884737&mode=full`.
+/// Both receive JSON messages like `{"type": "ticks", "data": [..]}`, where
+/// every tick has the shape of `Tick`, and order updates as `"order"`.
+///
+/// Connect it as a `TickerHandler`; subscriptions are restored whenever the
+/// upstream connection opens.
+#[derive(Clone, Default)]
+pub struct TickBridge {
+    state: Arc<Mutex<State>>,
+}
+
+impl TickBridge {
+    pub fn new() -> TickBridge {
+        TickBridge::default()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Accepts websocket clients on `address` from a background thread
+    pub fn listen_ws<A: ToSocketAddrs>(&self, address: A) -> ws::Result<SocketAddr> {
+        let bridge = self.clone();
+        let server = WebSocket::new(move |out: Sender| {
+            let id = bridge.lock().register(Sink::Ws(out.clone()));
+            WsClient { id, out, bridge: bridge.clone() }
+        })?.bind(address)?;
+        let address = server.local_addr()?;
+        thread::spawn(move || {
+            if let Err(err) = server.run() {
+                debug!("Bridge websocket server stopped: {}", err);
+            }
+        });
+        Ok(address)
+    }
+
+    /// Serves server-sent event streams at `GET /events` on `address` from
+    /// a background thread
+    pub fn listen_sse<A: ToSocketAddrs>(&self, address: A) -> io::Result<SocketAddr> {
+        let listener = TcpListener::bind(address)?;
+        let address = listener.local_addr()?;
+        let bridge = self.clone();
+        let active = Arc::new(AtomicUsize::new(0));
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(mut stream) => {
+                        if active.fetch_add(1, Ordering::SeqCst) >= MAX_SSE_CLIENTS {
+                            active.fetch_sub(1, Ordering::SeqCst);
+                            debug!("Refusing event stream client, {} already connected", MAX_SSE_CLIENTS);
+                            let _ = stream.write_all(
+                                b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+                            );
+                            continue;
+                        }
+                        let bridge = bridge.clone();
+                        let active = active.clone();
+                        thread::spawn(move || {
+                            if let Err(err) = bridge.serve_events(stream) {
+                                debug!("Event stream closed: {}", err);
+                            }
+                            active.fetch_sub(1, Ordering::SeqCst);
+                        });
+                    },
+                    Err(err) => debug!("Event stream connection failed: {}", err),
+                }
+            }
+        });
+        Ok(address)
+    }
+
+    /// Number of connected clients
+    pub fn clients(&self) -> usize {
+        self.lock().clients.len()
+    }
+
+    /// Instruments subscribed upstream along with their mode
+    pub fn subscriptions(&self) -> HashMap<u32, Mode> {
+        self.lock().upstream.clone()
+    }
+
+    fn subscribe(&self, id: u64, mode: Option<Mode>, tokens: &[u32]) -> ws::Result<()> {
+        let mut state = self.lock();
+        state.check_limit(tokens)?;
+        if let Some(client) = state.clients.get_mut(&id) {
+            for token in tokens {
+                // Kite streams quote mode unless asked otherwise
+                let current = client.tokens.get(token).cloned().unwrap_or(Mode::Quote);
+                client.tokens.insert(*token, mode.unwrap_or(current));
+            }
+        }
+        state.sync()
+    }
+
+    fn set_mode(&self, id: u64, mode: Mode, tokens: &[u32]) -> ws::Result<()> {
+        let mut state = self.lock();
+        if let Some(client) = state.clients.get_mut(&id) {
+            for token in tokens {
+                if let Some(current) = client.tokens.get_mut(token) {
+                    *current = mode;
+                }
+            }
+        }
+        state.sync()
+    }
+
+    fn unsubscribe(&self, id: u64, tokens: &[u32]) -> ws::Result<()> {
+        let mut state = self.lock();
+        if let Some(client) = state.clients.get_mut(&id) {
+            for token in tokens {
+                client.tokens.remove(token);
+            }
+        }
+        state.sync()
+    }
+
+    /// Applies a Kite protocol request of a websocket client
+    fn request(&self, id: u64, request: &JsonValue) -> ws::Result<()> {
+        let tokens = |value: &JsonValue| -> Vec<u32> {
+            value.as_array()
+                .map(|tokens| tokens.iter().filter_map(|token| token.as_u64()).map(|token| token as u32).collect())
+                .unwrap_or_default()
+        };
+        let invalid = || ws::Error::new(ws::ErrorKind::Protocol, format!("Invalid request {}", request));
+
+        match request["a"].as_str() {
+            Some("subscribe") => self.subscribe(id, None, &tokens(&request["v"])),
+            Some("unsubscribe") => self.unsubscribe(id, &tokens(&request["v"])),
+            Some("mode") => {
+                let mode = request["v"][0].as_str()
+                    .and_then(|mode| mode.parse::<Mode>().ok())
+                    .ok_or_else(invalid)?;
+                self.set_mode(id, mode, &tokens(&request["v"][1]))
+            },
+            _ => Err(invalid()),
+        }
+    }
+
+    fn serve_events(&self, mut stream: TcpStream) -> io::Result<()> {
+        let request = read_request(&stream, REQUEST_TIMEOUT)?;
+
+        let path = request.split_whitespace().nth(1).unwrap_or("/");
+        let url = Url::parse(&format!("http://localhost{}", path)).map_err(io::Error::other)?;
+        if !request.starts_with("GET ") || url.path() != "/events" {
+            stream.write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")?;
+            return Ok(());
+        }
+
+        let mut tokens = Vec::new();
+        let mut mode = None;
+        for (name, value) in url.query_pairs() {
+            match name.as_ref() {
+                "tokens" => tokens.extend(value.split(',').filter_map(|token| token.trim().parse::<u32>().ok())),
+                "mode" => mode = value.parse::<Mode>().ok(),
+                _ => {}
+            }
+        }
+
+        stream.write_all(
+            b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: keep-alive\r\n\r\n"
+        )?;
+        stream.flush()?;
+
+        let (sender, receiver) = mpsc::sync_channel(SSE_BUFFER);
+        let id = self.lock().register(Sink::Sse(sender));
+        let subscribed = self.subscribe(id, mode, &tokens);
+
+        let result = subscribed.map_err(io::Error::other).and_then(|_| loop {
+            match receiver.recv_timeout(SSE_KEEP_ALIVE) {
+                Ok(message) => write!(stream, "data: {}\n\n", message)?,
+                Err(RecvTimeoutError::Timeout) => stream.write_all(b": keep-alive\n\n")?,
+                Err(RecvTimeoutError::Disconnected) => break Ok(()),
+            }
+            stream.flush()?;
+        });
+        self.lock().remove(id);
+        result
+    }
+}
+
+impl TickerHandler for TickBridge {
+    fn on_open(&mut self, ticker: &TickerHandle) {
+        let mut state = self.lock();
+        state.ticker = Some(ticker.clone());
+        // Restore the clients' subscriptions on the new connection
+        state.upstream.clear();
+        if let Err(err) = state.sync() {
+            debug!("Unable to restore bridge subscriptions: {}", err);
+        }
+    }
+
+    fn on_ticks(&mut self, _ticker: &TickerHandle, ticks: &[Tick]) {
+        let encoded: Vec<(u32, String)> = ticks.iter()
+            .filter_map(|tick| serde_json::to_string(tick).ok().map(|json| (tick.instrument_token, json)))
+            .collect();
+
+        let mut state = self.lock();
+        let mut gone = Vec::new();
+        for (id, client) in state.clients.iter() {
+            let data: Vec<&str> = encoded.iter()
+                .filter(|(token, _)| client.tokens.contains_key(token))
+                .map(|(_, json)| json.as_str())
+                .collect();
+            if data.is_empty() {
+                continue;
+            }
+            let message = format!("{{\"type\":\"ticks\",\"data\":[{}]}}", data.join(","));
+            if !client.sink.send(&message) {
+                gone.push(*id);
+            }
+        }
+        for id in gone {
+            state.remove(id);
+        }
+    }
+
+    fn on_order_update(&mut self, _ticker: &TickerHandle, order: &JsonValue) {
+        let message = json!({"type": "order", "data": order}).to_string();
+        let mut state = self.lock();
+        let gone: Vec<u64> = state.clients.iter()
+            .filter(|(_, client)| !client.sink.send(&message))
+            .map(|(id, _)| *id)
+            .collect();
+        for id in gone {
+            state.remove(id);
+        }
+    }
+
+    fn on_close(&mut self, _ticker: &TickerHandle) {
+        self.lock().ticker = None;
+    }
+}
+
+struct WsClient {
+    id: u64,
+    out: Sender,
+    bridge: TickBridge,
+}
+
+impl Handler for WsClient {
+    fn on_message(&mut self, msg: Message) -> ws::Result<()> {
+        let text = msg.into_text()?;
+        let result = serde_json::from_str::<JsonValue>(&text)
+            .map_err(|err| ws::Error::new(ws::ErrorKind::Protocol, err.to_string()))
+            .and_then(|request| self.bridge.request(self.id, &request));
+        if let Err(err) = result {
+            self.out.send(json!({"type": "error", "data": err.to_string()}).to_string())?;
+        }
+        Ok(())
+    }
+
+    fn on_close(&mut self, _code: CloseCode, _reason: &str) {
+        self.bridge.lock().remove(self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use crate::ticker::{KiteTicker, MockTickerServer};
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn tick(instrument_token: u32, last_price: f64) -> Tick {
+        Tick { instrument_token, last_price, ..Tick::default() }
+    }
+
+    /// Reads the next `data:` line of an event stream
+    fn next_event(reader: &mut BufReader<TcpStream>) -> JsonValue {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+            if let Some(data) = line.strip_prefix("data: ") {
+                return serde_json::from_str(data.trim()).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_bridge_filters_per_client() {
+        let server = MockTickerServer::start().unwrap();
+        let bridge = TickBridge::new();
+        let ws_address = bridge.listen_ws("127.0.0.1:0").unwrap();
+        let sse_address = bridge.listen_sse("127.0.0.1:0").unwrap();
+
+        let mut ticker = KiteTicker::new("<API-KEY>", "<ACCESS-TOKEN>");
+        ticker.connect_dyn(Box::new(bridge.clone()), Some(&server.url())).unwrap();
+        assert!(server.wait_for_clients(1, TIMEOUT));
+
+        let (messages, received) = mpsc::channel();
+        thread::spawn(move || {
+            ws::connect(format!("ws://{}", ws_address), |out| {
+                out.send(r#"{"a": "subscribe", "v": [408065]}"#).unwrap();
+                out.send(r#"{"a": "mode", "v": ["full", [408065]]}"#).unwrap();
+                out.send(r#"{"a": "unknown"}"#).unwrap();
+                let messages = messages.clone();
+                move |msg: Message| {
+                    let message: JsonValue = serde_json::from_str(&msg.into_text()?).unwrap();
+                    messages.send(message).unwrap();
+                    Ok(())
+                }
+            }).unwrap();
+        });
+        assert_eq!(received.recv_timeout(TIMEOUT).unwrap()["type"], "error");
+        assert!(server.wait_for_subscription(408065, Mode::Full, TIMEOUT));
+
+        let mut events = TcpStream::connect(sse_address).unwrap();
+        events.write_all(b"GET /events?tokens=884737&mode=ltp HTTP/1.1\r\n\r\n").unwrap();
+        let mut events = BufReader::new(events);
+        assert!(server.wait_for_subscription(884737, Mode::Ltp, TIMEOUT));
+        assert_eq!(bridge.clients(), 2);
+
+        server.send_ticks(&[tick(408065, 100.0), tick(884737, 200.0)]).unwrap();
+        let message = received.recv_timeout(TIMEOUT).unwrap();
+        assert_eq!(message["type"], "ticks");
+        assert_eq!(message["data"].as_array().unwrap().len(), 1);
+        assert_eq!(message["data"][0]["instrument_token"], 408065);
+        assert_eq!(message["data"][0]["mode"], "full");
+
+        let event = next_event(&mut events);
+        assert_eq!(event["data"][0]["instrument_token"], 884737);
+        assert_eq!(event["data"][0]["last_price"], 200.0);
+
+        server.send_order_update(json!({"order_id": "1"})).unwrap();
+        assert_eq!(received.recv_timeout(TIMEOUT).unwrap()["data"]["order_id"], "1");
+        assert_eq!(next_event(&mut events)["type"], "order");
+
+        // Tokens nobody wants any more are unsubscribed upstream once the
+        // bridge notices the closed stream on a write
+        drop(events);
+        let mut attempts = 0;
+        while server.subscriptions().contains_key(&884737) {
+            assert!(attempts < 500, "Event stream client was never dropped");
+            server.send_ticks(&[tick(884737, 201.0)]).unwrap();
+            thread::sleep(Duration::from_millis(10));
+            attempts += 1;
+        }
+        assert_eq!(bridge.clients(), 1);
+        assert_eq!(bridge.subscriptions().keys().collect::<Vec<_>>(), vec![&408065]);
+
+        ticker.close().unwrap();
+        ticker.join().unwrap();
+    }
+
+    #[test]
+    fn test_limit_checked_before_client_changes() {
+        use crate::ticker::Subscriptions;
+
+        let bridge = TickBridge::new();
+        let ticker = TickerHandle::new(None, Arc::new(Mutex::new(Subscriptions::default())));
+        bridge.clone().on_open(&ticker);
+        let (sender, _receiver) = mpsc::sync_channel(1);
+        let id = bridge.lock().register(Sink::Sse(sender));
+
+        bridge.subscribe(id, Some(Mode::Ltp), &[1, 2]).unwrap();
+        let too_many: Vec<u32> = (10..MAX_TOKENS_PER_CONNECTION as u32 + 9).collect();
+        assert!(bridge.subscribe(id, None, &too_many).is_err());
+        assert_eq!(bridge.lock().clients[&id].tokens.len(), 2);
+        assert_eq!(bridge.subscriptions().len(), 2);
+    }
+
+    #[test]
+    fn test_event_stream_client_limit() {
+        let bridge = TickBridge::new();
+        let address = bridge.listen_sse("127.0.0.1:0").unwrap();
+
+        // Idle clients that never send a request take up the slots
+        let idle: Vec<TcpStream> = (0..MAX_SSE_CLIENTS).map(|_| TcpStream::connect(address).unwrap()).collect();
+        let mut refused = TcpStream::connect(address).unwrap();
+        refused.set_read_timeout(Some(TIMEOUT)).unwrap();
+        let mut response = String::new();
+        BufReader::new(&mut refused).read_line(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 503"));
+
+        // Their requests time out and free the slots
+        drop(idle);
+        let mut attempts = 0;
+        loop {
+            let mut events = TcpStream::connect(address).unwrap();
+            events.write_all(b"GET /events?tokens=1 HTTP/1.1\r\n\r\n").unwrap();
+            let mut response = String::new();
+            BufReader::new(&mut events).read_line(&mut response).unwrap();
+            if response.starts_with("HTTP/1.1 200") {
+                break;
+            }
+            assert!(attempts < 100, "Event stream slots were never freed");
+            thread::sleep(Duration::from_millis(50));
+            attempts += 1;
+        }
+    }
+}
@@ -0,0 +1,243 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use serde_json::Value as JsonValue;
+
+use super::snapshot::merge;
+use super::{Tick, TickerHandle, TickerHandler};
+
+struct State {
+    /// Latest tick of each instrument, in the order they first ticked
+    pending: Vec<Tick>,
+    positions: HashMap<u32, usize>,
+    ticker: Option<TickerHandle>,
+    stopped: bool,
+}
+
+struct Shared<H> {
+    state: Mutex<State>,
+    /// Kept apart from `state` so a slow handler never blocks the socket
+    /// thread queueing ticks. Taken before `state` when both are needed.
+    handler: Mutex<H>,
+    stop: Condvar,
+}
+
+impl<H> Shared<H> where H: TickerHandler {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn handler(&self) -> MutexGuard<'_, H> {
+        self.handler.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Delivers the pending ticks, holding the state lock only to take them
+    fn flush(&self) {
+        let mut handler = self.handler();
+        let (ticks, ticker) = {
+            let mut state = self.lock();
+            if state.pending.is_empty() {
+                return;
+            }
+            state.positions.clear();
+            (std::mem::take(&mut state.pending), state.ticker.clone())
+        };
+        if let Some(ticker) = ticker {
+            handler.on_ticks(&ticker, &ticks);
+        }
+    }
+}
+
+/// Wraps a `TickerHandler` so it receives at most one batch of ticks per
+/// `interval`, holding the latest state of each instrument that ticked in
+/// between. Ticks of different modes are merged like `MarketSnapshot` does.
+/// Other callbacks are passed through as they arrive.
+///
+/// Batches are delivered from a timer thread, so a quiet feed still flushes
+/// on time. Combine it with `FanOut` to throttle some handlers while others
+/// get the raw feed.
+pub struct Conflated<H> where H: TickerHandler + 'static {
+    shared: Arc<Shared<H>>,
+    timer: Option<JoinHandle<()>>,
+}
+
+impl<H> Conflated<H> where H: TickerHandler + 'static {
+    pub fn new(handler: H, interval: Duration) -> Conflated<H> {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                pending: Vec::new(),
+                positions: HashMap::new(),
+                ticker: None,
+                stopped: false,
+            }),
+            handler: Mutex::new(handler),
+            stop: Condvar::new(),
+        });
+
+        let timer = {
+            let shared = shared.clone();
+            thread::spawn(move || {
+                let mut next = Instant::now() + interval;
+                let mut state = shared.lock();
+                while !state.stopped {
+                    let now = Instant::now();
+                    if now >= next {
+                        drop(state);
+                        shared.flush();
+                        state = shared.lock();
+                        next += interval;
+                        // Skip the ticks missed by a slow handler
+                        if next < now {
+                            next = now + interval;
+                        }
+                        continue;
+                    }
+                    state = shared.stop.wait_timeout(state, next - now)
+                        .unwrap_or_else(|err| err.into_inner()).0;
+                }
+            })
+        };
+
+        Conflated { shared, timer: Some(timer) }
+    }
+
+    /// Delivers the pending ticks now
+    pub fn flush(&self) {
+        self.shared.flush();
+    }
+
+    /// Instruments waiting for the next batch
+    pub fn pending(&self) -> usize {
+        self.shared.lock().pending.len()
+    }
+}
+
+impl<H> TickerHandler for Conflated<H> where H: TickerHandler + 'static {
+    fn on_open(&mut self, ticker: &TickerHandle) {
+        self.shared.lock().ticker = Some(ticker.clone());
+        self.shared.handler().on_open(ticker);
+    }
+
+    fn on_ticks(&mut self, ticker: &TickerHandle, ticks: &[Tick]) {
+        let mut state = self.shared.lock();
+        if state.ticker.is_none() {
+            state.ticker = Some(ticker.clone());
+        }
+        for tick in ticks {
+            match state.positions.get(&tick.instrument_token).cloned() {
+                Some(position) => {
+                    let merged = merge(&state.pending[position], tick);
+                    state.pending[position] = merged;
+                },
+                None => {
+                    let position = state.pending.len();
+                    state.positions.insert(tick.instrument_token, position);
+                    state.pending.push(tick.clone());
+                }
+            }
+        }
+    }
+
+    fn on_order_update(&mut self, ticker: &TickerHandle, order: &JsonValue) {
+        self.shared.handler().on_order_update(ticker, order);
+    }
+
+    fn on_message(&mut self, ticker: &TickerHandle, message: &JsonValue) {
+        self.shared.handler().on_message(ticker, message);
+    }
+
+    fn on_close(&mut self, ticker: &TickerHandle) {
+        self.shared.flush();
+        self.shared.handler().on_close(ticker);
+    }
+
+    fn on_error(&mut self, ticker: &TickerHandle, error: Option<&str>) {
+        self.shared.handler().on_error(ticker, error);
+    }
+}
+
+impl<H> Drop for Conflated<H> where H: TickerHandler + 'static {
+    fn drop(&mut self) {
+        self.shared.lock().stopped = true;
+        self.shared.stop.notify_all();
+        if let Some(timer) = self.timer.take() {
+            let _ = timer.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use crate::ticker::{Mode, Subscriptions};
+
+    struct Forward(mpsc::Sender<Vec<Tick>>);
+
+    impl TickerHandler for Forward {
+        fn on_ticks(&mut self, _ticker: &TickerHandle, ticks: &[Tick]) {
+            self.0.send(ticks.to_vec()).unwrap();
+        }
+    }
+
+    fn tick(instrument_token: u32, last_price: f64) -> Tick {
+        Tick { instrument_token, last_price, ..Tick::default() }
+    }
+
+    #[test]
+    fn test_conflated_batches() {
+        let (sender, batches) = mpsc::channel();
+        let ticker = TickerHandle::new(None, Arc::new(Mutex::new(Subscriptions::default())));
+        let mut conflated = Conflated::new(Forward(sender), Duration::from_millis(50));
+
+        let full = Tick { mode: Mode::Full, oi: 1500, ..tick(408065, 100.0) };
+        conflated.on_ticks(&ticker, &[full, tick(884737, 50.0)]);
+        conflated.on_ticks(&ticker, &[tick(408065, 101.0)]);
+        conflated.on_ticks(&ticker, &[tick(408065, 102.0)]);
+        assert_eq!(conflated.pending(), 2);
+
+        let batch = batches.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(batch.len(), 2);
+        assert_eq!((batch[0].instrument_token, batch[0].last_price), (408065, 102.0));
+        // Later ltp ticks keep the open interest of the full tick
+        assert_eq!((batch[0].mode, batch[0].oi), (Mode::Full, 1500));
+        assert_eq!(batch[1], tick(884737, 50.0));
+
+        // Quiet intervals deliver nothing
+        assert!(batches.recv_timeout(Duration::from_millis(150)).is_err());
+
+        conflated.on_ticks(&ticker, &[tick(884737, 51.0)]);
+        conflated.flush();
+        assert_eq!(batches.try_recv().unwrap(), vec![tick(884737, 51.0)]);
+        assert_eq!(conflated.pending(), 0);
+    }
+
+    #[test]
+    fn test_slow_handler_does_not_block_ticks() {
+        // Blocks in on_ticks until released
+        struct Slow(mpsc::Sender<()>, Arc<Mutex<()>>);
+        impl TickerHandler for Slow {
+            fn on_ticks(&mut self, _ticker: &TickerHandle, _ticks: &[Tick]) {
+                self.0.send(()).unwrap();
+                let _release = self.1.lock().unwrap();
+            }
+        }
+
+        let (delivering, delivered) = mpsc::channel();
+        let gate = Arc::new(Mutex::new(()));
+        let closed = gate.lock().unwrap();
+        let ticker = TickerHandle::new(None, Arc::new(Mutex::new(Subscriptions::default())));
+        let mut conflated = Conflated::new(Slow(delivering, gate.clone()), Duration::from_millis(10));
+
+        conflated.on_ticks(&ticker, &[tick(408065, 100.0)]);
+        delivered.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        // The timer thread is stuck in the handler, ticks still queue up
+        let started = Instant::now();
+        conflated.on_ticks(&ticker, &[tick(408065, 101.0), tick(884737, 50.0)]);
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(conflated.pending(), 2);
+        drop(closed);
+    }
+}
@@ -0,0 +1,215 @@
+use super::{Depth, DepthItem, Tick};
+
+/// Side of the order book
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    /// Buy orders, best price first
+    Bid,
+    /// Sell orders, best price first
+    Ask,
+}
+
+/// Order book metrics derived from a single depth update
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DepthMetrics {
+    pub best_bid: f64,
+    pub best_ask: f64,
+    /// Best ask minus best bid
+    pub spread: f64,
+    /// Average of the best bid and ask
+    pub mid: f64,
+    /// Mid weighted towards the side with less quantity at the top of the book
+    pub microprice: f64,
+    /// Quantity across all bid levels
+    pub bid_quantity: u64,
+    /// Quantity across all ask levels
+    pub ask_quantity: u64,
+    /// Quantity weighted average price of all bid levels
+    pub weighted_bid: f64,
+    /// Quantity weighted average price of all ask levels
+    pub weighted_ask: f64,
+    /// `(bid - ask) / (bid + ask)` quantity across all levels, from -1 to 1
+    pub imbalance: f64,
+}
+
+impl Depth {
+    /// Levels of `side` carrying orders. Kite pads missing levels with zeros.
+    pub fn levels(&self, side: Side) -> impl Iterator<Item = &DepthItem> {
+        let levels = match side {
+            Side::Bid => &self.buy,
+            Side::Ask => &self.sell,
+        };
+        levels.iter().filter(|level| level.quantity > 0 && level.price > 0.0)
+    }
+
+    /// Highest bid
+    pub fn best_bid(&self) -> Option<&DepthItem> {
+        self.levels(Side::Bid).next()
+    }
+
+    /// Lowest ask
+    pub fn best_ask(&self) -> Option<&DepthItem> {
+        self.levels(Side::Ask).next()
+    }
+
+    /// Best ask minus best bid
+    pub fn spread(&self) -> Option<f64> {
+        Some(self.best_ask()?.price - self.best_bid()?.price)
+    }
+
+    /// Average of the best bid and ask
+    pub fn mid(&self) -> Option<f64> {
+        Some((self.best_ask()?.price + self.best_bid()?.price) / 2.0)
+    }
+
+    /// Top of book prices weighted by the quantity on the opposite side,
+    /// which leans towards the price more likely to trade next
+    pub fn microprice(&self) -> Option<f64> {
+        let bid = self.best_bid()?;
+        let ask = self.best_ask()?;
+        let bid_quantity = bid.quantity as f64;
+        let ask_quantity = ask.quantity as f64;
+        Some((bid.price * ask_quantity + ask.price * bid_quantity) / (bid_quantity + ask_quantity))
+    }
+
+    /// Total quantity of the best `levels` levels of `side`
+    pub fn quantity(&self, side: Side, levels: usize) -> u64 {
+        self.levels(side).take(levels).map(|level| level.quantity as u64).sum()
+    }
+
+    /// Quantity weighted average price of the best `levels` levels of `side`
+    pub fn weighted_price(&self, side: Side, levels: usize) -> Option<f64> {
+        let (value, quantity) = self.levels(side).take(levels)
+            .fold((0.0, 0u64), |(value, quantity), level| {
+                (value + level.price * level.quantity as f64, quantity + level.quantity as u64)
+            });
+        if quantity == 0 {
+            None
+        } else {
+            Some(value / quantity as f64)
+        }
+    }
+
+    /// `(bid - ask) / (bid + ask)` quantity of the best `levels` levels.
+    /// Positive values mean more buying interest.
+    pub fn imbalance(&self, levels: usize) -> Option<f64> {
+        let bid = self.quantity(Side::Bid, levels) as f64;
+        let ask = self.quantity(Side::Ask, levels) as f64;
+        if bid + ask == 0.0 {
+            None
+        } else {
+            Some((bid - ask) / (bid + ask))
+        }
+    }
+
+    /// Average price of filling `quantity` by walking the levels of `side`,
+    /// or `None` if the visible depth holds less than `quantity`
+    pub fn average_fill_price(&self, side: Side, quantity: u64) -> Option<f64> {
+        if quantity == 0 {
+            return None;
+        }
+
+        let mut remaining = quantity;
+        let mut value = 0.0;
+        for level in self.levels(side) {
+            let filled = remaining.min(level.quantity as u64);
+            value += filled as f64 * level.price;
+            remaining -= filled;
+            if remaining == 0 {
+                return Some(value / quantity as f64);
+            }
+        }
+        None
+    }
+
+    /// Average price a market buy order of `quantity` would pay
+    pub fn average_buy_price(&self, quantity: u64) -> Option<f64> {
+        self.average_fill_price(Side::Ask, quantity)
+    }
+
+    /// Average price a market sell order of `quantity` would receive
+    pub fn average_sell_price(&self, quantity: u64) -> Option<f64> {
+        self.average_fill_price(Side::Bid, quantity)
+    }
+
+    /// All metrics at once, `None` unless both sides have orders
+    pub fn metrics(&self) -> Option<DepthMetrics> {
+        let levels = usize::MAX;
+        let best_bid = self.best_bid()?.price;
+        let best_ask = self.best_ask()?.price;
+
+        Some(DepthMetrics {
+            best_bid,
+            best_ask,
+            spread: best_ask - best_bid,
+            mid: (best_ask + best_bid) / 2.0,
+            microprice: self.microprice()?,
+            bid_quantity: self.quantity(Side::Bid, levels),
+            ask_quantity: self.quantity(Side::Ask, levels),
+            weighted_bid: self.weighted_price(Side::Bid, levels)?,
+            weighted_ask: self.weighted_price(Side::Ask, levels)?,
+            imbalance: self.imbalance(levels)?,
+        })
+    }
+}
+
+impl Tick {
+    /// Order book metrics of a full mode tick
+    pub fn depth_metrics(&self) -> Option<DepthMetrics> {
+        self.depth.as_ref().and_then(|depth| depth.metrics())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn level(price: f64, quantity: u32) -> DepthItem {
+        DepthItem { quantity, price, orders: 1 }
+    }
+
+    fn depth() -> Depth {
+        Depth {
+            buy: vec![level(99.0, 100), level(98.5, 200), level(98.0, 300), level(0.0, 0), level(0.0, 0)],
+            sell: vec![level(100.0, 300), level(100.5, 100), level(101.0, 100), level(0.0, 0), level(0.0, 0)],
+        }
+    }
+
+    #[test]
+    fn test_top_of_book() {
+        let depth = depth();
+        assert_eq!(depth.spread(), Some(1.0));
+        assert_eq!(depth.mid(), Some(99.5));
+        // Heavier ask side pulls the microprice towards the bid
+        assert_eq!(depth.microprice(), Some(99.25));
+        assert_eq!(depth.imbalance(1), Some(-0.5));
+        assert_eq!(depth.imbalance(5), Some(100.0 / 1100.0));
+        assert_eq!(depth.weighted_price(Side::Ask, 2), Some(100.125));
+
+        let metrics = Tick { depth: Some(depth), ..Tick::default() }.depth_metrics().unwrap();
+        assert_eq!((metrics.bid_quantity, metrics.ask_quantity), (600, 500));
+        assert_eq!(metrics.best_bid, 99.0);
+    }
+
+    #[test]
+    fn test_average_fill_price() {
+        let depth = depth();
+        assert_eq!(depth.average_buy_price(300), Some(100.0));
+        assert_eq!(depth.average_buy_price(400), Some(100.125));
+        assert_eq!(depth.average_sell_price(300), Some((99.0 * 100.0 + 98.5 * 200.0) / 300.0));
+        assert_eq!(depth.average_buy_price(501), None);
+        assert_eq!(depth.average_buy_price(0), None);
+    }
+
+    #[test]
+    fn test_empty_depth() {
+        let depth = Depth {
+            buy: vec![level(0.0, 0); 5],
+            sell: vec![level(100.0, 10)],
+        };
+        assert_eq!(depth.spread(), None);
+        assert_eq!(depth.metrics(), None);
+        assert_eq!(depth.imbalance(5), Some(-1.0));
+        assert_eq!(Tick::default().depth_metrics(), None);
+    }
+}
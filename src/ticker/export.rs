@@ -0,0 +1,635 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use chrono::{DateTime, FixedOffset, NaiveDate, SecondsFormat};
+use log::debug;
+use serde_json::{Map, Value as JsonValue};
+use ws::Message;
+
+use super::recorder::{self, TickReader};
+use super::tick::{ist, parse_binary};
+use super::{Tick, TickerEvent};
+
+/// Number of depth levels exported per side
+const DEPTH_LEVELS: usize = 5;
+
+/// Partitions kept open at once unless set with `set_max_open_partitions`
+const DEFAULT_MAX_OPEN_PARTITIONS: usize = 64;
+
+/// File format of exported ticks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// Comma separated values with a header row, empty cells for missing values
+    Csv,
+    /// One JSON object per line, `null` for missing values
+    Ndjson,
+    /// Apache Parquet, with timestamps as UTC microseconds
+    #[cfg(feature = "parquet")]
+    Parquet,
+}
+
+impl ExportFormat {
+    fn extension(&self) -> &'static str {
+        match *self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Ndjson => "ndjson",
+            #[cfg(feature = "parquet")]
+            ExportFormat::Parquet => "parquet",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Timestamp,
+    Int,
+    Float,
+    Bool,
+    Text,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Timestamp(DateTime<FixedOffset>),
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    Text(&'static str),
+    Null,
+}
+
+impl Value {
+    fn time(time: Option<DateTime<FixedOffset>>) -> Value {
+        time.map(Value::Timestamp).unwrap_or(Value::Null)
+    }
+
+    fn to_cell(&self) -> String {
+        match *self {
+            Value::Timestamp(time) => time.to_rfc3339_opts(SecondsFormat::AutoSi, true),
+            Value::Int(value) => value.to_string(),
+            Value::Float(value) => value.to_string(),
+            Value::Bool(value) => value.to_string(),
+            Value::Text(value) => value.to_string(),
+            Value::Null => String::new(),
+        }
+    }
+
+    fn to_json(&self) -> JsonValue {
+        match *self {
+            Value::Timestamp(time) => JsonValue::from(time.to_rfc3339_opts(SecondsFormat::AutoSi, true)),
+            Value::Int(value) => JsonValue::from(value),
+            Value::Float(value) => JsonValue::from(value),
+            Value::Bool(value) => JsonValue::from(value),
+            Value::Text(value) => JsonValue::from(value),
+            Value::Null => JsonValue::Null,
+        }
+    }
+}
+
+fn schema() -> Vec<(String, Kind)> {
+    let mut columns: Vec<(String, Kind)> = [
+        ("received", Kind::Timestamp),
+        ("exchange_timestamp", Kind::Timestamp),
+        ("last_trade_time", Kind::Timestamp),
+        ("instrument_token", Kind::Int),
+        ("mode", Kind::Text),
+        ("tradable", Kind::Bool),
+        ("last_price", Kind::Float),
+        ("last_quantity", Kind::Int),
+        ("average_price", Kind::Float),
+        ("volume", Kind::Int),
+        ("buy_quantity", Kind::Int),
+        ("sell_quantity", Kind::Int),
+        ("open", Kind::Float),
+        ("high", Kind::Float),
+        ("low", Kind::Float),
+        ("close", Kind::Float),
+        ("change", Kind::Float),
+        ("oi", Kind::Int),
+        ("oi_day_high", Kind::Int),
+        ("oi_day_low", Kind::Int),
+    ].iter().map(|(name, kind)| (name.to_string(), *kind)).collect();
+
+    for side in ["buy", "sell"].iter() {
+        for level in 1..=DEPTH_LEVELS {
+            columns.push((format!("{}_{}_price", side, level), Kind::Float));
+            columns.push((format!("{}_{}_quantity", side, level), Kind::Int));
+            columns.push((format!("{}_{}_orders", side, level), Kind::Int));
+        }
+    }
+    columns
+}
+
+/// Names of the exported columns, in order. Depth levels are flattened as
+/// `buy_1_price`, `buy_1_quantity`, `buy_1_orders` up to `sell_5_orders`.
+pub fn export_columns() -> Vec<String> {
+    schema().into_iter().map(|(name, _)| name).collect()
+}
+
+/// Flattens a tick into values matching `schema`
+fn row(received: DateTime<FixedOffset>, tick: &Tick) -> Vec<Value> {
+    let mut values = vec![
+        Value::Timestamp(received),
+        Value::time(tick.exchange_timestamp),
+        Value::time(tick.last_trade_time),
+        Value::Int(tick.instrument_token as i64),
+        Value::Text(tick.mode.as_str()),
+        Value::Bool(tick.tradable),
+        Value::Float(tick.last_price),
+        Value::Int(tick.last_quantity as i64),
+        Value::Float(tick.average_price),
+        Value::Int(tick.volume as i64),
+        Value::Int(tick.buy_quantity as i64),
+        Value::Int(tick.sell_quantity as i64),
+        Value::Float(tick.ohlc.open),
+        Value::Float(tick.ohlc.high),
+        Value::Float(tick.ohlc.low),
+        Value::Float(tick.ohlc.close),
+        Value::Float(tick.change),
+        Value::Int(tick.oi as i64),
+        Value::Int(tick.oi_day_high as i64),
+        Value::Int(tick.oi_day_low as i64),
+    ];
+
+    let empty = Vec::new();
+    let (buy, sell) = match tick.depth {
+        Some(ref depth) => (&depth.buy, &depth.sell),
+        None => (&empty, &empty),
+    };
+    for side in [buy, sell].iter() {
+        for level in 0..DEPTH_LEVELS {
+            match side.get(level) {
+                Some(item) => values.extend(vec![
+                    Value::Float(item.price),
+                    Value::Int(item.quantity as i64),
+                    Value::Int(item.orders as i64),
+                ]),
+                None => values.extend(vec![Value::Null, Value::Null, Value::Null]),
+            }
+        }
+    }
+    values
+}
+
+enum PartitionWriter {
+    Csv(Box<csv::Writer<File>>),
+    Ndjson(BufWriter<File>, Vec<String>),
+    #[cfg(feature = "parquet")]
+    Parquet(parquet_writer::ParquetPartition),
+}
+
+impl PartitionWriter {
+    fn write(&mut self, values: Vec<Value>) -> io::Result<()> {
+        match *self {
+            PartitionWriter::Csv(ref mut writer) => {
+                writer.write_record(values.iter().map(|value| value.to_cell()))?;
+            },
+            PartitionWriter::Ndjson(ref mut writer, ref columns) => {
+                let object: Map<String, JsonValue> = columns.iter()
+                    .zip(values.iter())
+                    .map(|(name, value)| (name.clone(), value.to_json()))
+                    .collect();
+                serde_json::to_writer(&mut *writer, &object)?;
+                writer.write_all(b"\n")?;
+            },
+            #[cfg(feature = "parquet")]
+            PartitionWriter::Parquet(ref mut writer) => writer.write(values)?,
+        }
+        Ok(())
+    }
+
+    fn close(self) -> io::Result<()> {
+        match self {
+            PartitionWriter::Csv(mut writer) => writer.flush(),
+            PartitionWriter::Ndjson(mut writer, _) => writer.flush(),
+            #[cfg(feature = "parquet")]
+            PartitionWriter::Parquet(writer) => writer.close(),
+        }
+    }
+}
+
+/// Converts ticks into files partitioned the Hive way, as
+/// `date=YYYY-MM-DD/instrument_token=N/ticks.<format>`, so pandas, Polars
+/// and Spark read a directory as a single dataset. The date is the IST date
+/// the tick was received on. CSV and NDJSON partitions are appended to,
+/// Parquet partitions get a new numbered file per export.
+///
+/// Only the most recently written partitions are kept open. The least
+/// recently used one is closed when another is needed and reopened if more
+/// ticks arrive for it, which adds a numbered Parquet file to the partition.
+pub struct TickExporter {
+    directory: PathBuf,
+    format: ExportFormat,
+    writers: HashMap<(NaiveDate, u32), OpenPartition>,
+    max_open: usize,
+    writes: u64,
+    written: Vec<PathBuf>,
+}
+
+struct OpenPartition {
+    path: PathBuf,
+    writer: PartitionWriter,
+    /// Write count when the partition was last written, for eviction
+    last_write: u64,
+}
+
+impl TickExporter {
+    /// Exports into `directory`, creating it if needed
+    pub fn new<P: AsRef<Path>>(directory: P, format: ExportFormat) -> io::Result<TickExporter> {
+        fs::create_dir_all(directory.as_ref())?;
+        Ok(TickExporter {
+            directory: directory.as_ref().to_path_buf(),
+            format,
+            writers: HashMap::new(),
+            max_open: DEFAULT_MAX_OPEN_PARTITIONS,
+            writes: 0,
+            written: Vec::new(),
+        })
+    }
+
+    /// Number of partition files kept open at once, 64 by default
+    pub fn set_max_open_partitions(&mut self, max_open: usize) {
+        self.max_open = max_open.max(1);
+    }
+
+    fn open(&self, partition: &Path) -> io::Result<OpenPartition> {
+        fs::create_dir_all(partition)?;
+        let extension = self.format.extension();
+        let path = partition.join(format!("ticks.{}", extension));
+
+        let (path, writer) = match self.format {
+            ExportFormat::Csv => {
+                let is_new = !path.exists();
+                let file = OpenOptions::new().create(true).append(true).open(&path)?;
+                let mut writer = csv::Writer::from_writer(file);
+                if is_new {
+                    writer.write_record(export_columns())?;
+                }
+                (path, PartitionWriter::Csv(Box::new(writer)))
+            },
+            ExportFormat::Ndjson => {
+                let file = OpenOptions::new().create(true).append(true).open(&path)?;
+                (path, PartitionWriter::Ndjson(BufWriter::new(file), export_columns()))
+            },
+            #[cfg(feature = "parquet")]
+            ExportFormat::Parquet => {
+                let path = (0..).map(|part| partition.join(format!("ticks-{}.{}", part, extension)))
+                    .find(|path| !path.exists())
+                    .expect("some part number is unused");
+                let writer = parquet_writer::ParquetPartition::create(&path, schema())?;
+                (path, PartitionWriter::Parquet(writer))
+            },
+        };
+        Ok(OpenPartition { path, writer, last_write: 0 })
+    }
+
+    /// Writes a single tick received at `received`
+    pub fn write(&mut self, received: DateTime<FixedOffset>, tick: &Tick) -> io::Result<()> {
+        let date = received.with_timezone(&ist()).date_naive();
+        let key = (date, tick.instrument_token);
+        if !self.writers.contains_key(&key) {
+            if self.writers.len() >= self.max_open {
+                self.close_least_recent()?;
+            }
+            let partition = self.directory
+                .join(format!("date={}", date.format("%Y-%m-%d")))
+                .join(format!("instrument_token={}", tick.instrument_token));
+            let writer = self.open(&partition)?;
+            self.writers.insert(key, writer);
+        }
+
+        self.writes += 1;
+        match self.writers.get_mut(&key) {
+            Some(partition) => {
+                partition.last_write = self.writes;
+                partition.writer.write(row(received, tick))
+            },
+            None => unreachable!(),
+        }
+    }
+
+    fn close_least_recent(&mut self) -> io::Result<()> {
+        let key = self.writers.iter()
+            .min_by_key(|(_, partition)| partition.last_write)
+            .map(|(key, _)| *key);
+        match key.and_then(|key| self.writers.remove(&key)) {
+            Some(partition) => {
+                partition.writer.close()?;
+                self.written.push(partition.path);
+                Ok(())
+            },
+            None => Ok(()),
+        }
+    }
+
+    /// Exports every tick of a recording written by `TickRecorder`,
+    /// returning the number of ticks written
+    pub fn export_recording<P: AsRef<Path>>(&mut self, path: P) -> io::Result<usize> {
+        let mut count = 0;
+        for frame in TickReader::open(path)? {
+            let frame = frame?;
+            if let Message::Binary(ref data) = frame.message {
+                if data.len() <= 2 {
+                    continue;
+                }
+                for tick in parse_binary(data)? {
+                    self.write(frame.received, &tick)?;
+                    count += 1;
+                }
+            }
+        }
+        Ok(count)
+    }
+
+    /// Exports ticks of a live stream, such as a `TickerStream`, until it
+    /// ends. Ticks are stamped with the time they are exported.
+    pub fn export_stream<I>(&mut self, events: I) -> io::Result<usize>
+    where I: IntoIterator<Item = TickerEvent> {
+        let mut count = 0;
+        for event in events {
+            if let TickerEvent::Tick(tick) = event {
+                self.write(recorder::now(), &tick)?;
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    fn close_writers(&mut self) -> io::Result<()> {
+        let mut result = Ok(());
+        for (_, partition) in self.writers.drain() {
+            match partition.writer.close() {
+                Ok(()) => self.written.push(partition.path),
+                Err(err) => result = Err(err),
+            }
+        }
+        result
+    }
+
+    /// Flushes and closes every partition, returning the files written
+    pub fn finish(mut self) -> io::Result<Vec<PathBuf>> {
+        self.close_writers()?;
+        let mut written = std::mem::take(&mut self.written);
+        written.sort();
+        written.dedup();
+        Ok(written)
+    }
+}
+
+/// Closing an unfinished exporter flushes what was written so far
+impl Drop for TickExporter {
+    fn drop(&mut self) {
+        if let Err(err) = self.close_writers() {
+            debug!("Unable to close exported files: {}", err);
+        }
+    }
+}
+
+#[cfg(feature = "parquet")]
+mod parquet_writer {
+    use std::fs::File;
+    use std::io;
+    use std::path::Path;
+    use std::sync::Arc;
+    use parquet::basic::Compression;
+    use parquet::column::writer::ColumnWriter;
+    use parquet::data_type::ByteArray;
+    use parquet::errors::ParquetError;
+    use parquet::file::properties::WriterProperties;
+    use parquet::file::writer::SerializedFileWriter;
+    use parquet::schema::parser::parse_message_type;
+
+    use super::{Kind, Value};
+
+    /// Rows buffered before a row group is written
+    const ROW_GROUP_SIZE: usize = 10_000;
+
+    fn io_error(err: ParquetError) -> io::Error {
+        io::Error::other(err)
+    }
+
+    pub(super) struct ParquetPartition {
+        writer: SerializedFileWriter<File>,
+        rows: Vec<Vec<Value>>,
+    }
+
+    impl ParquetPartition {
+        pub(super) fn create(path: &Path, schema: Vec<(String, Kind)>) -> io::Result<ParquetPartition> {
+            let fields: Vec<String> = schema.iter().map(|(name, kind)| match *kind {
+                Kind::Timestamp => format!("OPTIONAL INT64 {} (TIMESTAMP(MICROS,true));", name),
+                Kind::Int => format!("OPTIONAL INT64 {};", name),
+                Kind::Float => format!("OPTIONAL DOUBLE {};", name),
+                Kind::Bool => format!("OPTIONAL BOOLEAN {};", name),
+                Kind::Text => format!("OPTIONAL BYTE_ARRAY {} (UTF8);", name),
+            }).collect();
+            let message = format!("message tick {{ {} }}", fields.join(" "));
+            let schema = Arc::new(parse_message_type(&message).map_err(io_error)?);
+
+            let properties = WriterProperties::builder()
+                .set_compression(Compression::SNAPPY)
+                .build();
+            let writer = SerializedFileWriter::new(File::create(path)?, schema, Arc::new(properties))
+                .map_err(io_error)?;
+            Ok(ParquetPartition { writer, rows: Vec::new() })
+        }
+
+        pub(super) fn write(&mut self, values: Vec<Value>) -> io::Result<()> {
+            self.rows.push(values);
+            if self.rows.len() >= ROW_GROUP_SIZE {
+                self.flush()?;
+            }
+            Ok(())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            if self.rows.is_empty() {
+                return Ok(());
+            }
+
+            let mut row_group = self.writer.next_row_group().map_err(io_error)?;
+            let mut index = 0;
+            while let Some(mut column) = row_group.next_column().map_err(io_error)? {
+                let cells: Vec<&Value> = self.rows.iter().map(|row| &row[index]).collect();
+                let levels: Vec<i16> = cells.iter()
+                    .map(|value| if **value == Value::Null { 0 } else { 1 })
+                    .collect();
+
+                match *column.untyped() {
+                    ColumnWriter::Int64ColumnWriter(ref mut writer) => {
+                        let values: Vec<i64> = cells.iter().filter_map(|value| match **value {
+                            Value::Int(value) => Some(value),
+                            Value::Timestamp(time) => Some(time.timestamp_micros()),
+                            _ => None,
+                        }).collect();
+                        writer.write_batch(&values, Some(&levels), None).map_err(io_error)?;
+                    },
+                    ColumnWriter::DoubleColumnWriter(ref mut writer) => {
+                        let values: Vec<f64> = cells.iter().filter_map(|value| match **value {
+                            Value::Float(value) => Some(value),
+                            _ => None,
+                        }).collect();
+                        writer.write_batch(&values, Some(&levels), None).map_err(io_error)?;
+                    },
+                    ColumnWriter::BoolColumnWriter(ref mut writer) => {
+                        let values: Vec<bool> = cells.iter().filter_map(|value| match **value {
+                            Value::Bool(value) => Some(value),
+                            _ => None,
+                        }).collect();
+                        writer.write_batch(&values, Some(&levels), None).map_err(io_error)?;
+                    },
+                    ColumnWriter::ByteArrayColumnWriter(ref mut writer) => {
+                        let values: Vec<ByteArray> = cells.iter().filter_map(|value| match **value {
+                            Value::Text(value) => Some(ByteArray::from(value)),
+                            _ => None,
+                        }).collect();
+                        writer.write_batch(&values, Some(&levels), None).map_err(io_error)?;
+                    },
+                    _ => unreachable!("column type not in the tick schema"),
+                }
+                column.close().map_err(io_error)?;
+                index += 1;
+            }
+            row_group.close().map_err(io_error)?;
+            self.rows.clear();
+            Ok(())
+        }
+
+        pub(super) fn close(mut self) -> io::Result<()> {
+            self.flush()?;
+            self.writer.close().map_err(io_error)?;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_directory;
+    use chrono::TimeZone;
+    use crate::ticker::{Depth, DepthItem, Mode, TickRecorder};
+
+    fn received(day: u32) -> DateTime<FixedOffset> {
+        ist().with_ymd_and_hms(2020, 1, day, 9, 15, 0).unwrap()
+    }
+
+    fn tick(instrument_token: u32, last_price: f64) -> Tick {
+        Tick {
+            mode: Mode::Full,
+            instrument_token,
+            last_price,
+            exchange_timestamp: Some(received(1)),
+            depth: Some(Depth {
+                buy: vec![DepthItem { quantity: 100, price: last_price - 0.05, orders: 2 }; 5],
+                sell: vec![DepthItem { quantity: 50, price: last_price + 0.05, orders: 1 }; 5],
+            }),
+            ..Tick::default()
+        }
+    }
+
+    #[test]
+    fn test_schema_matches_rows() {
+        let columns = export_columns();
+        assert_eq!(columns.len(), 20 + 2 * 5 * 3);
+        assert_eq!(columns[20], "buy_1_price");
+        assert_eq!(columns.last().unwrap(), "sell_5_orders");
+        assert_eq!(row(received(1), &tick(1, 10.0)).len(), columns.len());
+        assert_eq!(row(received(1), &Tick::default()).len(), columns.len());
+    }
+
+    #[test]
+    fn test_export_recording_to_csv() {
+        let path = test_directory("export-csv");
+        let mut recorder = TickRecorder::new(path.join("recordings")).unwrap();
+        let frame = crate::ticker::encode_binary(&[tick(408065, 100.0), tick(884737, 200.0)]);
+        recorder.record(received(1), &Message::Binary(frame.clone())).unwrap();
+        recorder.record(received(1), &Message::Text("{}".into())).unwrap();
+        recorder.record(received(2), &Message::Binary(frame)).unwrap();
+
+        let mut exporter = TickExporter::new(path.join("csv"), ExportFormat::Csv).unwrap();
+        for recording in TickRecorder::recordings(path.join("recordings")).unwrap() {
+            assert_eq!(exporter.export_recording(recording).unwrap(), 2);
+        }
+        let files = exporter.finish().unwrap();
+        assert_eq!(files.len(), 4);
+        assert_eq!(files[0], path.join("csv/date=2020-01-01/instrument_token=408065/ticks.csv"));
+
+        let mut reader = csv::Reader::from_path(&files[0]).unwrap();
+        assert_eq!(reader.headers().unwrap().iter().collect::<Vec<_>>(), export_columns());
+        let record = reader.records().next().unwrap().unwrap();
+        assert_eq!(&record[0], "2020-01-01T09:15:00+05:30");
+        assert_eq!(&record[2], "");
+        assert_eq!(&record[4], "full");
+        assert_eq!(&record[20], "99.95");
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_partitions_beyond_open_limit() {
+        let path = test_directory("export-evict");
+        let mut exporter = TickExporter::new(&path, ExportFormat::Csv).unwrap();
+        exporter.set_max_open_partitions(2);
+        for round in 0..3 {
+            for token in 1..=5 {
+                exporter.write(received(1), &tick(token, round as f64)).unwrap();
+                assert!(exporter.writers.len() <= 2);
+            }
+        }
+        let files = exporter.finish().unwrap();
+        assert_eq!(files.len(), 5);
+
+        // Reopened partitions are appended to without repeating the header
+        let mut reader = csv::Reader::from_path(&files[0]).unwrap();
+        let prices: Vec<String> = reader.records().map(|record| record.unwrap()[6].to_string()).collect();
+        assert_eq!(prices, vec!["0", "1", "2"]);
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_export_stream_to_ndjson() {
+        let path = test_directory("export-ndjson");
+        let mut exporter = TickExporter::new(&path, ExportFormat::Ndjson).unwrap();
+        let events = vec![
+            TickerEvent::Connected,
+            TickerEvent::Tick(Tick { instrument_token: 408065, last_price: 100.0, ..Tick::default() }),
+            TickerEvent::Disconnected,
+        ];
+        assert_eq!(exporter.export_stream(events).unwrap(), 1);
+
+        let files = exporter.finish().unwrap();
+        let line: JsonValue = serde_json::from_str(&fs::read_to_string(&files[0]).unwrap()).unwrap();
+        assert_eq!(line["last_price"], 100.0);
+        assert_eq!(line["mode"], "ltp");
+        assert_eq!(line["buy_1_price"], JsonValue::Null);
+        assert_eq!(line.as_object().unwrap().len(), export_columns().len());
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[cfg(feature = "parquet")]
+    #[test]
+    fn test_export_parquet() {
+        use parquet::file::reader::{FileReader, SerializedFileReader};
+        use parquet::record::RowAccessor;
+
+        let path = test_directory("export-parquet");
+        let mut exporter = TickExporter::new(&path, ExportFormat::Parquet).unwrap();
+        exporter.write(received(1), &tick(408065, 100.0)).unwrap();
+        exporter.write(received(1), &Tick { instrument_token: 408065, ..Tick::default() }).unwrap();
+        let files = exporter.finish().unwrap();
+        assert_eq!(files, vec![path.join("date=2020-01-01/instrument_token=408065/ticks-0.parquet")]);
+
+        let reader = SerializedFileReader::new(File::open(&files[0]).unwrap()).unwrap();
+        let rows: Vec<_> = reader.get_row_iter(None).unwrap().map(|row| row.unwrap()).collect();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].get_timestamp_micros(0).unwrap(), received(1).timestamp_micros());
+        assert_eq!(rows[0].get_string(4).unwrap(), "full");
+        assert_eq!(rows[0].get_double(20).unwrap(), 99.95);
+        assert!(rows[1].get_double(20).is_err());
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+}
@@ -0,0 +1,245 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use log::debug;
+use serde_json::{json, Value as JsonValue};
+use ws::{CloseCode, Error, ErrorKind, Result, Sender};
+
+use crate::instruments::InstrumentIndex;
+
+use super::subscriptions::{Mode, Subscriptions};
+
+/// Cloneable, thread safe handle to a connected `KiteTicker`. Every clone
+/// talks to the same websocket and shares the same subscription set, so
+/// subscriptions can be changed from any thread, not just from inside the
+/// `KiteTickerHandler` callbacks.
+#[derive(Clone)]
+pub struct TickerHandle {
+    pub(crate) sender: Option<Sender>,
+    subscriptions: Arc<Mutex<Subscriptions>>,
+}
+
+/// Resolves symbols to tokens, reporting unknown ones as a protocol error
+pub(crate) fn resolve(index: &InstrumentIndex, symbols: &[&str]) -> Result<Vec<u32>> {
+    index.resolve(symbols).map_err(|err| Error::new(ErrorKind::Protocol, err.to_string()))
+}
+
+impl TickerHandle {
+    pub(crate) fn new(sender: Option<Sender>, subscriptions: Arc<Mutex<Subscriptions>>) -> TickerHandle {
+        TickerHandle { sender, subscriptions }
+    }
+
+    fn registry(&self) -> MutexGuard<'_, Subscriptions> {
+        self.subscriptions.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    pub(crate) fn send(&self, data: JsonValue) -> Result<()> {
+        match self.sender {
+            Some(ref s) => s.send(data.to_string()),
+            None => {
+                debug!("Sender not bound to the instance");
+                Ok(())
+            }
+        }
+    }
+
+    /// Subscribe to a list of instrument_tokens. Fails without sending
+    /// anything if the connection would exceed `MAX_TOKENS_PER_CONNECTION`.
+    pub fn subscribe(&self, instrument_tokens: Vec<u32>) -> Result<()> {
+        let mut subscriptions = self.registry();
+        subscriptions.check_subscribe(&instrument_tokens)?;
+
+        self.send(json!({
+            "a": "subscribe",
+            "v": instrument_tokens
+        }))?;
+        subscriptions.subscribe(&instrument_tokens)
+    }
+
+    /// Unsubscribe the given list of instrument_tokens
+    pub fn unsubscribe(&self, instrument_tokens: Vec<u32>) -> Result<()> {
+        let mut subscriptions = self.registry();
+
+        self.send(json!({
+            "a": "unsubscribe",
+            "v": instrument_tokens
+        }))?;
+        subscriptions.unsubscribe(&instrument_tokens);
+        Ok(())
+    }
+
+    /// Set streaming mode for the given list of subscribed tokens.
+    pub fn set_mode(&self, mode: Mode, instrument_tokens: Vec<u32>) -> Result<()> {
+        let mut subscriptions = self.registry();
+        subscriptions.check_subscribed(&instrument_tokens)?;
+
+        self.send(json!({
+            "a": "mode",
+            "v": [mode.as_str(), instrument_tokens]
+        }))?;
+        subscriptions.set_mode(mode, &instrument_tokens)
+    }
+
+    /// Subscribe to a list of instrument_tokens and set their mode. Both
+    /// messages are sent back to back while holding the subscription lock so
+    /// no other handle can interleave its own requests. The tokens are
+    /// registered once the subscribe request is sent, so if the mode request
+    /// can't be sent they stay subscribed in the default mode and the error
+    /// says so.
+    pub fn subscribe_with_mode(&self, mode: Mode, instrument_tokens: Vec<u32>) -> Result<()> {
+        let mut subscriptions = self.registry();
+        subscriptions.check_subscribe(&instrument_tokens)?;
+
+        self.send(json!({
+            "a": "subscribe",
+            "v": instrument_tokens
+        }))?;
+        subscriptions.subscribe(&instrument_tokens)?;
+
+        self.send(json!({
+            "a": "mode",
+            "v": [mode.as_str(), instrument_tokens]
+        })).map_err(|mut err| {
+            err.details = format!("Subscribed but unable to set mode {}: {}", mode, err.details).into();
+            err
+        })?;
+        subscriptions.set_mode(mode, &instrument_tokens)
+    }
+
+    /// Subscribe to `EXCHANGE:TRADINGSYMBOL` strings resolved through `index`.
+    /// Nothing is sent if any symbol is unknown.
+    pub fn subscribe_symbols(&self, index: &InstrumentIndex, symbols: &[&str]) -> Result<()> {
+        self.subscribe(resolve(index, symbols)?)
+    }
+
+    /// Unsubscribe the given `EXCHANGE:TRADINGSYMBOL` strings
+    pub fn unsubscribe_symbols(&self, index: &InstrumentIndex, symbols: &[&str]) -> Result<()> {
+        self.unsubscribe(resolve(index, symbols)?)
+    }
+
+    /// Set streaming mode for the given `EXCHANGE:TRADINGSYMBOL` strings
+    pub fn set_mode_symbols(&self, mode: Mode, index: &InstrumentIndex, symbols: &[&str]) -> Result<()> {
+        self.set_mode(mode, resolve(index, symbols)?)
+    }
+
+    /// Resubscribe to all current subscribed tokens
+    pub fn resubscribe(&self) -> Result<()> {
+        let subscriptions = self.registry();
+
+        for (mode, tokens) in subscriptions.by_mode() {
+            debug!("Resubscribing and set mode: {:?} - {:?}", mode, tokens);
+            self.send(json!({
+                "a": "subscribe",
+                "v": tokens
+            }))?;
+            if let Some(mode) = mode {
+                self.send(json!({
+                    "a": "mode",
+                    "v": [mode.as_str(), tokens]
+                }))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the subscribed instrument_tokens along with their mode. `None`
+    /// means no mode was requested and the server default applies.
+    pub fn subscriptions(&self) -> HashMap<u32, Option<Mode>> {
+        self.registry().tokens().clone()
+    }
+
+    /// Sends a close frame to the server. The ticker thread exits once the
+    /// close handshake completes.
+    pub fn close(&self) -> Result<()> {
+        match self.sender {
+            Some(ref s) => s.close(CloseCode::Normal),
+            None => {
+                debug!("Sender not bound to the instance");
+                Ok(())
+            }
+        }
+    }
+
+    /// Stops the websocket event loop without waiting for the close handshake
+    pub(crate) fn shutdown(&self) -> Result<()> {
+        match self.sender {
+            Some(ref s) => s.shutdown(),
+            None => Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn test_ticker_handle_is_thread_safe() {
+        assert_send_sync::<TickerHandle>();
+    }
+
+    #[test]
+    fn test_subscriptions_shared_across_clones() {
+        let handle = TickerHandle::new(None, Arc::new(Mutex::new(Subscriptions::default())));
+        let cloned = handle.clone();
+
+        handle.subscribe(vec![408065, 884737]).unwrap();
+        cloned.set_mode(Mode::Full, vec![408065]).unwrap();
+        cloned.unsubscribe(vec![884737]).unwrap();
+
+        let subscriptions = handle.subscriptions();
+        assert_eq!(subscriptions.len(), 1);
+        assert_eq!(subscriptions[&408065], Some(Mode::Full));
+    }
+
+    #[test]
+    fn test_subscribe_with_mode_is_validated() {
+        let handle = TickerHandle::new(None, Arc::new(Mutex::new(Subscriptions::with_limit(2))));
+
+        handle.subscribe_with_mode(Mode::Ltp, vec![1, 2]).unwrap();
+        assert!(handle.subscribe_with_mode(Mode::Full, vec![3]).is_err());
+        assert!(handle.set_mode(Mode::Full, vec![3]).is_err());
+
+        let subscriptions = handle.subscriptions();
+        assert_eq!(subscriptions.len(), 2);
+        assert_eq!(subscriptions[&1], Some(Mode::Ltp));
+    }
+
+    #[test]
+    fn test_subscribe_with_mode_when_mode_send_fails() {
+        // Nothing drains this queue, which holds a single message, so the
+        // mode request waits until the socket is dropped and then fails
+        let settings = ws::Settings { max_connections: 1, queue_size: 1, ..ws::Settings::default() };
+        let socket = ws::Builder::new().with_settings(settings)
+            .build(|_: Sender| |_: ws::Message| Ok(()))
+            .unwrap();
+        let handle = TickerHandle::new(Some(socket.broadcaster()), Arc::new(Mutex::new(Subscriptions::default())));
+        std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(200));
+            drop(socket);
+        });
+
+        let err = handle.subscribe_with_mode(Mode::Full, vec![408065]).unwrap_err();
+        assert!(err.to_string().contains("Subscribed but unable to set mode full"), "{}", err);
+        assert_eq!(handle.subscriptions()[&408065], None);
+    }
+
+    #[test]
+    fn test_subscribe_symbols() {
+        let index = InstrumentIndex::from_csv(std::fs::File::open("mocks/instruments.csv").unwrap()).unwrap();
+        let handle = TickerHandle::new(None, Arc::new(Mutex::new(Subscriptions::default())));
+
+        handle.subscribe_symbols(&index, &["NSE:INFY", "NFO:NIFTY15DECFUT"]).unwrap();
+        handle.set_mode_symbols(Mode::Quote, &index, &["NSE:INFY"]).unwrap();
+        handle.unsubscribe_symbols(&index, &["NFO:NIFTY15DECFUT"]).unwrap();
+
+        // Unknown symbols leave the subscriptions untouched
+        let err = handle.subscribe_symbols(&index, &["MCX:SILVER15DECFUT", "NSE:TCS"]).unwrap_err();
+        assert!(err.to_string().contains("Unknown instruments NSE:TCS"));
+
+        let subscriptions = handle.subscriptions();
+        assert_eq!(subscriptions.len(), 1);
+        assert_eq!(subscriptions[&408065], Some(Mode::Quote));
+    }
+}
@@ -0,0 +1,242 @@
+use log::debug;
+use serde_json::Value as JsonValue;
+
+use crate::alert::AlertEngine;
+use crate::candle::CandleAggregator;
+use super::{KiteTickerHandler, Tick, TickerHandle, WebSocketHandler};
+
+/// Object safe alternative to `KiteTickerHandler`. Callbacks get the
+/// connection's `TickerHandle` instead of the generic `WebSocketHandler`,
+/// so handlers can be boxed, composed with `FanOut` and chosen at runtime.
+/// Connect one with `KiteTicker::connect_dyn`.
+pub trait TickerHandler: Send {
+    fn on_open(&mut self, _ticker: &TickerHandle) {
+        debug!("Connection opened");
+    }
+
+    fn on_ticks(&mut self, _ticker: &TickerHandle, ticks: &[Tick]) {
+        debug!("{:?}", ticks);
+    }
+
+    fn on_order_update(&mut self, _ticker: &TickerHandle, order: &JsonValue) {
+        debug!("Order update {:?}", order);
+    }
+
+    fn on_message(&mut self, _ticker: &TickerHandle, message: &JsonValue) {
+        debug!("Message {:?}", message);
+    }
+
+    fn on_close(&mut self, _ticker: &TickerHandle) {
+        debug!("Connection closed");
+    }
+
+    /// `error` is the websocket error that was reported
+    fn on_error(&mut self, _ticker: &TickerHandle, error: Option<&str>) {
+        debug!("Error {:?}", error);
+    }
+}
+
+impl<H> TickerHandler for Box<H> where H: TickerHandler + ?Sized {
+    fn on_open(&mut self, ticker: &TickerHandle) {
+        (**self).on_open(ticker)
+    }
+
+    fn on_ticks(&mut self, ticker: &TickerHandle, ticks: &[Tick]) {
+        (**self).on_ticks(ticker, ticks)
+    }
+
+    fn on_order_update(&mut self, ticker: &TickerHandle, order: &JsonValue) {
+        (**self).on_order_update(ticker, order)
+    }
+
+    fn on_message(&mut self, ticker: &TickerHandle, message: &JsonValue) {
+        (**self).on_message(ticker, message)
+    }
+
+    fn on_close(&mut self, ticker: &TickerHandle) {
+        (**self).on_close(ticker)
+    }
+
+    fn on_error(&mut self, ticker: &TickerHandle, error: Option<&str>) {
+        (**self).on_error(ticker, error)
+    }
+}
+
+/// Dispatches every event to each registered handler in registration order
+#[derive(Default)]
+pub struct FanOut {
+    handlers: Vec<Box<dyn TickerHandler>>,
+}
+
+impl FanOut {
+    pub fn new() -> FanOut {
+        FanOut::default()
+    }
+
+    /// Registers `handler` after the ones already added
+    pub fn add<H>(&mut self, handler: H) -> &mut FanOut
+    where H: TickerHandler + 'static {
+        self.handlers.push(Box::new(handler));
+        self
+    }
+
+    /// Builder style `add`
+    pub fn with<H>(mut self, handler: H) -> FanOut
+    where H: TickerHandler + 'static {
+        self.add(handler);
+        self
+    }
+
+    /// Number of registered handlers
+    pub fn len(&self) -> usize {
+        self.handlers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.handlers.is_empty()
+    }
+}
+
+impl TickerHandler for FanOut {
+    fn on_open(&mut self, ticker: &TickerHandle) {
+        for handler in self.handlers.iter_mut() {
+            handler.on_open(ticker);
+        }
+    }
+
+    fn on_ticks(&mut self, ticker: &TickerHandle, ticks: &[Tick]) {
+        for handler in self.handlers.iter_mut() {
+            handler.on_ticks(ticker, ticks);
+        }
+    }
+
+    fn on_order_update(&mut self, ticker: &TickerHandle, order: &JsonValue) {
+        for handler in self.handlers.iter_mut() {
+            handler.on_order_update(ticker, order);
+        }
+    }
+
+    fn on_message(&mut self, ticker: &TickerHandle, message: &JsonValue) {
+        for handler in self.handlers.iter_mut() {
+            handler.on_message(ticker, message);
+        }
+    }
+
+    fn on_close(&mut self, ticker: &TickerHandle) {
+        for handler in self.handlers.iter_mut() {
+            handler.on_close(ticker);
+        }
+    }
+
+    fn on_error(&mut self, ticker: &TickerHandle, error: Option<&str>) {
+        for handler in self.handlers.iter_mut() {
+            handler.on_error(ticker, error);
+        }
+    }
+}
+
+impl TickerHandler for CandleAggregator {
+    fn on_ticks(&mut self, _ticker: &TickerHandle, ticks: &[Tick]) {
+        for tick in ticks {
+            self.update(tick);
+        }
+    }
+}
+
+impl TickerHandler for AlertEngine {
+    fn on_ticks(&mut self, _ticker: &TickerHandle, ticks: &[Tick]) {
+        for tick in ticks {
+            self.update(tick);
+        }
+    }
+}
+
+/// Drives a boxed `TickerHandler` from the generic websocket callbacks
+pub(crate) struct DynHandler(pub(crate) Box<dyn TickerHandler>);
+
+impl KiteTickerHandler for DynHandler {
+    fn on_open<T>(&mut self, ws: &mut WebSocketHandler<T>)
+    where T: KiteTickerHandler {
+        self.0.on_open(&ws.ticker);
+    }
+
+    fn on_ticks<T>(&mut self, ws: &mut WebSocketHandler<T>, ticks: Vec<Tick>)
+    where T: KiteTickerHandler {
+        self.0.on_ticks(&ws.ticker, &ticks);
+    }
+
+    fn on_order_update<T>(&mut self, ws: &mut WebSocketHandler<T>, order: JsonValue)
+    where T: KiteTickerHandler {
+        self.0.on_order_update(&ws.ticker, &order);
+    }
+
+    fn on_message<T>(&mut self, ws: &mut WebSocketHandler<T>, message: JsonValue)
+    where T: KiteTickerHandler {
+        self.0.on_message(&ws.ticker, &message);
+    }
+
+    fn on_close<T>(&mut self, ws: &mut WebSocketHandler<T>)
+    where T: KiteTickerHandler {
+        self.0.on_close(&ws.ticker);
+    }
+
+    fn on_error<T>(&mut self, ws: &mut WebSocketHandler<T>)
+    where T: KiteTickerHandler {
+        self.0.on_error(&ws.ticker, ws.last_error.as_deref());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::time::Duration;
+    use crate::ticker::{KiteTicker, MockTickerServer, Mode};
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    struct Subscriber;
+
+    impl TickerHandler for Subscriber {
+        fn on_open(&mut self, ticker: &TickerHandle) {
+            ticker.subscribe_with_mode(Mode::Ltp, vec![408065]).unwrap();
+        }
+    }
+
+    struct Forward(&'static str, mpsc::Sender<(&'static str, Vec<Tick>)>);
+
+    impl TickerHandler for Forward {
+        fn on_ticks(&mut self, _ticker: &TickerHandle, ticks: &[Tick]) {
+            self.1.send((self.0, ticks.to_vec())).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_fan_out() {
+        let server = MockTickerServer::start().unwrap();
+        let (sender, received) = mpsc::channel();
+
+        // Handlers picked at runtime
+        let handlers: Vec<Box<dyn TickerHandler>> = vec![
+            Box::new(Forward("first", sender.clone())),
+            Box::new(Forward("second", sender)),
+        ];
+        let mut fan_out = FanOut::new().with(Subscriber);
+        for handler in handlers {
+            fan_out.add(handler);
+        }
+        assert_eq!(fan_out.len(), 3);
+
+        let mut ticker = KiteTicker::new("<API-KEY>", "<ACCESS-TOKEN>");
+        ticker.connect_dyn(Box::new(fan_out), Some(&server.url())).unwrap();
+        assert!(server.wait_for_subscription(408065, Mode::Ltp, TIMEOUT));
+
+        let tick = Tick { instrument_token: 408065, last_price: 1234.5, ..Tick::default() };
+        server.send_ticks(std::slice::from_ref(&tick)).unwrap();
+        assert_eq!(received.recv_timeout(TIMEOUT).unwrap(), ("first", vec![tick.clone()]));
+        assert_eq!(received.recv_timeout(TIMEOUT).unwrap(), ("second", vec![tick]));
+
+        ticker.close().unwrap();
+        ticker.join().unwrap();
+    }
+}
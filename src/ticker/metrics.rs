@@ -0,0 +1,568 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as FmtWrite;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use chrono::{DateTime, FixedOffset};
+use log::debug;
+
+/// Bucket bounds in seconds for parse and callback durations
+const DURATION_BUCKETS: &[f64] = &[0.00001, 0.00005, 0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5];
+
+/// Bucket bounds in seconds for exchange to receive latency
+const LATENCY_BUCKETS: &[f64] = &[0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+/// Window over which ticks and frames per second are measured
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// Scrapes served at once, further connections are dropped
+const MAX_SCRAPES: usize = 8;
+
+/// How long a client has to send its request
+pub(super) const REQUEST_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Longest request or header line accepted by the local HTTP endpoints
+const MAX_LINE: usize = 8192;
+
+/// Most header lines accepted by the local HTTP endpoints
+const MAX_HEADERS: usize = 100;
+
+/// Metric name, help text and how to read it from a snapshot
+type Field<T> = (&'static str, &'static str, fn(&ConnectionSnapshot) -> T);
+type HistogramField = (&'static str, &'static str, fn(&ConnectionSnapshot) -> &HistogramSnapshot);
+
+struct Histogram {
+    bounds: &'static [f64],
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Histogram {
+        Histogram { bounds, counts: vec![0; bounds.len()], sum: 0.0, count: 0 }
+    }
+
+    fn observe(&mut self, value: f64) {
+        if let Some(index) = self.bounds.iter().position(|bound| value <= *bound) {
+            self.counts[index] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn snapshot(&self) -> HistogramSnapshot {
+        let mut cumulative = 0;
+        let buckets = self.bounds.iter().zip(self.counts.iter())
+            .map(|(bound, count)| {
+                cumulative += count;
+                (*bound, cumulative)
+            })
+            .collect();
+        HistogramSnapshot { buckets, sum: self.sum, count: self.count }
+    }
+}
+
+/// Observations of a histogram, in seconds
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistogramSnapshot {
+    /// Upper bound of each bucket with the number of observations at or below it
+    pub buckets: Vec<(f64, u64)>,
+    pub sum: f64,
+    pub count: u64,
+}
+
+impl HistogramSnapshot {
+    /// Average observation, `None` before the first one
+    pub fn mean(&self) -> Option<f64> {
+        if self.count == 0 {
+            None
+        } else {
+            Some(self.sum / self.count as f64)
+        }
+    }
+
+    /// Upper bound of the bucket holding the `quantile` (0 to 1) observation.
+    /// `f64::INFINITY` if it is above the last bucket.
+    pub fn quantile(&self, quantile: f64) -> Option<f64> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = (quantile.clamp(0.0, 1.0) * self.count as f64).ceil().max(1.0) as u64;
+        Some(self.buckets.iter()
+            .find(|(_, count)| *count >= rank)
+            .map_or(f64::INFINITY, |(bound, _)| *bound))
+    }
+}
+
+struct Rate {
+    started: Instant,
+    frames: u64,
+    ticks: u64,
+    frames_per_second: f64,
+    ticks_per_second: f64,
+}
+
+impl Rate {
+    /// Closes the window once it has run for `RATE_WINDOW`
+    fn roll(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.started);
+        if elapsed >= RATE_WINDOW {
+            let seconds = elapsed.as_secs_f64();
+            self.frames_per_second = self.frames as f64 / seconds;
+            self.ticks_per_second = self.ticks as f64 / seconds;
+            self.frames = 0;
+            self.ticks = 0;
+            self.started = now;
+        }
+    }
+}
+
+struct State {
+    connects: u64,
+    frames: u64,
+    bytes: u64,
+    ticks: u64,
+    messages: u64,
+    errors: u64,
+    rate: Rate,
+    parse_time: Histogram,
+    callback_time: Histogram,
+    latency: Histogram,
+}
+
+/// Counters of a single ticker connection, shared with the `TickerMetrics`
+/// it was created from. Attach it with `KiteTicker::set_metrics`; reconnects
+/// of that ticker keep adding to it.
+#[derive(Clone)]
+pub struct ConnectionMetrics {
+    name: Arc<str>,
+    state: Arc<Mutex<State>>,
+}
+
+impl ConnectionMetrics {
+    fn new(name: &str) -> ConnectionMetrics {
+        ConnectionMetrics {
+            name: name.into(),
+            state: Arc::new(Mutex::new(State {
+                connects: 0,
+                frames: 0,
+                bytes: 0,
+                ticks: 0,
+                messages: 0,
+                errors: 0,
+                rate: Rate {
+                    started: Instant::now(),
+                    frames: 0,
+                    ticks: 0,
+                    frames_per_second: 0.0,
+                    ticks_per_second: 0.0,
+                },
+                parse_time: Histogram::new(DURATION_BUCKETS),
+                callback_time: Histogram::new(DURATION_BUCKETS),
+                latency: Histogram::new(LATENCY_BUCKETS),
+            })),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    pub(crate) fn connected(&self) {
+        self.state().connects += 1;
+    }
+
+    pub(crate) fn frame(&self, bytes: usize) {
+        let mut state = self.state();
+        state.frames += 1;
+        state.bytes += bytes as u64;
+        state.rate.frames += 1;
+        state.rate.roll(Instant::now());
+    }
+
+    pub(crate) fn message(&self) {
+        self.state().messages += 1;
+    }
+
+    pub(crate) fn error(&self) {
+        self.state().errors += 1;
+    }
+
+    /// Counts a parsed frame of ticks and the latency of each tick carrying
+    /// an exchange timestamp
+    pub(crate) fn ticks<I>(&self, parse_time: Duration, received: DateTime<FixedOffset>, timestamps: I)
+    where I: IntoIterator<Item = Option<DateTime<FixedOffset>>> {
+        let mut state = self.state();
+        state.parse_time.observe(parse_time.as_secs_f64());
+        for timestamp in timestamps {
+            state.ticks += 1;
+            state.rate.ticks += 1;
+            if let Some(timestamp) = timestamp {
+                // Clocks drift, a tick can't arrive before it was sent
+                let latency = received.signed_duration_since(timestamp).num_microseconds().unwrap_or(0).max(0);
+                state.latency.observe(latency as f64 / 1e6);
+            }
+        }
+    }
+
+    pub(crate) fn callback(&self, elapsed: Duration) {
+        self.state().callback_time.observe(elapsed.as_secs_f64());
+    }
+
+    /// Name the connection was registered with
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Current values of every counter
+    pub fn snapshot(&self) -> ConnectionSnapshot {
+        let mut state = self.state();
+        // Closes a window left open by a quiet feed
+        state.rate.roll(Instant::now());
+
+        ConnectionSnapshot {
+            connection: self.name.to_string(),
+            connects: state.connects,
+            frames: state.frames,
+            bytes: state.bytes,
+            ticks: state.ticks,
+            messages: state.messages,
+            errors: state.errors,
+            frames_per_second: state.rate.frames_per_second,
+            ticks_per_second: state.rate.ticks_per_second,
+            parse_time: state.parse_time.snapshot(),
+            callback_time: state.callback_time.snapshot(),
+            latency: state.latency.snapshot(),
+        }
+    }
+}
+
+/// Point in time view of a connection's counters
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectionSnapshot {
+    pub connection: String,
+    /// Number of times the connection was opened
+    pub connects: u64,
+    /// Websocket frames received
+    pub frames: u64,
+    pub bytes: u64,
+    /// Ticks decoded from binary frames
+    pub ticks: u64,
+    /// Text messages such as order updates
+    pub messages: u64,
+    /// Websocket and parse errors
+    pub errors: u64,
+    /// Rates over the last window of at least a second
+    pub frames_per_second: f64,
+    pub ticks_per_second: f64,
+    /// Time spent decoding binary frames
+    pub parse_time: HistogramSnapshot,
+    /// Time spent in handler callbacks
+    pub callback_time: HistogramSnapshot,
+    /// Receive time minus exchange timestamp of full mode ticks
+    pub latency: HistogramSnapshot,
+}
+
+/// Registry of per connection metrics, exposed through `snapshot`, the
+/// Prometheus text format or an HTTP endpoint started with `serve`.
+/// Clones share the same registry.
+#[derive(Clone, Default)]
+pub struct TickerMetrics {
+    connections: Arc<Mutex<BTreeMap<String, ConnectionMetrics>>>,
+}
+
+impl TickerMetrics {
+    pub fn new() -> TickerMetrics {
+        TickerMetrics::default()
+    }
+
+    /// Metrics of the connection called `name`, created on first use
+    pub fn connection(&self, name: &str) -> ConnectionMetrics {
+        let mut connections = self.connections.lock().unwrap_or_else(|err| err.into_inner());
+        connections.entry(name.to_string())
+            .or_insert_with(|| ConnectionMetrics::new(name))
+            .clone()
+    }
+
+    /// Current values of every connection, ordered by name
+    pub fn snapshot(&self) -> Vec<ConnectionSnapshot> {
+        let connections: Vec<ConnectionMetrics> = self.connections.lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .values()
+            .cloned()
+            .collect();
+        connections.iter().map(|connection| connection.snapshot()).collect()
+    }
+
+    /// Renders every connection in the Prometheus text exposition format
+    pub fn to_prometheus(&self) -> String {
+        let snapshots = self.snapshot();
+        let mut out = String::new();
+
+        let counters: [Field<u64>; 6] = [
+            ("connects_total", "Times the connection was opened", |s| s.connects),
+            ("frames_total", "Websocket frames received", |s| s.frames),
+            ("bytes_total", "Bytes of websocket frames received", |s| s.bytes),
+            ("ticks_total", "Ticks decoded", |s| s.ticks),
+            ("messages_total", "Text messages received", |s| s.messages),
+            ("errors_total", "Websocket and parse errors", |s| s.errors),
+        ];
+        for (name, help, value) in counters.iter() {
+            let _ = writeln!(out, "# HELP kite_ticker_{} {}\n# TYPE kite_ticker_{} counter", name, help, name);
+            for snapshot in snapshots.iter() {
+                let _ = writeln!(out, "kite_ticker_{}{{connection=\"{}\"}} {}", name, label(&snapshot.connection), value(snapshot));
+            }
+        }
+
+        let gauges: [Field<f64>; 2] = [
+            ("frames_per_second", "Frames received over the last second", |s| s.frames_per_second),
+            ("ticks_per_second", "Ticks decoded over the last second", |s| s.ticks_per_second),
+        ];
+        for (name, help, value) in gauges.iter() {
+            let _ = writeln!(out, "# HELP kite_ticker_{} {}\n# TYPE kite_ticker_{} gauge", name, help, name);
+            for snapshot in snapshots.iter() {
+                let _ = writeln!(out, "kite_ticker_{}{{connection=\"{}\"}} {}", name, label(&snapshot.connection), value(snapshot));
+            }
+        }
+
+        let histograms: [HistogramField; 3] = [
+            ("parse_seconds", "Time spent decoding binary frames", |s| &s.parse_time),
+            ("callback_seconds", "Time spent in handler callbacks", |s| &s.callback_time),
+            ("latency_seconds", "Receive time minus exchange timestamp", |s| &s.latency),
+        ];
+        for (name, help, value) in histograms.iter() {
+            let _ = writeln!(out, "# HELP kite_ticker_{} {}\n# TYPE kite_ticker_{} histogram", name, help, name);
+            for snapshot in snapshots.iter() {
+                let connection = label(&snapshot.connection);
+                let histogram = value(snapshot);
+                for (bound, count) in histogram.buckets.iter() {
+                    let _ = writeln!(out, "kite_ticker_{}_bucket{{connection=\"{}\",le=\"{}\"}} {}", name, connection, bound, count);
+                }
+                let _ = writeln!(out, "kite_ticker_{}_bucket{{connection=\"{}\",le=\"+Inf\"}} {}", name, connection, histogram.count);
+                let _ = writeln!(out, "kite_ticker_{}_sum{{connection=\"{}\"}} {}", name, connection, histogram.sum);
+                let _ = writeln!(out, "kite_ticker_{}_count{{connection=\"{}\"}} {}", name, connection, histogram.count);
+            }
+        }
+        out
+    }
+
+    /// Serves `to_prometheus` at `GET /metrics` on `address` until the
+    /// returned server is dropped
+    pub fn serve<A: ToSocketAddrs>(&self, address: A) -> io::Result<MetricsServer> {
+        let listener = TcpListener::bind(address)?;
+        let address = listener.local_addr()?;
+        let stopped = Arc::new(AtomicBool::new(false));
+
+        let metrics = self.clone();
+        let stop = stopped.clone();
+        let active = Arc::new(AtomicUsize::new(0));
+        let thread = thread::spawn(move || {
+            for stream in listener.incoming() {
+                if stop.load(Ordering::SeqCst) {
+                    break;
+                }
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        debug!("Metrics connection failed: {}", err);
+                        continue;
+                    }
+                };
+                // Each scrape gets its own thread so a stalled client
+                // doesn't hold up the others
+                if active.fetch_add(1, Ordering::SeqCst) >= MAX_SCRAPES {
+                    active.fetch_sub(1, Ordering::SeqCst);
+                    debug!("Dropping metrics connection, {} scrapes in progress", MAX_SCRAPES);
+                    continue;
+                }
+                let metrics = metrics.clone();
+                let active = active.clone();
+                thread::spawn(move || {
+                    if let Err(err) = respond(&metrics, stream) {
+                        debug!("Unable to serve metrics: {}", err);
+                    }
+                    active.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+        });
+
+        Ok(MetricsServer { address, stopped, thread: Some(thread) })
+    }
+}
+
+/// Escapes a Prometheus label value
+fn label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// Reads a line of at most `MAX_LINE` bytes
+fn read_line<R: BufRead>(reader: &mut R, line: &mut String) -> io::Result<usize> {
+    let read = reader.take(MAX_LINE as u64).read_line(line)?;
+    if read == MAX_LINE && !line.ends_with('\n') {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "request line too long"));
+    }
+    Ok(read)
+}
+
+/// Reads the request line and headers of a local HTTP request, with a read
+/// timeout and size limits so a client can't stall or flood the server.
+/// Returns the request line.
+pub(crate) fn read_request(stream: &TcpStream, timeout: Duration) -> io::Result<String> {
+    stream.set_read_timeout(Some(timeout))?;
+    let mut reader = BufReader::new(stream);
+    let mut request = String::new();
+    read_line(&mut reader, &mut request)?;
+    // Drain the headers
+    let mut line = String::new();
+    let mut headers = 0;
+    while read_line(&mut reader, &mut line)? > 2 {
+        headers += 1;
+        if headers > MAX_HEADERS {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "too many headers"));
+        }
+        line.clear();
+    }
+    Ok(request)
+}
+
+fn respond(metrics: &TickerMetrics, mut stream: TcpStream) -> io::Result<()> {
+    let request = read_request(&stream, REQUEST_TIMEOUT)?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut parts = request.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", metrics.to_prometheus()),
+        _ => ("404 Not Found", String::new()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, body.len(), body
+    )?;
+    stream.flush()
+}
+
+/// HTTP endpoint started by `TickerMetrics::serve`. Stops when dropped.
+pub struct MetricsServer {
+    address: SocketAddr,
+    stopped: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MetricsServer {
+    /// Address the endpoint listens on
+    pub fn local_addr(&self) -> SocketAddr {
+        self.address
+    }
+}
+
+impl Drop for MetricsServer {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        // Wakes the listener blocked in accept
+        let _ = TcpStream::connect(self.address);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use chrono::TimeZone;
+    use crate::ticker::ist;
+
+    #[test]
+    fn test_snapshot() {
+        let metrics = TickerMetrics::new();
+        let connection = metrics.connection("main");
+        connection.connected();
+        connection.frame(100);
+        let received = ist().with_ymd_and_hms(2020, 1, 1, 9, 15, 1).unwrap();
+        let sent = received - chrono::Duration::milliseconds(200);
+        connection.ticks(Duration::from_micros(30), received, vec![Some(sent), None]);
+        connection.callback(Duration::from_micros(700));
+        connection.message();
+
+        let snapshot = &metrics.snapshot()[0];
+        assert_eq!(snapshot.connection, "main");
+        assert_eq!((snapshot.connects, snapshot.frames, snapshot.bytes, snapshot.ticks), (1, 1, 100, 2));
+        assert_eq!(snapshot.latency.count, 1);
+        assert_eq!(snapshot.latency.quantile(0.99), Some(0.25));
+        assert!((snapshot.latency.mean().unwrap() - 0.2).abs() < 1e-9);
+        assert_eq!(snapshot.parse_time.quantile(0.5), Some(0.00005));
+        assert_eq!(snapshot.callback_time.quantile(0.5), Some(0.001));
+
+        // Same name, same counters
+        metrics.connection("main").error();
+        assert_eq!(connection.snapshot().errors, 1);
+    }
+
+    #[test]
+    fn test_ticker_metrics() {
+        use crate::ticker::{Backpressure, KiteTicker, MockTickerServer, Mode, Tick, TickerEvent};
+
+        let metrics = TickerMetrics::new();
+        let server = MockTickerServer::start().unwrap();
+        let mut ticker = KiteTicker::new("<API-KEY>", "<ACCESS-TOKEN>");
+        ticker.set_metrics(metrics.connection("main"));
+        let (handle, stream) = ticker.stream(100, Backpressure::Block, Some(&server.url())).unwrap();
+        assert_eq!(stream.recv(), Some(TickerEvent::Connected));
+
+        handle.subscribe_with_mode(Mode::Ltp, vec![408065]).unwrap();
+        assert!(server.wait_for_subscription(408065, Mode::Ltp, Duration::from_secs(5)));
+        server.send_ticks(&[Tick { instrument_token: 408065, last_price: 1234.5, ..Tick::default() }]).unwrap();
+        assert!(matches!(stream.recv(), Some(TickerEvent::Tick(_))));
+
+        // The callback is timed after the stream received the tick
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while metrics.snapshot()[0].callback_time.count == 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(1));
+        }
+        let snapshot = &metrics.snapshot()[0];
+        assert_eq!((snapshot.connects, snapshot.frames, snapshot.ticks), (1, 1, 1));
+        assert_eq!((snapshot.parse_time.count, snapshot.callback_time.count), (1, 1));
+        // Ltp ticks carry no exchange timestamp
+        assert_eq!(snapshot.latency.count, 0);
+
+        ticker.close().unwrap();
+        ticker.join().unwrap();
+    }
+
+    #[test]
+    fn test_prometheus_endpoint() {
+        let metrics = TickerMetrics::new();
+        metrics.connection("shard \"1\"").frame(10);
+        let server = metrics.serve("127.0.0.1:0").unwrap();
+        // An idle client doesn't hold up scrapes
+        let _idle = TcpStream::connect(server.local_addr()).unwrap();
+
+        let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        stream.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("# TYPE kite_ticker_frames_total counter\n"));
+        assert!(response.contains("kite_ticker_frames_total{connection=\"shard \\\"1\\\"\"} 1\n"));
+        assert!(response.contains("kite_ticker_latency_seconds_bucket{connection=\"shard \\\"1\\\"\",le=\"+Inf\"} 0\n"));
+
+        let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 404"));
+
+        let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+        let _ = stream.write_all(&[b'A'; MAX_LINE + 1]);
+        let mut response = String::new();
+        let _ = stream.read_to_string(&mut response);
+        assert!(response.is_empty());
+        drop(server);
+    }
+}
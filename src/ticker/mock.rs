@@ -0,0 +1,340 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use log::debug;
+use serde_json::{json, Value as JsonValue};
+use ws::{CloseCode, Handler, Handshake, Message, Result, Sender, WebSocket};
+
+use super::{encode_binary, Mode, Tick};
+
+/// Mode Kite streams in until a client sets one
+const DEFAULT_MODE: Mode = Mode::Quote;
+
+struct Client {
+    out: Sender,
+    tokens: HashMap<u32, Mode>,
+}
+
+#[derive(Default)]
+struct State {
+    clients: HashMap<u32, Client>,
+    messages: Vec<JsonValue>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+/// Server side of a single client connection
+struct Connection {
+    out: Sender,
+    shared: Arc<Shared>,
+}
+
+fn tokens(value: &JsonValue) -> Vec<u32> {
+    value.as_array()
+        .map(|tokens| tokens.iter().filter_map(|token| token.as_u64()).map(|token| token as u32).collect())
+        .unwrap_or_default()
+}
+
+impl Handler for Connection {
+    fn on_open(&mut self, _shake: Handshake) -> Result<()> {
+        self.shared.lock().clients.insert(self.out.connection_id(), Client {
+            out: self.out.clone(),
+            tokens: HashMap::new(),
+        });
+        self.shared.changed.notify_all();
+        Ok(())
+    }
+
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        let text = msg.into_text()?;
+        let message: JsonValue = match serde_json::from_str(&text) {
+            Ok(message) => message,
+            Err(err) => {
+                debug!("Unable to parse client message {:?}: {}", text, err);
+                return Ok(());
+            }
+        };
+
+        let mut state = self.shared.lock();
+        if let Some(client) = state.clients.get_mut(&self.out.connection_id()) {
+            match message["a"].as_str() {
+                Some("subscribe") => {
+                    for token in tokens(&message["v"]) {
+                        client.tokens.entry(token).or_insert(DEFAULT_MODE);
+                    }
+                },
+                Some("unsubscribe") => {
+                    for token in tokens(&message["v"]) {
+                        client.tokens.remove(&token);
+                    }
+                },
+                Some("mode") => {
+                    match message["v"][0].as_str().map(|mode| mode.parse::<Mode>()) {
+                        Some(Ok(mode)) => {
+                            for token in tokens(&message["v"][1]) {
+                                if let Some(current) = client.tokens.get_mut(&token) {
+                                    *current = mode;
+                                }
+                            }
+                        },
+                        _ => debug!("Invalid mode message {:?}", message)
+                    }
+                },
+                _ => debug!("Unknown client message {:?}", message)
+            }
+        }
+        state.messages.push(message);
+        drop(state);
+
+        self.shared.changed.notify_all();
+        Ok(())
+    }
+
+    fn on_close(&mut self, code: CloseCode, _reason: &str) {
+        debug!("Mock client disconnected {:?}", code);
+        self.shared.lock().clients.remove(&self.out.connection_id());
+        self.shared.changed.notify_all();
+    }
+}
+
+/// Local stand-in for the Kite ticker, for testing handlers against a real
+/// `KiteTicker` without network access. It tracks the subscribe and mode
+/// messages of every client and streams ticks only to the clients subscribed
+/// to them, encoded in the mode each client asked for.
+///
+/// ```no_run
+/// use kiteconnect::ticker::{Backpressure, KiteTicker, MockTickerServer};
+///
+/// let server = MockTickerServer::start().unwrap();
+/// let mut ticker = KiteTicker::new("<API-KEY>", "<ACCESS-TOKEN>");
+/// let (handle, stream) = ticker.stream(100, Backpressure::Block, Some(&server.url())).unwrap();
+/// ```
+pub struct MockTickerServer {
+    address: SocketAddr,
+    broadcaster: Sender,
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<Result<()>>>,
+}
+
+impl MockTickerServer {
+    /// Starts the server on a free local port
+    pub fn start() -> Result<MockTickerServer> {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State::default()),
+            changed: Condvar::new(),
+        });
+
+        let factory_shared = shared.clone();
+        let server = WebSocket::new(move |out: Sender| Connection {
+            out,
+            shared: factory_shared.clone(),
+        })?.bind("127.0.0.1:0")?;
+        let address = server.local_addr()?;
+        let broadcaster = server.broadcaster();
+        let thread = thread::spawn(move || server.run().map(|_| ()));
+
+        Ok(MockTickerServer {
+            address,
+            broadcaster,
+            shared,
+            thread: Some(thread),
+        })
+    }
+
+    /// Uri to pass to `KiteTicker::connect`
+    pub fn url(&self) -> String {
+        format!("ws://{}", self.address)
+    }
+
+    /// Number of connected clients
+    pub fn clients(&self) -> usize {
+        self.shared.lock().clients.len()
+    }
+
+    /// Tokens subscribed by any client along with the mode they stream in
+    pub fn subscriptions(&self) -> HashMap<u32, Mode> {
+        let state = self.shared.lock();
+        state.clients.values()
+            .flat_map(|client| client.tokens.iter().map(|(token, mode)| (*token, *mode)))
+            .collect()
+    }
+
+    /// Every message received from clients so far, in order
+    pub fn messages(&self) -> Vec<JsonValue> {
+        self.shared.lock().messages.clone()
+    }
+
+    fn wait<F>(&self, timeout: Duration, condition: F) -> bool
+    where F: Fn(&State) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.shared.lock();
+        while !condition(&state) {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            state = self.shared.changed.wait_timeout(state, deadline - now)
+                .unwrap_or_else(|err| err.into_inner()).0;
+        }
+        true
+    }
+
+    /// Blocks until at least `clients` are connected, returning `false` on
+    /// timeout
+    pub fn wait_for_clients(&self, clients: usize, timeout: Duration) -> bool {
+        self.wait(timeout, |state| state.clients.len() >= clients)
+    }
+
+    /// Blocks until some client streams `token` in `mode`, returning `false`
+    /// on timeout
+    pub fn wait_for_subscription(&self, token: u32, mode: Mode, timeout: Duration) -> bool {
+        self.wait(timeout, |state| {
+            state.clients.values().any(|client| client.tokens.get(&token) == Some(&mode))
+        })
+    }
+
+    /// Sends `ticks` as a single frame to every client, keeping only the
+    /// ticks it subscribed to. Returns the number of ticks delivered.
+    pub fn send_ticks(&self, ticks: &[Tick]) -> Result<usize> {
+        let state = self.shared.lock();
+        let mut delivered = 0;
+        for client in state.clients.values() {
+            let frame: Vec<Tick> = ticks.iter()
+                .filter_map(|tick| client.tokens.get(&tick.instrument_token).map(|mode| Tick {
+                    mode: *mode,
+                    ..tick.clone()
+                }))
+                .collect();
+
+            if !frame.is_empty() {
+                client.out.send(Message::Binary(encode_binary(&frame)))?;
+                delivered += frame.len();
+            }
+        }
+        Ok(delivered)
+    }
+
+    /// Streams scripted or recorded frames, pausing `interval` after each
+    pub fn play<I>(&self, frames: I, interval: Duration) -> Result<usize>
+    where I: IntoIterator<Item = Vec<Tick>> {
+        let mut delivered = 0;
+        for frame in frames {
+            delivered += self.send_ticks(&frame)?;
+            thread::sleep(interval);
+        }
+        Ok(delivered)
+    }
+
+    /// Pushes an order postback to every client
+    pub fn send_order_update(&self, order: JsonValue) -> Result<()> {
+        self.send_message(json!({
+            "type": "order",
+            "data": order
+        }))
+    }
+
+    /// Pushes a text message to every client
+    pub fn send_message(&self, message: JsonValue) -> Result<()> {
+        self.broadcaster.send(message.to_string())
+    }
+
+    /// Closes every client connection, leaving the server running so
+    /// clients can reconnect
+    pub fn disconnect(&self) -> Result<()> {
+        self.broadcaster.close(CloseCode::Away)
+    }
+
+    /// Stops the server and waits for its thread to exit
+    pub fn shutdown(&mut self) -> Result<()> {
+        if let Some(thread) = self.thread.take() {
+            self.broadcaster.shutdown()?;
+            if thread.join().is_err() {
+                debug!("Mock ticker server thread panicked");
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for MockTickerServer {
+    fn drop(&mut self) {
+        if let Err(err) = self.shutdown() {
+            debug!("Unable to stop the mock ticker server: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ticker::{Backpressure, Depth, DepthItem, KiteTicker, Ohlc, TickerEvent};
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn full_tick(instrument_token: u32) -> Tick {
+        Tick {
+            mode: Mode::Full,
+            instrument_token,
+            last_price: 1234.5,
+            last_quantity: 10,
+            average_price: 1230.0,
+            volume: 50000,
+            ohlc: Ohlc { open: 1220.0, high: 1240.0, low: 1210.0, close: 1200.0 },
+            change: 2.875,
+            exchange_timestamp: crate::ticker::tick::exchange_time(1577850300),
+            depth: Some(Depth {
+                buy: vec![DepthItem { quantity: 100, price: 1234.0, orders: 2 }; 5],
+                sell: vec![DepthItem { quantity: 50, price: 1235.0, orders: 1 }; 5],
+            }),
+            ..Tick::default()
+        }
+    }
+
+    #[test]
+    fn test_streams_subscribed_ticks() {
+        let server = MockTickerServer::start().unwrap();
+        let mut ticker = KiteTicker::new("<API-KEY>", "<ACCESS-TOKEN>");
+        let (handle, stream) = ticker.stream(100, Backpressure::Block, Some(&server.url())).unwrap();
+        assert_eq!(stream.recv(), Some(TickerEvent::Connected));
+
+        handle.subscribe_with_mode(Mode::Full, vec![408065]).unwrap();
+        handle.subscribe(vec![884737]).unwrap();
+        assert!(server.wait_for_subscription(408065, Mode::Full, TIMEOUT));
+        assert!(server.wait_for_subscription(884737, Mode::Quote, TIMEOUT));
+
+        // 738561 isn't subscribed and 884737 is downgraded to quote mode
+        let delivered = server.send_ticks(&[full_tick(408065), full_tick(884737), full_tick(738561)]).unwrap();
+        assert_eq!(delivered, 2);
+
+        assert_eq!(stream.recv(), Some(TickerEvent::Tick(full_tick(408065))));
+        match stream.recv() {
+            Some(TickerEvent::Tick(tick)) => {
+                assert_eq!(tick.mode, Mode::Quote);
+                assert_eq!(tick.last_price, 1234.5);
+                assert_eq!(tick.depth, None);
+            },
+            event => panic!("Expected a tick, got {:?}", event),
+        }
+
+        server.send_order_update(json!({"order_id": "1"})).unwrap();
+        assert_eq!(stream.recv(), Some(TickerEvent::OrderUpdate(json!({"order_id": "1"}))));
+
+        handle.unsubscribe(vec![408065]).unwrap();
+        assert!(server.wait(TIMEOUT, |state| state.messages.len() == 4));
+        assert_eq!(server.subscriptions().len(), 1);
+
+        ticker.close().unwrap();
+        ticker.join().unwrap();
+    }
+}
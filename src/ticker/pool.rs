@@ -0,0 +1,718 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use log::debug;
+use serde_json::Value as JsonValue;
+use ws::{Error, ErrorKind, Result};
+
+use crate::instruments::InstrumentIndex;
+use crate::session::AccessToken;
+
+use super::handle::resolve;
+use super::stream::{self, ChannelHandler};
+use super::{
+    Backpressure, ConnectionMetrics, KiteTicker, KiteTickerHandler, Mode, Subscriptions, Tick,
+    TickerHandle, TickerMetrics, TickerStream, WebSocketHandler, MAX_TOKENS_PER_CONNECTION
+};
+
+/// Maximum number of websocket connections Kite allows per user
+pub const MAX_CONNECTIONS: usize = 3;
+
+const RECONNECT_MIN_DELAY: Duration = Duration::from_secs(1);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(60);
+
+struct Shard {
+    /// Tokens this shard should be subscribed to, replayed on every reconnect
+    desired: Subscriptions,
+    /// Handle of the live connection, `None` while (re)connecting
+    handle: Option<TickerHandle>,
+    /// Whether the current connection attempt reached `on_open`
+    opened: bool,
+}
+
+struct PoolState {
+    shards: Vec<Shard>,
+    stopped: bool,
+}
+
+struct Shared {
+    state: Mutex<PoolState>,
+    stop: Condvar,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, PoolState> {
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+/// Shares a single user handler between the connections of a pool
+struct SharedHandler<H> {
+    inner: Arc<Mutex<H>>,
+}
+
+impl<H> Clone for SharedHandler<H> {
+    fn clone(&self) -> Self {
+        SharedHandler { inner: self.inner.clone() }
+    }
+}
+
+impl<H> KiteTickerHandler for SharedHandler<H> where H: KiteTickerHandler {
+    fn on_open<T>(&mut self, ws: &mut WebSocketHandler<T>)
+    where T: KiteTickerHandler {
+        self.inner.lock().unwrap().on_open(ws);
+    }
+
+    fn on_ticks<T>(&mut self, ws: &mut WebSocketHandler<T>, ticks: Vec<Tick>)
+    where T: KiteTickerHandler {
+        self.inner.lock().unwrap().on_ticks(ws, ticks);
+    }
+
+    fn on_order_update<T>(&mut self, ws: &mut WebSocketHandler<T>, order: JsonValue)
+    where T: KiteTickerHandler {
+        self.inner.lock().unwrap().on_order_update(ws, order);
+    }
+
+    fn on_message<T>(&mut self, ws: &mut WebSocketHandler<T>, message: JsonValue)
+    where T: KiteTickerHandler {
+        self.inner.lock().unwrap().on_message(ws, message);
+    }
+
+    fn on_close<T>(&mut self, ws: &mut WebSocketHandler<T>)
+    where T: KiteTickerHandler {
+        self.inner.lock().unwrap().on_close(ws);
+    }
+
+    fn on_error<T>(&mut self, ws: &mut WebSocketHandler<T>)
+    where T: KiteTickerHandler {
+        self.inner.lock().unwrap().on_error(ws);
+    }
+}
+
+/// Restores a shard's subscriptions whenever its connection opens before
+/// handing the callbacks over to the pool's handler
+struct ShardHandler<H> {
+    shared: Arc<Shared>,
+    index: usize,
+    inner: H,
+}
+
+impl<H> KiteTickerHandler for ShardHandler<H> where H: KiteTickerHandler {
+    fn on_open<T>(&mut self, ws: &mut WebSocketHandler<T>)
+    where T: KiteTickerHandler {
+        let handle = ws.handle();
+        {
+            let mut state = self.shared.lock();
+            if state.stopped {
+                // The pool was closed during the handshake
+                if let Err(err) = handle.close() {
+                    debug!("Unable to close shard {}: {:?}", self.index, err);
+                }
+                return;
+            }
+            let shard = &mut state.shards[self.index];
+            for (mode, tokens) in shard.desired.by_mode() {
+                let restored = match mode {
+                    Some(mode) => handle.subscribe_with_mode(mode, tokens),
+                    None => handle.subscribe(tokens),
+                };
+                if let Err(err) = restored {
+                    debug!("Unable to restore subscriptions of shard {}: {:?}", self.index, err);
+                }
+            }
+            shard.handle = Some(handle);
+            shard.opened = true;
+        }
+        self.inner.on_open(ws);
+    }
+
+    fn on_ticks<T>(&mut self, ws: &mut WebSocketHandler<T>, ticks: Vec<Tick>)
+    where T: KiteTickerHandler {
+        self.inner.on_ticks(ws, ticks);
+    }
+
+    fn on_order_update<T>(&mut self, ws: &mut WebSocketHandler<T>, order: JsonValue)
+    where T: KiteTickerHandler {
+        self.inner.on_order_update(ws, order);
+    }
+
+    fn on_message<T>(&mut self, ws: &mut WebSocketHandler<T>, message: JsonValue)
+    where T: KiteTickerHandler {
+        self.inner.on_message(ws, message);
+    }
+
+    fn on_close<T>(&mut self, ws: &mut WebSocketHandler<T>)
+    where T: KiteTickerHandler {
+        self.shared.lock().shards[self.index].handle = None;
+        self.inner.on_close(ws);
+    }
+
+    fn on_error<T>(&mut self, ws: &mut WebSocketHandler<T>)
+    where T: KiteTickerHandler {
+        self.inner.on_error(ws);
+    }
+}
+
+/// Shards subscriptions over several `KiteTicker` connections so more than
+/// `MAX_TOKENS_PER_CONNECTION` instruments can be streamed. Ticks of every
+/// connection are merged into a single handler or stream and each connection
+/// reconnects on its own, restoring its share of the subscriptions.
+///
+/// Subscriptions must go through the pool; subscribing via the
+/// `WebSocketHandler` passed to callbacks bypasses the sharding.
+pub struct TickerPool {
+    api_key: String,
+    access_token: AccessToken,
+    shared: Arc<Shared>,
+    metrics: Option<TickerMetrics>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl TickerPool {
+
+    /// Constructor. `connections` is clamped between 1 and `MAX_CONNECTIONS`.
+    pub fn new(api_key: &str, access_token: &str, connections: usize) -> TickerPool {
+        TickerPool::with_access_token(api_key, AccessToken::new(access_token), connections)
+    }
+
+    /// Pool whose connections use the current token of a `KiteConnect`
+    pub(crate) fn with_access_token(api_key: &str, access_token: AccessToken, connections: usize) -> TickerPool {
+        let shards = (0..connections.clamp(1, MAX_CONNECTIONS))
+            .map(|_| Shard {
+                desired: Subscriptions::with_limit(MAX_TOKENS_PER_CONNECTION),
+                handle: None,
+                opened: false,
+            })
+            .collect();
+
+        TickerPool {
+            api_key: api_key.to_string(),
+            access_token,
+            shared: Arc::new(Shared {
+                state: Mutex::new(PoolState { shards, stopped: false }),
+                stop: Condvar::new(),
+            }),
+            metrics: None,
+            threads: Vec::new(),
+        }
+    }
+
+    /// Connects every shard and dispatches the callbacks of all of them to
+    /// `handler`, one callback at a time.
+    pub fn connect<F>(&mut self, handler: F, uri: Option<&str>) -> Result<()>
+        where F: KiteTickerHandler + Send + 'static {
+        self.spawn(SharedHandler { inner: Arc::new(Mutex::new(handler)) }, uri)
+    }
+
+    /// Connects every shard and merges their events into a single stream
+    pub fn stream(
+        &mut self,
+        capacity: usize,
+        backpressure: Backpressure,
+        uri: Option<&str>
+    ) -> Result<TickerStream> {
+        let (sender, stream) = stream::channel(capacity, backpressure);
+        self.spawn(ChannelHandler { sender }, uri)?;
+        Ok(stream)
+    }
+
+    /// Collects the metrics of each connection in `metrics`, named
+    /// `shard-0`, `shard-1` and so on. Takes effect on the next `connect`.
+    pub fn set_metrics(&mut self, metrics: TickerMetrics) {
+        self.metrics = Some(metrics);
+    }
+
+    fn spawn<F>(&mut self, handler: F, uri: Option<&str>) -> Result<()>
+        where F: KiteTickerHandler + Clone + Send + 'static {
+        if !self.threads.is_empty() {
+            return Err(Error::new(ErrorKind::Internal, "Ticker pool is already connected"));
+        }
+
+        let shards = self.shared.lock().shards.len();
+        for index in 0..shards {
+            let shared = self.shared.clone();
+            let handler = handler.clone();
+            let api_key = self.api_key.clone();
+            let access_token = self.access_token.clone();
+            let uri = uri.map(|uri| uri.to_string());
+            let metrics = self.metrics.as_ref().map(|metrics| metrics.connection(&format!("shard-{}", index)));
+
+            self.threads.push(thread::spawn(move || {
+                supervise(shared, index, handler, &api_key, access_token, uri.as_deref(), metrics)
+            }));
+        }
+        Ok(())
+    }
+
+    /// Subscribe to a list of instrument_tokens, spreading new tokens over
+    /// the least loaded connections
+    pub fn subscribe(&self, instrument_tokens: Vec<u32>) -> Result<()> {
+        self.assign(None, instrument_tokens)
+    }
+
+    /// Subscribe to a list of instrument_tokens and set their mode
+    pub fn subscribe_with_mode(&self, mode: Mode, instrument_tokens: Vec<u32>) -> Result<()> {
+        self.assign(Some(mode), instrument_tokens)
+    }
+
+    /// Subscribe to `EXCHANGE:TRADINGSYMBOL` strings resolved through `index`
+    pub fn subscribe_symbols(&self, index: &InstrumentIndex, symbols: &[&str]) -> Result<()> {
+        self.subscribe(resolve(index, symbols)?)
+    }
+
+    fn assign(&self, mode: Option<Mode>, instrument_tokens: Vec<u32>) -> Result<()> {
+        let mut state = self.shared.lock();
+
+        let mut sizes: Vec<usize> = state.shards.iter().map(|shard| shard.desired.len()).collect();
+        let mut batches: Vec<Vec<u32>> = vec![Vec::new(); sizes.len()];
+        for token in instrument_tokens {
+            // Tokens already subscribed stay on their shard
+            let existing = state.shards.iter().position(|shard| shard.desired.contains(token))
+                .or_else(|| batches.iter().position(|batch| batch.contains(&token)));
+            let index = match existing {
+                Some(index) => index,
+                None => {
+                    let (index, size) = sizes.iter().enumerate()
+                        .min_by_key(|&(_, size)| *size)
+                        .map(|(index, size)| (index, *size))
+                        .unwrap();
+                    if size >= MAX_TOKENS_PER_CONNECTION {
+                        return Err(Error::new(
+                            ErrorKind::Capacity,
+                            format!("All {} ticker connections are full", sizes.len())
+                        ));
+                    }
+                    sizes[index] += 1;
+                    index
+                }
+            };
+            batches[index].push(token);
+        }
+
+        let mut errors = Vec::new();
+        for (index, (shard, tokens)) in state.shards.iter_mut().zip(batches).enumerate() {
+            if !tokens.is_empty() {
+                if let Err(err) = shard_subscribe(shard, mode, tokens) {
+                    errors.push((index, err));
+                }
+            }
+        }
+        collect_errors(errors)
+    }
+
+    /// Unsubscribe the given list of instrument_tokens and rebalance the
+    /// connections if they drifted too far apart
+    pub fn unsubscribe(&self, instrument_tokens: Vec<u32>) -> Result<()> {
+        let mut errors = Vec::new();
+        {
+            let mut state = self.shared.lock();
+            for (index, shard) in state.shards.iter_mut().enumerate() {
+                let tokens: Vec<u32> = instrument_tokens.iter()
+                    .filter(|token| shard.desired.contains(**token))
+                    .cloned()
+                    .collect();
+                if tokens.is_empty() {
+                    continue;
+                }
+                shard.desired.unsubscribe(&tokens);
+                if let Some(ref handle) = shard.handle {
+                    if let Err(err) = handle.unsubscribe(tokens) {
+                        errors.push((index, err));
+                    }
+                }
+            }
+        }
+        let rebalanced = self.rebalance_above(MAX_TOKENS_PER_CONNECTION / 10);
+        collect_errors(errors).and(rebalanced)
+    }
+
+    /// Unsubscribe the given `EXCHANGE:TRADINGSYMBOL` strings
+    pub fn unsubscribe_symbols(&self, index: &InstrumentIndex, symbols: &[&str]) -> Result<()> {
+        self.unsubscribe(resolve(index, symbols)?)
+    }
+
+    /// Set streaming mode for the given `EXCHANGE:TRADINGSYMBOL` strings
+    pub fn set_mode_symbols(&self, mode: Mode, index: &InstrumentIndex, symbols: &[&str]) -> Result<()> {
+        self.set_mode(mode, resolve(index, symbols)?)
+    }
+
+    /// Set streaming mode for the given list of subscribed tokens
+    pub fn set_mode(&self, mode: Mode, instrument_tokens: Vec<u32>) -> Result<()> {
+        let mut state = self.shared.lock();
+        if let Some(token) = instrument_tokens.iter()
+            .find(|token| !state.shards.iter().any(|shard| shard.desired.contains(**token))) {
+            return Err(Error::new(ErrorKind::Protocol, format!("Instrument {} is not subscribed", token)));
+        }
+
+        let mut errors = Vec::new();
+        for (index, shard) in state.shards.iter_mut().enumerate() {
+            let tokens: Vec<u32> = instrument_tokens.iter()
+                .filter(|token| shard.desired.contains(**token))
+                .cloned()
+                .collect();
+            if tokens.is_empty() {
+                continue;
+            }
+            let updated = shard.desired.set_mode(mode, &tokens).and_then(|_| match shard.handle {
+                Some(ref handle) => handle.set_mode(mode, tokens),
+                None => Ok(()),
+            });
+            if let Err(err) = updated {
+                errors.push((index, err));
+            }
+        }
+        collect_errors(errors)
+    }
+
+    /// Evens out the number of instruments across connections
+    pub fn rebalance(&self) -> Result<()> {
+        self.rebalance_above(1)
+    }
+
+    /// Moves tokens from the busiest to the idlest connection until their
+    /// sizes differ by at most `threshold`
+    fn rebalance_above(&self, threshold: usize) -> Result<()> {
+        let mut state = self.shared.lock();
+        loop {
+            let sizes: Vec<usize> = state.shards.iter().map(|shard| shard.desired.len()).collect();
+            let (busiest, max) = sizes.iter().enumerate().max_by_key(|&(_, size)| *size).unwrap();
+            let (idlest, min) = sizes.iter().enumerate().min_by_key(|&(_, size)| *size).unwrap();
+            if max - min <= threshold {
+                return Ok(());
+            }
+
+            // Move the lowest tokens so rebalancing is reproducible
+            let mut moved: Vec<(u32, Option<Mode>)> = state.shards[busiest].desired.tokens().iter()
+                .map(|(token, mode)| (*token, *mode))
+                .collect();
+            moved.sort_unstable_by_key(|(token, _)| *token);
+            moved.truncate((max - min) / 2);
+            debug!("Moving {} instruments from shard {} to {}", moved.len(), busiest, idlest);
+
+            let mut by_mode: Vec<(Option<Mode>, Vec<u32>)> = Vec::new();
+            for (token, mode) in moved {
+                match by_mode.iter_mut().find(|(batch_mode, _)| *batch_mode == mode) {
+                    Some((_, tokens)) => tokens.push(token),
+                    None => by_mode.push((mode, vec![token])),
+                }
+            }
+            for (mode, tokens) in by_mode {
+                move_tokens(&mut state.shards, busiest, idlest, mode, tokens)?;
+            }
+        }
+    }
+
+    /// All subscribed instruments across connections along with their mode
+    pub fn subscriptions(&self) -> HashMap<u32, Option<Mode>> {
+        let state = self.shared.lock();
+        let mut subscriptions = HashMap::new();
+        for shard in state.shards.iter() {
+            subscriptions.extend(shard.desired.tokens().iter().map(|(token, mode)| (*token, *mode)));
+        }
+        subscriptions
+    }
+
+    /// Number of instruments assigned to each connection
+    pub fn shard_sizes(&self) -> Vec<usize> {
+        self.shared.lock().shards.iter().map(|shard| shard.desired.len()).collect()
+    }
+
+    /// Number of connections currently open
+    pub fn connected(&self) -> usize {
+        self.shared.lock().shards.iter().filter(|shard| shard.handle.is_some()).count()
+    }
+
+    /// Closes every connection and stops reconnecting. Every connection is
+    /// closed even if some fail, the first error is returned.
+    pub fn close(&self) -> Result<()> {
+        let mut state = self.shared.lock();
+        state.stopped = true;
+        self.shared.stop.notify_all();
+        let mut result = Ok(());
+        for shard in state.shards.iter() {
+            if let Some(ref handle) = shard.handle {
+                if let Err(err) = handle.close() {
+                    debug!("Unable to close a ticker shard {:?}", err);
+                    result = result.and(Err(err));
+                }
+            }
+        }
+        result
+    }
+
+    /// Blocks until every connection has been closed with `close`
+    pub fn join(&mut self) {
+        for thread in self.threads.drain(..) {
+            if thread.join().is_err() {
+                debug!("Ticker pool thread panicked");
+            }
+        }
+    }
+}
+
+impl Drop for TickerPool {
+    fn drop(&mut self) {
+        if let Err(err) = self.close() {
+            debug!("Unable to close ticker pool {:?}", err);
+        }
+        self.join();
+    }
+}
+
+/// Moves `tokens` between shards, subscribing them on the new connection
+/// before dropping them from the old one to avoid a gap in ticks. If the
+/// subscribe fails they are left on `from` only, so no reconnect ever
+/// subscribes them twice.
+fn move_tokens(shards: &mut [Shard], from: usize, to: usize, mode: Option<Mode>, tokens: Vec<u32>) -> Result<()> {
+    let target = &mut shards[to];
+    if let Err(err) = shard_subscribe(target, mode, tokens.clone()) {
+        target.desired.unsubscribe(&tokens);
+        if let Some(ref handle) = target.handle {
+            if let Err(err) = handle.unsubscribe(tokens) {
+                debug!("Unable to undo the move to shard {}: {:?}", to, err);
+            }
+        }
+        return Err(err);
+    }
+
+    let source = &mut shards[from];
+    source.desired.unsubscribe(&tokens);
+    match source.handle {
+        Some(ref handle) => handle.unsubscribe(tokens),
+        None => Ok(()),
+    }
+}
+
+/// Combines the errors of several shards into one naming each shard, so a
+/// batch applied to every shard reports all of its failures
+fn collect_errors(errors: Vec<(usize, Error)>) -> Result<()> {
+    let details: Vec<String> = errors.iter()
+        .map(|(index, err)| format!("shard {}: {}", index, err))
+        .collect();
+    match errors.into_iter().next() {
+        Some((_, first)) => Err(Error::new(first.kind, details.join("; "))),
+        None => Ok(()),
+    }
+}
+
+/// Records `tokens` on the shard and subscribes them on its live connection
+fn shard_subscribe(shard: &mut Shard, mode: Option<Mode>, tokens: Vec<u32>) -> Result<()> {
+    shard.desired.subscribe(&tokens)?;
+    if let Some(mode) = mode {
+        shard.desired.set_mode(mode, &tokens)?;
+    }
+
+    match (shard.handle.as_ref(), mode) {
+        (Some(handle), Some(mode)) => handle.subscribe_with_mode(mode, tokens),
+        (Some(handle), None) => handle.subscribe(tokens),
+        (None, _) => Ok(()),
+    }
+}
+
+/// Keeps one shard connected until the pool is closed, backing off
+/// exponentially between failed attempts
+fn supervise<F>(
+    shared: Arc<Shared>,
+    index: usize,
+    handler: F,
+    api_key: &str,
+    access_token: AccessToken,
+    uri: Option<&str>,
+    metrics: Option<ConnectionMetrics>
+) where F: KiteTickerHandler + Clone + Send + 'static {
+    let mut delay = RECONNECT_MIN_DELAY;
+    loop {
+        // Reconnects pick up a renewed access token
+        let mut ticker = KiteTicker::with_access_token(api_key, access_token.clone());
+        if let Some(ref metrics) = metrics {
+            ticker.set_metrics(metrics.clone());
+        }
+        let shard_handler = ShardHandler {
+            shared: shared.clone(),
+            index,
+            inner: handler.clone(),
+        };
+        let result = ticker.connect(shard_handler, uri).and_then(|_| ticker.join());
+
+        let mut state = shared.lock();
+        let shard = &mut state.shards[index];
+        shard.handle = None;
+        if shard.opened {
+            delay = RECONNECT_MIN_DELAY;
+        }
+        shard.opened = false;
+        if state.stopped {
+            return;
+        }
+        debug!("Ticker shard {} disconnected {:?}, reconnecting in {:?}", index, result, delay);
+
+        state = shared.stop.wait_timeout(state, delay).unwrap_or_else(|err| err.into_inner()).0;
+        if state.stopped {
+            return;
+        }
+        delay = (delay * 2).min(RECONNECT_MAX_DELAY);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ticker::TickerEvent;
+
+    #[test]
+    fn test_subscriptions_are_sharded() {
+        let pool = TickerPool::new("<API-KEY>", "<ACCESS-TOKEN>", 3);
+        pool.subscribe((0..7000).collect()).unwrap();
+        assert_eq!(pool.shard_sizes(), vec![2334, 2333, 2333]);
+
+        // Resubscribing doesn't move or duplicate tokens
+        pool.subscribe_with_mode(Mode::Full, (0..10).collect()).unwrap();
+        assert_eq!(pool.shard_sizes().iter().sum::<usize>(), 7000);
+        assert_eq!(pool.subscriptions()[&5], Some(Mode::Full));
+
+        assert!(pool.subscribe((7000..9001).collect()).is_err());
+        assert!(pool.set_mode(Mode::Ltp, vec![9000]).is_err());
+    }
+
+    #[test]
+    fn test_unsubscribe_rebalances() {
+        let pool = TickerPool::new("<API-KEY>", "<ACCESS-TOKEN>", 2);
+        pool.subscribe((0..2000).collect()).unwrap();
+        pool.set_mode(Mode::Full, (0..2000).collect()).unwrap();
+
+        let first_shard: Vec<u32> = {
+            let state = pool.shared.lock();
+            state.shards[0].desired.tokens().keys().cloned().collect()
+        };
+        pool.unsubscribe(first_shard[..800].to_vec()).unwrap();
+
+        let sizes = pool.shard_sizes();
+        assert_eq!(sizes.iter().sum::<usize>(), 1200);
+        assert!(sizes[0].max(sizes[1]) - sizes[0].min(sizes[1]) <= MAX_TOKENS_PER_CONNECTION / 10);
+        assert!(pool.subscriptions().values().all(|mode| *mode == Some(Mode::Full)));
+    }
+
+    /// Handle whose every send fails, as if its connection just dropped
+    fn dead_handle() -> TickerHandle {
+        let socket = ws::WebSocket::new(|_: ws::Sender| |_: ws::Message| Ok(())).unwrap();
+        let handle = TickerHandle::new(Some(socket.broadcaster()), Default::default());
+        drop(socket);
+        handle
+    }
+
+    #[test]
+    fn test_batches_reach_every_shard() {
+        let pool = TickerPool::new("<API-KEY>", "<ACCESS-TOKEN>", 3);
+        pool.subscribe((0..3).collect()).unwrap();
+        pool.shared.lock().shards[0].handle = Some(dead_handle());
+
+        // The failing first shard doesn't stop the others from being updated
+        let err = pool.subscribe_with_mode(Mode::Full, (0..6).collect()).unwrap_err();
+        assert!(err.to_string().contains("shard 0:") && !err.to_string().contains("shard 1:"), "{}", err);
+        assert_eq!(pool.subscriptions().len(), 6);
+        assert!(pool.subscriptions().values().all(|mode| *mode == Some(Mode::Full)));
+
+        pool.shared.lock().shards[1].handle = Some(dead_handle());
+        let err = pool.set_mode(Mode::Ltp, (0..6).collect()).unwrap_err();
+        assert!(err.to_string().contains("shard 0:") && err.to_string().contains("; shard 1:"), "{}", err);
+        assert!(pool.subscriptions().values().all(|mode| *mode == Some(Mode::Ltp)));
+
+        assert!(pool.unsubscribe((0..6).collect()).is_err());
+        assert!(pool.subscriptions().is_empty());
+    }
+
+    #[test]
+    fn test_failed_rebalance_keeps_tokens_on_one_shard() {
+        let pool = TickerPool::new("<API-KEY>", "<ACCESS-TOKEN>", 2);
+        pool.subscribe((0..10).collect()).unwrap();
+        {
+            let mut state = pool.shared.lock();
+            let tokens: Vec<u32> = state.shards[1].desired.tokens().keys().cloned().collect();
+            state.shards[1].desired.unsubscribe(&tokens);
+            state.shards[0].desired.subscribe(&tokens).unwrap();
+            state.shards[1].handle = Some(dead_handle());
+        }
+
+        assert!(pool.rebalance().is_err());
+        assert_eq!(pool.shard_sizes(), vec![10, 0]);
+
+        // Without a live connection the lowest tokens move
+        pool.shared.lock().shards[1].handle = None;
+        pool.rebalance().unwrap();
+        let state = pool.shared.lock();
+        let mut moved: Vec<u32> = state.shards[1].desired.tokens().keys().cloned().collect();
+        moved.sort_unstable();
+        assert_eq!(moved, vec![0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_pool_connects_every_shard() {
+        let server = ws::WebSocket::new(|_out: ws::Sender| {
+            |_msg: ws::Message| Ok(())
+        }).unwrap().bind("127.0.0.1:0").unwrap();
+        let address = server.local_addr().unwrap();
+        thread::spawn(move || server.run());
+
+        let mut pool = TickerPool::new("<API-KEY>", "<ACCESS-TOKEN>", 2);
+        pool.subscribe(vec![408065, 884737]).unwrap();
+        let uri = format!("ws://{}", address);
+        let stream = pool.stream(100, Backpressure::Block, Some(uri.as_str())).unwrap();
+
+        assert_eq!(stream.recv(), Some(TickerEvent::Connected));
+        assert_eq!(stream.recv(), Some(TickerEvent::Connected));
+        assert_eq!(pool.connected(), 2);
+
+        pool.close().unwrap();
+        pool.join();
+        assert_eq!(pool.connected(), 0);
+    }
+
+    #[test]
+    fn test_close_during_handshake() {
+        use std::io;
+        use std::net::{Shutdown, TcpListener, TcpStream};
+        use std::sync::mpsc;
+
+        let server = ws::WebSocket::new(|_out: ws::Sender| {
+            |_msg: ws::Message| Ok(())
+        }).unwrap().bind("127.0.0.1:0").unwrap();
+        let address = server.local_addr().unwrap();
+        thread::spawn(move || server.run());
+
+        // Holds the connection back until the pool is closed, then relays it
+        let relay = TcpListener::bind("127.0.0.1:0").unwrap();
+        let relay_address = relay.local_addr().unwrap();
+        let (accepted, on_accept) = mpsc::channel();
+        let (release, on_release) = mpsc::channel::<()>();
+        thread::spawn(move || {
+            let (client, _) = relay.accept().unwrap();
+            accepted.send(()).unwrap();
+            on_release.recv().unwrap();
+            let upstream = TcpStream::connect(address).unwrap();
+            let pipe = |mut from: TcpStream, mut to: TcpStream| thread::spawn(move || {
+                let _ = io::copy(&mut from, &mut to);
+                let _ = to.shutdown(Shutdown::Both);
+            });
+            pipe(client.try_clone().unwrap(), upstream.try_clone().unwrap());
+            pipe(upstream, client);
+        });
+
+        struct MyHandler;
+        impl KiteTickerHandler for MyHandler {}
+        let mut pool = TickerPool::new("<API-KEY>", "<ACCESS-TOKEN>", 1);
+        let uri = format!("ws://{}", relay_address);
+        pool.connect(MyHandler, Some(uri.as_str())).unwrap();
+
+        on_accept.recv_timeout(Duration::from_secs(5)).unwrap();
+        pool.close().unwrap();
+        release.send(()).unwrap();
+
+        let (dropped, on_dropped) = mpsc::channel();
+        thread::spawn(move || {
+            drop(pool);
+            dropped.send(()).unwrap();
+        });
+        on_dropped.recv_timeout(Duration::from_secs(10)).unwrap();
+    }
+}
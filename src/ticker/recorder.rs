@@ -0,0 +1,515 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use byteorder::{BigEndian, ReadBytesExt};
+use chrono::{DateTime, FixedOffset, NaiveDate, TimeZone, Utc};
+use log::debug;
+use ws::{Handler, Message, Result};
+
+use super::tick::ist;
+use super::{KiteTickerHandler, Subscriptions, TickerHandle, WebSocketHandler};
+
+/// Written at the start of every recording, followed by one record per
+/// frame: receive time in microseconds since the epoch (i64), frame kind
+/// (u8), frame length (u32) and the raw frame, all big endian.
+const MAGIC: &[u8; 4] = b"KTR1";
+const BINARY: u8 = 0;
+const TEXT: u8 = 1;
+const HEADER_LENGTH: usize = 13;
+/// Larger frames are taken as a corrupt record rather than allocated
+const MAX_FRAME_LENGTH: usize = 16 << 20;
+
+/// Current time in IST, the time zone recordings are rotated in
+pub(crate) fn now() -> DateTime<FixedOffset> {
+    Utc::now().with_timezone(&ist())
+}
+
+/// A frame read back from a recording
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordedFrame {
+    /// When the frame was received, in IST
+    pub received: DateTime<FixedOffset>,
+    /// Raw websocket message as sent by the server
+    pub message: Message,
+}
+
+/// Appends raw ticker frames with their receive time to one file per day,
+/// named `ticks-YYYY-MM-DD.ktr` after the IST date. Attach it to a ticker
+/// with `KiteTicker::set_recorder`.
+#[derive(Debug)]
+pub struct TickRecorder {
+    directory: PathBuf,
+    current: Option<(NaiveDate, File)>,
+}
+
+impl TickRecorder {
+    /// Records into `directory`, creating it if needed. Existing recordings
+    /// of the same day are appended to, after dropping any partial record
+    /// left at their end by a crash.
+    pub fn new<P: AsRef<Path>>(directory: P) -> io::Result<TickRecorder> {
+        fs::create_dir_all(directory.as_ref())?;
+        Ok(TickRecorder {
+            directory: directory.as_ref().to_path_buf(),
+            current: None,
+        })
+    }
+
+    /// File holding the frames received on `date`
+    pub fn path(&self, date: NaiveDate) -> PathBuf {
+        self.directory.join(format!("ticks-{}.ktr", date.format("%Y-%m-%d")))
+    }
+
+    /// Recordings found in `directory`, oldest first
+    pub fn recordings<P: AsRef<Path>>(directory: P) -> io::Result<Vec<PathBuf>> {
+        let mut paths: Vec<PathBuf> = Vec::new();
+        for entry in fs::read_dir(directory)? {
+            let path = entry?.path();
+            let name = path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+            if name.starts_with("ticks-") && name.ends_with(".ktr") {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+        Ok(paths)
+    }
+
+    fn file(&mut self, date: NaiveDate) -> io::Result<&mut File> {
+        let rotate = match self.current {
+            Some((current, _)) => current != date,
+            None => true,
+        };
+        if rotate {
+            let path = self.path(date);
+            let mut file = OpenOptions::new().create(true).read(true).append(true).open(&path)?;
+            let length = file.metadata()?.len();
+            let complete = complete_length(&mut file, length)?;
+            if complete < length {
+                debug!("Dropping {} bytes of a partial record from {}", length - complete, path.display());
+                file.set_len(complete)?;
+            }
+            if complete == 0 {
+                file.write_all(MAGIC)?;
+            }
+            self.current = Some((date, file));
+        }
+        match self.current {
+            Some((_, ref mut file)) => Ok(file),
+            None => unreachable!(),
+        }
+    }
+
+    /// Appends `message` as received at `received`, rotating to a new file
+    /// when the IST date changes
+    pub fn record(&mut self, received: DateTime<FixedOffset>, message: &Message) -> io::Result<()> {
+        let (kind, data): (u8, &[u8]) = match *message {
+            Message::Binary(ref data) => (BINARY, data),
+            Message::Text(ref text) => (TEXT, text.as_bytes()),
+        };
+        if data.len() > MAX_FRAME_LENGTH {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Frame of {} bytes is too large to record", data.len())
+            ));
+        }
+
+        let mut record = Vec::with_capacity(HEADER_LENGTH + data.len());
+        record.extend_from_slice(&received.timestamp_micros().to_be_bytes());
+        record.push(kind);
+        record.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        record.extend_from_slice(data);
+
+        let date = received.with_timezone(&ist()).date_naive();
+        self.file(date)?.write_all(&record)
+    }
+}
+
+/// Length of a recording of `length` bytes up to the end of its last
+/// complete record, so appending after a crash mid-write doesn't leave a
+/// broken record in the middle of the file. Zero if not even the header
+/// was written.
+fn complete_length(file: &mut File, length: u64) -> io::Result<u64> {
+    if length < MAGIC.len() as u64 {
+        return Ok(0);
+    }
+    let mut magic = [0; 4];
+    file.seek(SeekFrom::Start(0))?;
+    file.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Not a tick recording"));
+    }
+
+    let mut complete = MAGIC.len() as u64;
+    let mut header = [0; HEADER_LENGTH];
+    while complete + HEADER_LENGTH as u64 <= length {
+        file.seek(SeekFrom::Start(complete))?;
+        file.read_exact(&mut header)?;
+        let frame_length = (&header[9..]).read_u32::<BigEndian>()? as u64;
+        let end = complete + HEADER_LENGTH as u64 + frame_length;
+        if frame_length > MAX_FRAME_LENGTH as u64 || end > length {
+            break;
+        }
+        complete = end;
+    }
+    Ok(complete)
+}
+
+/// Reads the frames of a single recording in order
+pub struct TickReader<R> {
+    reader: R,
+}
+
+impl TickReader<BufReader<File>> {
+    /// Opens a recording written by `TickRecorder`
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<TickReader<BufReader<File>>> {
+        TickReader::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> TickReader<R> {
+    /// Reads a recording from `reader`, checking its header
+    pub fn new(mut reader: R) -> io::Result<TickReader<R>> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Not a tick recording"));
+        }
+        Ok(TickReader { reader })
+    }
+
+    /// Fills `buf` as far as the recording goes, returning the bytes read
+    fn read_fully(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut filled = 0;
+        while filled < buf.len() {
+            match self.reader.read(&mut buf[filled..]) {
+                Ok(0) => break,
+                Ok(read) => filled += read,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {},
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(filled)
+    }
+
+    /// Reads the next record. A record cut short at the end of the file, as
+    /// left by a process killed while writing, ends the recording.
+    fn read_frame(&mut self) -> io::Result<Option<RecordedFrame>> {
+        let mut header = [0; HEADER_LENGTH];
+        match self.read_fully(&mut header)? {
+            0 => return Ok(None),
+            HEADER_LENGTH => {},
+            read => {
+                debug!("Ignoring a truncated record of {} bytes at the end of the recording", read);
+                return Ok(None);
+            }
+        }
+        let mut header = &header[..];
+        let micros = header.read_i64::<BigEndian>()?;
+        let kind = header.read_u8()?;
+        let length = header.read_u32::<BigEndian>()? as usize;
+        if length > MAX_FRAME_LENGTH {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Frame length {} is corrupt", length)
+            ));
+        }
+
+        let mut data = vec![0; length];
+        let read = self.read_fully(&mut data)?;
+        if read < length {
+            debug!("Ignoring a truncated frame of {} out of {} bytes at the end of the recording", read, length);
+            return Ok(None);
+        }
+        let message = match kind {
+            BINARY => Message::Binary(data),
+            TEXT => Message::Text(String::from_utf8(data)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?),
+            _ => return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unknown frame kind {}", kind)
+            )),
+        };
+
+        let received = ist().timestamp_micros(micros).single()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Invalid receive time"))?;
+        Ok(Some(RecordedFrame { received, message }))
+    }
+}
+
+impl<R: Read> Iterator for TickReader<R> {
+    type Item = io::Result<RecordedFrame>;
+
+    fn next(&mut self) -> Option<io::Result<RecordedFrame>> {
+        self.read_frame().transpose()
+    }
+}
+
+/// Pace at which recorded frames are replayed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReplaySpeed {
+    /// Frames are spaced as they were received
+    Original,
+    /// Gaps between frames are divided by the given factor
+    Accelerated(f64),
+    /// Frames are replayed back to back
+    AsFastAsPossible,
+}
+
+impl ReplaySpeed {
+    /// Time from the start of the replay at which a frame received `elapsed`
+    /// after the first one is due
+    fn due(&self, elapsed: Duration) -> Option<Duration> {
+        match *self {
+            ReplaySpeed::Original => Some(elapsed),
+            ReplaySpeed::Accelerated(factor) if factor > 0.0 => Some(elapsed.div_f64(factor)),
+            ReplaySpeed::Accelerated(_) | ReplaySpeed::AsFastAsPossible => None,
+        }
+    }
+}
+
+/// Feeds recordings back through the `KiteTickerHandler` callbacks, decoding
+/// frames exactly as a live connection does. Replays run on the calling
+/// thread, so the same recording always produces the same callbacks.
+#[derive(Debug, Clone)]
+pub struct TickReplayer {
+    paths: Vec<PathBuf>,
+}
+
+impl TickReplayer {
+    /// Replays a single recording
+    pub fn new<P: AsRef<Path>>(path: P) -> TickReplayer {
+        TickReplayer {
+            paths: vec![path.as_ref().to_path_buf()],
+        }
+    }
+
+    /// Replays every recording in `directory`, day after day
+    pub fn from_directory<P: AsRef<Path>>(directory: P) -> io::Result<TickReplayer> {
+        Ok(TickReplayer {
+            paths: TickRecorder::recordings(directory)?,
+        })
+    }
+
+    /// Calls `on_open`, then `on_ticks`, `on_order_update` or `on_message`
+    /// for every recorded frame and finally `on_close`. Handlers see a
+    /// `WebSocketHandler` without a connection, so subscription calls are
+    /// recorded but nothing is sent. Returns the handler once done.
+    pub fn replay<H>(&self, handler: H, speed: ReplaySpeed) -> Result<H>
+    where H: KiteTickerHandler {
+        let handler = Arc::new(Mutex::new(Box::new(handler)));
+        let mut ws = WebSocketHandler {
+            ticker: TickerHandle::new(None, Arc::new(Mutex::new(Subscriptions::default()))),
+            handler: handler.clone(),
+            recorder: None,
+            snapshot: None,
+            metrics: None,
+            endpoint: None,
+            last_error: None,
+        };
+        handler.lock().unwrap().on_open(&mut ws);
+
+        let started = Instant::now();
+        let mut first: Option<DateTime<FixedOffset>> = None;
+        for path in self.paths.iter() {
+            for frame in TickReader::open(path)? {
+                let frame = frame?;
+                let first = *first.get_or_insert(frame.received);
+                let elapsed = (frame.received - first).to_std().unwrap_or_default();
+                if let Some(due) = speed.due(elapsed) {
+                    let now = started.elapsed();
+                    if due > now {
+                        thread::sleep(due - now);
+                    }
+                }
+                // Live connections only log frames they can't decode
+                if let Err(err) = ws.on_message(frame.message) {
+                    debug!("Skipping a recorded frame: {:?}", err);
+                }
+            }
+        }
+
+        handler.lock().unwrap().on_close(&mut ws);
+        drop(ws);
+        match Arc::try_unwrap(handler) {
+            Ok(handler) => Ok(*handler.into_inner().unwrap_or_else(|err| err.into_inner())),
+            Err(_) => unreachable!("replay handler is not shared"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_directory;
+    use serde_json::Value as JsonValue;
+    use crate::ticker::{encode_binary, Tick};
+
+    fn at(hour: u32, day: u32) -> DateTime<FixedOffset> {
+        ist().with_ymd_and_hms(2020, 1, day, hour, 15, 0).unwrap()
+    }
+
+    fn frame(last_price: f64) -> Message {
+        Message::Binary(encode_binary(&[Tick {
+            instrument_token: 408065,
+            last_price,
+            ..Tick::default()
+        }]))
+    }
+
+    #[derive(Default)]
+    struct Collector {
+        events: Vec<String>,
+    }
+
+    impl KiteTickerHandler for Collector {
+        fn on_open<T>(&mut self, _ws: &mut WebSocketHandler<T>)
+        where T: KiteTickerHandler {
+            self.events.push("open".to_string());
+        }
+
+        fn on_ticks<T>(&mut self, _ws: &mut WebSocketHandler<T>, ticks: Vec<Tick>)
+        where T: KiteTickerHandler {
+            self.events.push(format!("tick {}", ticks[0].last_price));
+        }
+
+        fn on_order_update<T>(&mut self, _ws: &mut WebSocketHandler<T>, order: JsonValue)
+        where T: KiteTickerHandler {
+            self.events.push(format!("order {}", order["order_id"]));
+        }
+
+        fn on_close<T>(&mut self, _ws: &mut WebSocketHandler<T>)
+        where T: KiteTickerHandler {
+            self.events.push("close".to_string());
+        }
+    }
+
+    #[test]
+    fn test_record_rotates_per_day() {
+        let path = test_directory("rotate");
+        let mut recorder = TickRecorder::new(&path).unwrap();
+        recorder.record(at(9, 1), &frame(100.0)).unwrap();
+        recorder.record(at(15, 1), &Message::Text(r#"{"type":"order","data":{"order_id":"1"}}"#.into())).unwrap();
+        recorder.record(at(9, 2), &frame(101.0)).unwrap();
+        drop(recorder);
+
+        // Reopening appends to the day's file
+        let mut recorder = TickRecorder::new(&path).unwrap();
+        recorder.record(at(10, 2), &frame(102.0)).unwrap();
+
+        let recordings = TickRecorder::recordings(&path).unwrap();
+        assert_eq!(recordings, vec![path.join("ticks-2020-01-01.ktr"), path.join("ticks-2020-01-02.ktr")]);
+
+        let frames: Vec<RecordedFrame> = TickReader::open(&recordings[1]).unwrap()
+            .collect::<io::Result<_>>().unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0], RecordedFrame { received: at(9, 2), message: frame(101.0) });
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_replay_through_handler() {
+        let path = test_directory("replay");
+        let mut recorder = TickRecorder::new(&path).unwrap();
+        recorder.record(at(9, 1), &frame(100.0)).unwrap();
+        recorder.record(at(9, 1), &Message::Text(r#"{"type":"order","data":{"order_id":"1"}}"#.into())).unwrap();
+        recorder.record(at(9, 2), &frame(101.0)).unwrap();
+
+        let replayer = TickReplayer::from_directory(&path).unwrap();
+        let collector = replayer.replay(Collector::default(), ReplaySpeed::AsFastAsPossible).unwrap();
+        assert_eq!(collector.events, vec!["open", "tick 100", "order \"1\"", "tick 101", "close"]);
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_replay_speed() {
+        let minute = Duration::from_secs(60);
+        assert_eq!(ReplaySpeed::Original.due(minute), Some(minute));
+        assert_eq!(ReplaySpeed::Accelerated(60.0).due(minute), Some(Duration::from_secs(1)));
+        assert_eq!(ReplaySpeed::AsFastAsPossible.due(minute), None);
+        assert!(TickReader::new(&b"nope"[..]).is_err());
+    }
+
+    #[test]
+    fn test_truncated_recording() {
+        let path = test_directory("truncated");
+        let mut recorder = TickRecorder::new(&path).unwrap();
+        recorder.record(at(9, 1), &frame(100.0)).unwrap();
+        recorder.record(at(9, 1), &frame(101.0)).unwrap();
+        drop(recorder);
+
+        // Cut the last record short as a crash while writing would
+        let recording = path.join("ticks-2020-01-01.ktr");
+        let length = fs::metadata(&recording).unwrap().len();
+        OpenOptions::new().write(true).open(&recording).unwrap().set_len(length - 3).unwrap();
+        let collector = TickReplayer::new(&recording)
+            .replay(Collector::default(), ReplaySpeed::AsFastAsPossible).unwrap();
+        assert_eq!(collector.events, vec!["open", "tick 100", "close"]);
+
+        // A partial header ends the recording as well
+        OpenOptions::new().write(true).open(&recording).unwrap().set_len(length / 2 + 6).unwrap();
+        assert_eq!(TickReader::open(&recording).unwrap().count(), 1);
+
+        // Corrupt lengths are rejected before allocating
+        let mut corrupt = MAGIC.to_vec();
+        corrupt.extend_from_slice(&[0; 9]);
+        corrupt.extend_from_slice(&u32::MAX.to_be_bytes());
+        let frames: Vec<io::Result<RecordedFrame>> = TickReader::new(&corrupt[..]).unwrap().collect();
+        assert_eq!(frames[0].as_ref().unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_record_after_truncated_record() {
+        let path = test_directory("resume");
+        let mut recorder = TickRecorder::new(&path).unwrap();
+        recorder.record(at(9, 1), &frame(100.0)).unwrap();
+        recorder.record(at(9, 1), &frame(101.0)).unwrap();
+        drop(recorder);
+
+        let recording = path.join("ticks-2020-01-01.ktr");
+        let length = fs::metadata(&recording).unwrap().len();
+        OpenOptions::new().write(true).open(&recording).unwrap().set_len(length - 3).unwrap();
+
+        // The partial record is dropped before new ones are appended
+        let mut recorder = TickRecorder::new(&path).unwrap();
+        recorder.record(at(10, 1), &frame(102.0)).unwrap();
+        recorder.record(at(11, 1), &frame(103.0)).unwrap();
+        drop(recorder);
+
+        let frames: Vec<RecordedFrame> = TickReader::open(&recording).unwrap()
+            .collect::<io::Result<_>>().unwrap();
+        let expected = vec![
+            RecordedFrame { received: at(9, 1), message: frame(100.0) },
+            RecordedFrame { received: at(10, 1), message: frame(102.0) },
+            RecordedFrame { received: at(11, 1), message: frame(103.0) },
+        ];
+        assert_eq!(frames, expected);
+
+        // So is a header cut short before the magic was complete
+        fs::write(&recording, &MAGIC[..2]).unwrap();
+        TickRecorder::new(&path).unwrap().record(at(9, 1), &frame(100.0)).unwrap();
+        assert_eq!(TickReader::open(&recording).unwrap().count(), 1);
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn test_replay_skips_undecodable_frames() {
+        let path = test_directory("undecodable");
+        let mut recorder = TickRecorder::new(&path).unwrap();
+        recorder.record(at(9, 1), &frame(100.0)).unwrap();
+        recorder.record(at(9, 1), &Message::Binary(vec![0xff, 0xff, 0xff])).unwrap();
+        recorder.record(at(9, 1), &frame(101.0)).unwrap();
+
+        let collector = TickReplayer::from_directory(&path).unwrap()
+            .replay(Collector::default(), ReplaySpeed::AsFastAsPossible).unwrap();
+        assert_eq!(collector.events, vec!["open", "tick 100", "tick 101", "close"]);
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+}
@@ -0,0 +1,336 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use arc_swap::ArcSwap;
+use chrono::{DateTime, FixedOffset, NaiveDateTime, TimeZone};
+use log::debug;
+use serde_json::Value as JsonValue;
+
+use crate::connect::KiteConnect;
+use super::tick::ist;
+use super::{Depth, DepthItem, Mode, Ohlc, Tick};
+
+type Instruments = HashMap<u32, Arc<ArcSwap<Tick>>>;
+
+struct Inner {
+    instruments: ArcSwap<Instruments>,
+    // Serializes writers, readers never take it
+    writer: Mutex<()>,
+}
+
+/// Latest known state of every instrument seen by the ticker. Ticks of
+/// different modes are merged, so an ltp tick updates the price while
+/// keeping the depth and open interest of earlier full ticks.
+///
+/// Clones share the same state. Reads never block, even while the ticker
+/// thread is writing, and return an immutable `Arc<Tick>` of the state at
+/// that moment. Attach it with `KiteTicker::set_snapshot`.
+#[derive(Clone)]
+pub struct MarketSnapshot {
+    inner: Arc<Inner>,
+}
+
+impl Default for MarketSnapshot {
+    fn default() -> Self {
+        MarketSnapshot::new()
+    }
+}
+
+/// Merges `tick` into the state built from earlier ticks
+pub(crate) fn merge(current: &Tick, tick: &Tick) -> Tick {
+    match tick.mode {
+        Mode::Full => tick.clone(),
+        Mode::Quote => Tick {
+            mode: current.mode.max(Mode::Quote),
+            last_trade_time: current.last_trade_time,
+            oi: current.oi,
+            oi_day_high: current.oi_day_high,
+            oi_day_low: current.oi_day_low,
+            exchange_timestamp: tick.exchange_timestamp.or(current.exchange_timestamp),
+            depth: current.depth.clone(),
+            ..tick.clone()
+        },
+        Mode::Ltp => {
+            let close = current.ohlc.close;
+            Tick {
+                last_price: tick.last_price,
+                change: if close != 0.0 { (tick.last_price - close) * 100_f64 / close } else { current.change },
+                ..current.clone()
+            }
+        }
+    }
+}
+
+impl MarketSnapshot {
+    pub fn new() -> MarketSnapshot {
+        MarketSnapshot {
+            inner: Arc::new(Inner {
+                instruments: ArcSwap::from_pointee(HashMap::new()),
+                writer: Mutex::new(()),
+            }),
+        }
+    }
+
+    /// Merges a batch of ticks into the snapshot
+    pub fn update(&self, ticks: &[Tick]) {
+        self.write(ticks, false);
+    }
+
+    /// Adds the ticks of instruments without a state yet, returning how many
+    /// were added. The check is made under the writer lock, so a tick
+    /// arriving meanwhile is never overwritten.
+    fn insert_if_absent(&self, ticks: &[Tick]) -> usize {
+        self.write(ticks, true)
+    }
+
+    fn write(&self, ticks: &[Tick], absent_only: bool) -> usize {
+        let _writer = self.inner.writer.lock().unwrap_or_else(|err| err.into_inner());
+        let instruments = self.inner.instruments.load();
+
+        let mut added: Vec<&Tick> = Vec::new();
+        for tick in ticks {
+            match instruments.get(&tick.instrument_token) {
+                Some(_) if absent_only => {},
+                Some(state) => state.store(Arc::new(merge(&state.load(), tick))),
+                None => added.push(tick),
+            }
+        }
+
+        let mut count = 0;
+        if !added.is_empty() {
+            // Copy on write, so readers of the old map are not disturbed
+            let mut updated: Instruments = (**instruments).clone();
+            for tick in added {
+                match updated.get(&tick.instrument_token) {
+                    Some(_) if absent_only => {},
+                    Some(state) => state.store(Arc::new(merge(&state.load(), tick))),
+                    None => {
+                        updated.insert(tick.instrument_token, Arc::new(ArcSwap::from_pointee(tick.clone())));
+                        count += 1;
+                    }
+                }
+            }
+            self.inner.instruments.store(Arc::new(updated));
+        }
+        count
+    }
+
+    /// Latest state of `instrument_token`
+    pub fn get(&self, instrument_token: u32) -> Option<Arc<Tick>> {
+        self.inner.instruments.load()
+            .get(&instrument_token)
+            .map(|state| state.load_full())
+    }
+
+    /// Last traded price of `instrument_token`
+    pub fn last_price(&self, instrument_token: u32) -> Option<f64> {
+        self.get(instrument_token).map(|tick| tick.last_price)
+    }
+
+    /// Last received market depth of `instrument_token`
+    pub fn depth(&self, instrument_token: u32) -> Option<Depth> {
+        self.get(instrument_token).and_then(|tick| tick.depth.clone())
+    }
+
+    /// Open interest of `instrument_token`
+    pub fn oi(&self, instrument_token: u32) -> Option<u32> {
+        self.get(instrument_token).map(|tick| tick.oi)
+    }
+
+    /// Tokens with a known state
+    pub fn tokens(&self) -> Vec<u32> {
+        self.inner.instruments.load().keys().cloned().collect()
+    }
+
+    /// Number of instruments with a known state
+    pub fn len(&self) -> usize {
+        self.inner.instruments.load().len()
+    }
+
+    /// Whether nothing has been received yet
+    pub fn is_empty(&self) -> bool {
+        self.inner.instruments.load().is_empty()
+    }
+
+    /// Seeds the snapshot from a `KiteConnect::quote` response. Instruments
+    /// that already ticked keep their state. Returns the number of
+    /// instruments seeded.
+    pub fn bootstrap(&self, quotes: &JsonValue) -> usize {
+        let quotes = match quotes.get("data").unwrap_or(quotes).as_object() {
+            Some(quotes) => quotes,
+            None => return 0,
+        };
+
+        let ticks: Vec<Tick> = quotes.iter()
+            .filter_map(|(instrument, quote)| {
+                let tick = tick_from_quote(quote);
+                if tick.is_none() {
+                    debug!("Unable to bootstrap {} from {:?}", instrument, quote);
+                }
+                tick
+            })
+            .collect();
+        self.insert_if_absent(&ticks)
+    }
+
+    /// Fetches quotes of `instruments`, given as `EXCHANGE:TRADINGSYMBOL`,
+    /// and seeds the snapshot with them
+    pub fn bootstrap_from(&self, kiteconnect: &KiteConnect, instruments: Vec<&str>) -> anyhow::Result<usize> {
+        let quotes = kiteconnect.quote(instruments)?;
+        Ok(self.bootstrap(&quotes))
+    }
+}
+
+fn quote_time(value: &JsonValue) -> Option<DateTime<FixedOffset>> {
+    let time = NaiveDateTime::parse_from_str(value.as_str()?, "%Y-%m-%d %H:%M:%S").ok()?;
+    ist().from_local_datetime(&time).single()
+}
+
+/// Converts a single instrument of a quote response into a full mode tick
+fn tick_from_quote(quote: &JsonValue) -> Option<Tick> {
+    let float = |value: &JsonValue| value.as_f64().unwrap_or_default();
+    let integer = |value: &JsonValue| value.as_f64().unwrap_or_default() as u32;
+    let depth = |side: &JsonValue| -> Vec<DepthItem> {
+        side.as_array().map(|items| items.iter().map(|item| DepthItem {
+            quantity: integer(&item["quantity"]),
+            price: float(&item["price"]),
+            orders: integer(&item["orders"]) as u16,
+        }).collect()).unwrap_or_default()
+    };
+
+    let instrument_token = quote["instrument_token"].as_u64()? as u32;
+    let last_price = quote["last_price"].as_f64()?;
+    let ohlc = Ohlc {
+        open: float(&quote["ohlc"]["open"]),
+        high: float(&quote["ohlc"]["high"]),
+        low: float(&quote["ohlc"]["low"]),
+        close: float(&quote["ohlc"]["close"]),
+    };
+
+    Some(Tick {
+        mode: Mode::Full,
+        instrument_token,
+        tradable: instrument_token & 0xFF != super::tick::SEGMENT_INDICES,
+        last_price,
+        last_quantity: integer(&quote["last_quantity"]),
+        average_price: float(&quote["average_price"]),
+        volume: integer(&quote["volume"]),
+        buy_quantity: integer(&quote["buy_quantity"]),
+        sell_quantity: integer(&quote["sell_quantity"]),
+        change: if ohlc.close != 0.0 { (last_price - ohlc.close) * 100_f64 / ohlc.close } else { 0.0 },
+        ohlc,
+        last_trade_time: quote_time(&quote["last_trade_time"]),
+        oi: integer(&quote["oi"]),
+        oi_day_high: integer(&quote["oi_day_high"]),
+        oi_day_low: integer(&quote["oi_day_low"]),
+        exchange_timestamp: quote_time(&quote["timestamp"]),
+        depth: Some(Depth {
+            buy: depth(&quote["depth"]["buy"]),
+            sell: depth(&quote["depth"]["sell"]),
+        }),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn full_tick(instrument_token: u32, last_price: f64) -> Tick {
+        Tick {
+            mode: Mode::Full,
+            instrument_token,
+            last_price,
+            ohlc: Ohlc { open: 100.0, high: 110.0, low: 90.0, close: 100.0 },
+            oi: 1500,
+            depth: Some(Depth {
+                buy: vec![DepthItem { quantity: 10, price: last_price - 0.05, orders: 1 }],
+                sell: vec![DepthItem { quantity: 10, price: last_price + 0.05, orders: 1 }],
+            }),
+            ..Tick::default()
+        }
+    }
+
+    #[test]
+    fn test_merge_modes() {
+        let snapshot = MarketSnapshot::new();
+        snapshot.update(&[full_tick(408065, 105.0)]);
+        let before = snapshot.get(408065).unwrap();
+
+        snapshot.update(&[Tick { instrument_token: 408065, last_price: 110.0, ..Tick::default() }]);
+        let tick = snapshot.get(408065).unwrap();
+        assert_eq!(tick.mode, Mode::Full);
+        assert_eq!(tick.last_price, 110.0);
+        assert_eq!(tick.change, 10.0);
+        assert_eq!(tick.oi, 1500);
+        assert!(tick.depth.is_some());
+
+        snapshot.update(&[Tick { mode: Mode::Quote, instrument_token: 408065, last_price: 111.0, volume: 5, ..Tick::default() }]);
+        let tick = snapshot.get(408065).unwrap();
+        assert_eq!((tick.mode, tick.volume, tick.oi), (Mode::Full, 5, 1500));
+        assert_eq!(snapshot.depth(408065), before.depth);
+
+        // Earlier reads are not affected by later updates
+        assert_eq!(before.last_price, 105.0);
+        assert_eq!(snapshot.last_price(884737), None);
+    }
+
+    #[test]
+    fn test_concurrent_reads() {
+        let snapshot = MarketSnapshot::new();
+        let writer = snapshot.clone();
+        let producer = thread::spawn(move || {
+            for i in 0..1000 {
+                writer.update(&[full_tick(i % 10, i as f64)]);
+            }
+        });
+        while !producer.is_finished() {
+            if let Some(tick) = snapshot.get(1) {
+                assert_eq!(tick.instrument_token, 1);
+            }
+        }
+        producer.join().unwrap();
+
+        assert_eq!(snapshot.len(), 10);
+        assert_eq!(snapshot.last_price(9), Some(999.0));
+    }
+
+    #[test]
+    fn test_bootstrap_never_overwrites_live_ticks() {
+        let snapshot = MarketSnapshot::new();
+        let live = snapshot.clone();
+        let producer = thread::spawn(move || {
+            for token in 0..500 {
+                live.update(&[full_tick(token, 200.0)]);
+            }
+        });
+        let stale: Vec<Tick> = (0..500).map(|token| full_tick(token, 100.0)).collect();
+        for chunk in stale.chunks(10) {
+            snapshot.insert_if_absent(chunk);
+        }
+        producer.join().unwrap();
+
+        assert_eq!(snapshot.len(), 500);
+        assert!((0..500).all(|token| snapshot.last_price(token) == Some(200.0)));
+    }
+
+    #[test]
+    fn test_bootstrap_from_quote() {
+        let _mock = mockito::mock("GET", mockito::Matcher::Regex(r"^/quote".to_string()))
+            .with_body_from_file("mocks/quote.json")
+            .create();
+        let kiteconnect = KiteConnect::new("API_KEY", "ACCESS_TOKEN");
+
+        let snapshot = MarketSnapshot::new();
+        assert_eq!(snapshot.bootstrap_from(&kiteconnect, vec!["NSE:INFY"]).unwrap(), 1);
+        let tick = snapshot.get(408065).unwrap();
+        assert_eq!(tick.last_price, 1412.95);
+        assert_eq!(tick.ohlc.close, 1389.65);
+        assert_eq!(tick.exchange_timestamp.unwrap().to_rfc3339(), "2021-06-08T15:45:56+05:30");
+        assert_eq!(tick.depth.as_ref().unwrap().sell[0].orders, 13);
+
+        // Ticks received since aren't overwritten
+        snapshot.update(&[Tick { instrument_token: 408065, last_price: 1415.0, ..Tick::default() }]);
+        assert_eq!(snapshot.bootstrap_from(&kiteconnect, vec!["NSE:INFY"]).unwrap(), 0);
+        assert_eq!(snapshot.last_price(408065), Some(1415.0));
+    }
+}
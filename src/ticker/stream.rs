@@ -0,0 +1,387 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use serde_json::Value as JsonValue;
+
+use super::{KiteTickerHandler, Tick, WebSocketHandler};
+
+/// Events delivered through a `TickerStream`
+#[derive(Debug, Clone, PartialEq)]
+pub enum TickerEvent {
+    /// Websocket connection is established
+    Connected,
+    /// A single decoded tick
+    Tick(Tick),
+    /// Order postback pushed on the websocket
+    OrderUpdate(JsonValue),
+    /// Any other text message sent by the server
+    Message(JsonValue),
+    /// Error reported by the server or the websocket
+    Error(String),
+    /// Websocket connection is closed
+    Disconnected,
+}
+
+impl TickerEvent {
+    /// Classifies a text message pushed by the server
+    pub(crate) fn from_message(message: JsonValue) -> TickerEvent {
+        match message["type"].as_str() {
+            Some("order") => TickerEvent::OrderUpdate(message["data"].clone()),
+            Some("error") => TickerEvent::Error(match message["data"].as_str() {
+                Some(error) => error.to_string(),
+                None => message["data"].to_string(),
+            }),
+            _ => TickerEvent::Message(message),
+        }
+    }
+}
+
+/// What the websocket thread does when the stream buffer is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backpressure {
+    /// Block the websocket thread until the consumer catches up
+    Block,
+    /// Drop the oldest buffered tick to make room for the new event
+    DropOldest,
+    /// Replace a buffered tick of the same instrument with the newer one and
+    /// fall back to `DropOldest` when the buffer is full of distinct instruments
+    Conflate,
+}
+
+/// A buffered event. Conflated ticks only hold their place in the queue,
+/// the latest tick of the instrument is kept in `Queue::conflated`.
+enum Slot {
+    Event(TickerEvent),
+    Conflated(u32),
+}
+
+impl Slot {
+    fn is_tick(&self) -> bool {
+        matches!(*self, Slot::Event(TickerEvent::Tick(_)) | Slot::Conflated(_))
+    }
+}
+
+struct Queue {
+    events: VecDeque<Slot>,
+    conflated: HashMap<u32, Tick>,
+    senders: usize,
+    receiver_alive: bool,
+    dropped: u64,
+}
+
+impl Queue {
+    fn pop(&mut self) -> Option<TickerEvent> {
+        let slot = self.events.pop_front()?;
+        Some(self.take(slot))
+    }
+
+    fn take(&mut self, slot: Slot) -> TickerEvent {
+        match slot {
+            Slot::Event(event) => event,
+            Slot::Conflated(token) => TickerEvent::Tick(self.conflated.remove(&token)
+                .expect("conflated tick without a slot")),
+        }
+    }
+}
+
+struct Shared {
+    queue: Mutex<Queue>,
+    not_empty: Condvar,
+    not_full: Condvar,
+    capacity: usize,
+    backpressure: Backpressure,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Queue> {
+        self.queue.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+/// Creates a bounded event channel with the given backpressure policy
+pub(crate) fn channel(capacity: usize, backpressure: Backpressure) -> (EventSender, TickerStream) {
+    let shared = Arc::new(Shared {
+        queue: Mutex::new(Queue {
+            events: VecDeque::with_capacity(capacity),
+            conflated: HashMap::new(),
+            senders: 1,
+            receiver_alive: true,
+            dropped: 0,
+        }),
+        not_empty: Condvar::new(),
+        not_full: Condvar::new(),
+        capacity: capacity.max(1),
+        backpressure,
+    });
+
+    (EventSender { shared: shared.clone() }, TickerStream { shared })
+}
+
+/// Producer half of the event channel, owned by the websocket thread
+pub(crate) struct EventSender {
+    shared: Arc<Shared>,
+}
+
+impl EventSender {
+    /// Queue an event applying the backpressure policy. Events are silently
+    /// discarded once the receiving `TickerStream` is dropped.
+    pub(crate) fn send(&self, event: TickerEvent) {
+        let shared = &self.shared;
+        let mut queue = shared.lock();
+        if !queue.receiver_alive {
+            return;
+        }
+
+        if shared.backpressure == Backpressure::Conflate {
+            if let TickerEvent::Tick(ref tick) = event {
+                if let Some(slot) = queue.conflated.get_mut(&tick.instrument_token) {
+                    *slot = tick.clone();
+                    return;
+                }
+            }
+        }
+
+        while queue.events.len() >= shared.capacity {
+            if shared.backpressure == Backpressure::Block {
+                queue = shared.not_full.wait(queue).unwrap_or_else(|err| err.into_inner());
+                if !queue.receiver_alive {
+                    return;
+                }
+                continue;
+            }
+
+            // Connection events are never dropped, only ticks make room
+            let oldest_tick = queue.events.iter().position(Slot::is_tick);
+            match oldest_tick {
+                Some(index) => {
+                    if let Some(slot) = queue.events.remove(index) {
+                        queue.take(slot);
+                    }
+                    queue.dropped += 1;
+                },
+                None => break,
+            }
+        }
+
+        let slot = match event {
+            TickerEvent::Tick(tick) if shared.backpressure == Backpressure::Conflate => {
+                let token = tick.instrument_token;
+                queue.conflated.insert(token, tick);
+                Slot::Conflated(token)
+            },
+            event => Slot::Event(event),
+        };
+        queue.events.push_back(slot);
+        shared.not_empty.notify_one();
+    }
+}
+
+impl Clone for EventSender {
+    fn clone(&self) -> Self {
+        self.shared.lock().senders += 1;
+        EventSender { shared: self.shared.clone() }
+    }
+}
+
+impl Drop for EventSender {
+    fn drop(&mut self) {
+        self.shared.lock().senders -= 1;
+        self.shared.not_empty.notify_all();
+    }
+}
+
+/// Receiving half of `KiteTicker::stream`. The stream ends once the
+/// websocket thread exits and all buffered events have been consumed.
+pub struct TickerStream {
+    shared: Arc<Shared>,
+}
+
+impl TickerStream {
+    /// Block until the next event is available. Returns `None` once the
+    /// ticker has shut down and the buffer is drained.
+    pub fn recv(&self) -> Option<TickerEvent> {
+        let mut queue = self.shared.lock();
+        loop {
+            if let Some(event) = queue.pop() {
+                self.shared.not_full.notify_one();
+                return Some(event);
+            }
+            if queue.senders == 0 {
+                return None;
+            }
+            queue = self.shared.not_empty.wait(queue).unwrap_or_else(|err| err.into_inner());
+        }
+    }
+
+    /// Return the next event if one is already buffered
+    pub fn try_recv(&self) -> Option<TickerEvent> {
+        let event = self.shared.lock().pop();
+        if event.is_some() {
+            self.shared.not_full.notify_one();
+        }
+        event
+    }
+
+    /// Wait up to `timeout` for the next event
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<TickerEvent> {
+        let deadline = Instant::now() + timeout;
+        let mut queue = self.shared.lock();
+        loop {
+            if let Some(event) = queue.pop() {
+                self.shared.not_full.notify_one();
+                return Some(event);
+            }
+            let now = Instant::now();
+            if queue.senders == 0 || now >= deadline {
+                return None;
+            }
+            queue = self.shared.not_empty.wait_timeout(queue, deadline - now)
+                .unwrap_or_else(|err| err.into_inner()).0;
+        }
+    }
+
+    /// Number of events currently buffered
+    pub fn len(&self) -> usize {
+        self.shared.lock().events.len()
+    }
+
+    /// Whether the buffer is currently empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of ticks dropped so far by the backpressure policy
+    pub fn dropped(&self) -> u64 {
+        self.shared.lock().dropped
+    }
+}
+
+impl Iterator for TickerStream {
+    type Item = TickerEvent;
+
+    fn next(&mut self) -> Option<TickerEvent> {
+        self.recv()
+    }
+}
+
+impl Drop for TickerStream {
+    fn drop(&mut self) {
+        let mut queue = self.shared.lock();
+        queue.receiver_alive = false;
+        queue.events.clear();
+        queue.conflated.clear();
+        self.shared.not_full.notify_all();
+    }
+}
+
+/// KiteTickerHandler which forwards every callback into an event channel
+#[derive(Clone)]
+pub(crate) struct ChannelHandler {
+    pub(crate) sender: EventSender,
+}
+
+impl KiteTickerHandler for ChannelHandler {
+    fn on_open<T>(&mut self, _ws: &mut WebSocketHandler<T>)
+    where T: KiteTickerHandler {
+        self.sender.send(TickerEvent::Connected);
+    }
+
+    fn on_ticks<T>(&mut self, _ws: &mut WebSocketHandler<T>, ticks: Vec<Tick>)
+    where T: KiteTickerHandler {
+        for tick in ticks {
+            self.sender.send(TickerEvent::Tick(tick));
+        }
+    }
+
+    fn on_order_update<T>(&mut self, _ws: &mut WebSocketHandler<T>, order: JsonValue)
+    where T: KiteTickerHandler {
+        self.sender.send(TickerEvent::OrderUpdate(order));
+    }
+
+    fn on_message<T>(&mut self, _ws: &mut WebSocketHandler<T>, message: JsonValue)
+    where T: KiteTickerHandler {
+        self.sender.send(TickerEvent::from_message(message));
+    }
+
+    fn on_close<T>(&mut self, _ws: &mut WebSocketHandler<T>)
+    where T: KiteTickerHandler {
+        self.sender.send(TickerEvent::Disconnected);
+    }
+
+    fn on_error<T>(&mut self, ws: &mut WebSocketHandler<T>)
+    where T: KiteTickerHandler {
+        let error = ws.last_error().unwrap_or("websocket error").to_string();
+        self.sender.send(TickerEvent::Error(error));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    fn tick(token: u32, last_price: f64) -> TickerEvent {
+        TickerEvent::Tick(Tick {
+            instrument_token: token,
+            last_price,
+            ..Tick::default()
+        })
+    }
+
+    #[test]
+    fn test_drop_oldest() {
+        let (tx, rx) = channel(2, Backpressure::DropOldest);
+        tx.send(TickerEvent::Connected);
+        tx.send(tick(1, 10.0));
+        tx.send(tick(2, 20.0));
+
+        assert_eq!(rx.dropped(), 1);
+        assert_eq!(rx.recv(), Some(TickerEvent::Connected));
+        assert_eq!(rx.recv(), Some(tick(2, 20.0)));
+        drop(tx);
+        assert_eq!(rx.recv(), None);
+    }
+
+    #[test]
+    fn test_conflate() {
+        let (tx, rx) = channel(2, Backpressure::Conflate);
+        tx.send(tick(1, 10.0));
+        tx.send(tick(2, 20.0));
+        tx.send(tick(1, 11.0));
+        tx.send(tick(3, 30.0));
+
+        assert_eq!(rx.dropped(), 1);
+        assert_eq!(rx.try_recv(), Some(tick(2, 20.0)));
+        assert_eq!(rx.try_recv(), Some(tick(3, 30.0)));
+        assert_eq!(rx.try_recv(), None);
+
+        // A dropped or delivered instrument starts a new slot
+        tx.send(tick(1, 12.0));
+        tx.send(tick(1, 13.0));
+        assert_eq!(rx.try_recv(), Some(tick(1, 13.0)));
+        assert_eq!(rx.try_recv(), None);
+    }
+
+    #[test]
+    fn test_block() {
+        let (tx, rx) = channel(1, Backpressure::Block);
+        let producer = thread::spawn(move || {
+            for i in 0..10_u32 {
+                tx.send(tick(i, i as f64));
+            }
+        });
+
+        let received: Vec<TickerEvent> = rx.collect();
+        producer.join().unwrap();
+        assert_eq!(received.len(), 10);
+        assert_eq!(received[9], tick(9, 9.0));
+    }
+
+    #[test]
+    fn test_receiver_dropped_unblocks_sender() {
+        let (tx, rx) = channel(1, Backpressure::Block);
+        tx.send(tick(1, 1.0));
+        let producer = thread::spawn(move || tx.send(tick(2, 2.0)));
+        drop(rx);
+        producer.join().unwrap();
+    }
+}
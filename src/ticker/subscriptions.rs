@@ -0,0 +1,213 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use serde_derive::{Deserialize, Serialize};
+use ws::{Error, ErrorKind, Result};
+
+/// Maximum number of instruments Kite streams on a single connection
+pub const MAX_TOKENS_PER_CONNECTION: usize = 3000;
+
+/// Streaming mode of a subscribed instrument, ordered by the data it carries
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    /// Last traded price only
+    Ltp,
+    /// Quote without market depth
+    Quote,
+    /// Quote along with five levels of market depth
+    Full,
+}
+
+impl Mode {
+    /// Value sent to and received from the ticker
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Mode::Ltp => "ltp",
+            Mode::Quote => "quote",
+            Mode::Full => "full",
+        }
+    }
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Mode {
+    type Err = Error;
+
+    fn from_str(mode: &str) -> Result<Mode> {
+        match mode {
+            "ltp" => Ok(Mode::Ltp),
+            "quote" => Ok(Mode::Quote),
+            "full" => Ok(Mode::Full),
+            _ => Err(Error::new(ErrorKind::Protocol, format!("Unknown ticker mode {:?}", mode))),
+        }
+    }
+}
+
+/// Instruments subscribed on a connection along with the mode explicitly
+/// requested for them. `None` means the server default mode applies.
+#[derive(Debug, Clone)]
+pub struct Subscriptions {
+    tokens: HashMap<u32, Option<Mode>>,
+    limit: usize,
+}
+
+impl Default for Subscriptions {
+    fn default() -> Self {
+        Subscriptions::with_limit(MAX_TOKENS_PER_CONNECTION)
+    }
+}
+
+impl Subscriptions {
+    /// Registry allowing at most `limit` instruments
+    pub fn with_limit(limit: usize) -> Subscriptions {
+        Subscriptions {
+            tokens: HashMap::new(),
+            limit,
+        }
+    }
+
+    /// Checks that subscribing to `instrument_tokens` stays within the limit
+    pub fn check_subscribe(&self, instrument_tokens: &[u32]) -> Result<()> {
+        let mut new_tokens: Vec<u32> = instrument_tokens.iter()
+            .filter(|token| !self.tokens.contains_key(token))
+            .cloned()
+            .collect();
+        new_tokens.sort_unstable();
+        new_tokens.dedup();
+
+        if self.tokens.len() + new_tokens.len() > self.limit {
+            return Err(Error::new(
+                ErrorKind::Capacity,
+                format!(
+                    "Subscribing to {} more instruments exceeds the limit of {} per connection",
+                    new_tokens.len(),
+                    self.limit
+                )
+            ));
+        }
+        Ok(())
+    }
+
+    /// Checks that every token in `instrument_tokens` is subscribed
+    pub fn check_subscribed(&self, instrument_tokens: &[u32]) -> Result<()> {
+        match instrument_tokens.iter().find(|token| !self.tokens.contains_key(token)) {
+            Some(token) => Err(Error::new(
+                ErrorKind::Protocol,
+                format!("Instrument {} is not subscribed", token)
+            )),
+            None => Ok(())
+        }
+    }
+
+    /// Records a subscription, keeping the mode of already subscribed tokens
+    pub fn subscribe(&mut self, instrument_tokens: &[u32]) -> Result<()> {
+        self.check_subscribe(instrument_tokens)?;
+        for token in instrument_tokens {
+            self.tokens.entry(*token).or_insert(None);
+        }
+        Ok(())
+    }
+
+    /// Removes the given tokens
+    pub fn unsubscribe(&mut self, instrument_tokens: &[u32]) {
+        for token in instrument_tokens {
+            self.tokens.remove(token);
+        }
+    }
+
+    /// Records the mode of already subscribed tokens
+    pub fn set_mode(&mut self, mode: Mode, instrument_tokens: &[u32]) -> Result<()> {
+        self.check_subscribed(instrument_tokens)?;
+        for token in instrument_tokens {
+            self.tokens.insert(*token, Some(mode));
+        }
+        Ok(())
+    }
+
+    /// Mode explicitly set for `token`, `None` if unsubscribed or left at
+    /// the server default
+    pub fn mode(&self, token: u32) -> Option<Mode> {
+        self.tokens.get(&token).cloned().flatten()
+    }
+
+    /// Whether `token` is subscribed
+    pub fn contains(&self, token: u32) -> bool {
+        self.tokens.contains_key(&token)
+    }
+
+    /// Number of subscribed instruments
+    pub fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    /// Whether nothing is subscribed
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+
+    /// Maximum number of instruments allowed
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Subscribed tokens grouped by their explicitly set mode
+    pub fn by_mode(&self) -> HashMap<Option<Mode>, Vec<u32>> {
+        let mut modes: HashMap<Option<Mode>, Vec<u32>> = HashMap::new();
+        for (token, mode) in self.tokens.iter() {
+            modes.entry(*mode).or_default().push(*token);
+        }
+        modes
+    }
+
+    /// All subscribed tokens with their mode
+    pub fn tokens(&self) -> &HashMap<u32, Option<Mode>> {
+        &self.tokens
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mode_from_str() {
+        assert_eq!("full".parse::<Mode>().unwrap(), Mode::Full);
+        assert_eq!(Mode::Ltp.to_string(), "ltp");
+        assert!("ful".parse::<Mode>().is_err());
+    }
+
+    #[test]
+    fn test_subscription_limit() {
+        let mut subscriptions = Subscriptions::with_limit(3);
+        subscriptions.subscribe(&[1, 2]).unwrap();
+        // Already subscribed tokens and duplicates don't count twice
+        subscriptions.subscribe(&[2, 3, 3]).unwrap();
+        assert!(subscriptions.subscribe(&[4]).is_err());
+        assert_eq!(subscriptions.len(), 3);
+
+        subscriptions.unsubscribe(&[1]);
+        subscriptions.subscribe(&[4]).unwrap();
+        assert!(subscriptions.contains(4));
+    }
+
+    #[test]
+    fn test_set_mode_requires_subscription() {
+        let mut subscriptions = Subscriptions::default();
+        assert!(subscriptions.set_mode(Mode::Full, &[408065]).is_err());
+
+        subscriptions.subscribe(&[408065, 884737]).unwrap();
+        subscriptions.set_mode(Mode::Full, &[408065]).unwrap();
+        assert_eq!(subscriptions.mode(408065), Some(Mode::Full));
+        assert_eq!(subscriptions.mode(884737), None);
+
+        let by_mode = subscriptions.by_mode();
+        assert_eq!(by_mode[&Some(Mode::Full)], vec![408065]);
+        assert_eq!(by_mode[&None], vec![884737]);
+    }
+}
@@ -0,0 +1,589 @@
+use std::io::{self, Cursor};
+use byteorder::{BigEndian, ReadBytesExt};
+use chrono::{DateTime, FixedOffset, TimeZone};
+use log::debug;
+use serde_derive::{Deserialize, Serialize};
+
+use super::Mode;
+
+/// Segment of currency derivatives, quoted with 7 decimals
+pub(crate) const SEGMENT_CDS: u32 = 3;
+/// Segment of indices, which can't be traded
+pub(crate) const SEGMENT_INDICES: u32 = 9;
+
+/// Offset of Indian Standard Time, in which exchange timestamps are reported
+pub fn ist() -> FixedOffset {
+    FixedOffset::east_opt(5 * 3600 + 30 * 60).unwrap()
+}
+
+/// Open, high, low and close of the day
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Ohlc {
+    pub open: f64,
+    pub high: f64,
+    pub low: f64,
+    pub close: f64,
+}
+
+/// A single price level of the market depth
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct DepthItem {
+    pub quantity: u32,
+    pub price: f64,
+    pub orders: u16,
+}
+
+/// Five best bids and offers, sent in full mode
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Depth {
+    pub buy: Vec<DepthItem>,
+    pub sell: Vec<DepthItem>,
+}
+
+/// A decoded tick. Fields not carried by the packet's `mode` are left at
+/// their defaults: ltp packets only fill `last_price`, index packets have no
+/// quantities and only full mode carries open interest, timestamps and depth.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Tick {
+    pub mode: Mode,
+    pub instrument_token: u32,
+    pub tradable: bool,
+    pub last_price: f64,
+    pub last_quantity: u32,
+    pub average_price: f64,
+    pub volume: u32,
+    pub buy_quantity: u32,
+    pub sell_quantity: u32,
+    pub ohlc: Ohlc,
+    /// Percentage change of `last_price` from the previous close
+    pub change: f64,
+    pub last_trade_time: Option<DateTime<FixedOffset>>,
+    pub oi: u32,
+    pub oi_day_high: u32,
+    pub oi_day_low: u32,
+    pub exchange_timestamp: Option<DateTime<FixedOffset>>,
+    pub depth: Option<Depth>,
+}
+
+impl Default for Tick {
+    fn default() -> Self {
+        Tick {
+            mode: Mode::Ltp,
+            instrument_token: 0,
+            tradable: true,
+            last_price: 0.0,
+            last_quantity: 0,
+            average_price: 0.0,
+            volume: 0,
+            buy_quantity: 0,
+            sell_quantity: 0,
+            ohlc: Ohlc::default(),
+            change: 0.0,
+            last_trade_time: None,
+            oi: 0,
+            oi_day_high: 0,
+            oi_day_low: 0,
+            exchange_timestamp: None,
+            depth: None,
+        }
+    }
+}
+
+impl Tick {
+    /// Exchange segment encoded in the lower byte of the instrument token
+    pub fn segment(&self) -> u32 {
+        self.instrument_token & 0xFF
+    }
+}
+
+/// Divisor applied to prices of the given instrument
+pub(crate) fn price_divisor(instrument_token: u32) -> f64 {
+    if instrument_token & 0xFF == SEGMENT_CDS {
+        10000000.0
+    } else {
+        100.0
+    }
+}
+
+/// Converts epoch seconds sent by the exchange into IST, `0` meaning unset
+pub(crate) fn exchange_time(seconds: u32) -> Option<DateTime<FixedOffset>> {
+    if seconds == 0 {
+        return None;
+    }
+    ist().timestamp_opt(seconds as i64, 0).single()
+}
+
+fn change(last_price: f64, close: f64) -> f64 {
+    if close != 0.0 {
+        (last_price - close) * 100_f64 / close
+    } else {
+        0.0
+    }
+}
+
+/// Parses a binary ticker frame into the list of ticks it carries
+pub fn parse_binary(data: &[u8]) -> io::Result<Vec<Tick>> {
+    let mut reader = Cursor::new(data);
+    let number_of_packets = reader.read_u16::<BigEndian>()?;
+
+    // The count comes off the wire, the smallest packet is 8 bytes
+    let capacity = (number_of_packets as usize).min(data.len() / 8);
+    let mut ticks: Vec<Tick> = Vec::with_capacity(capacity);
+    let mut position: usize = 2;
+    for _ in 0..number_of_packets {
+        let packet_length = reader.read_u16::<BigEndian>()? as usize;
+        let start = position + 2;
+        let end = start + packet_length;
+        if end > data.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("packet of {} bytes exceeds the frame", packet_length)
+            ));
+        }
+
+        if let Some(tick) = parse_packet(&data[start..end])? {
+            ticks.push(tick);
+        }
+
+        // Place reader in the position after the packet
+        reader.set_position(end as u64);
+        position = end;
+    }
+    Ok(ticks)
+}
+
+/// Parses a single packet, ignoring packet lengths the ticker doesn't define
+fn parse_packet(packet: &[u8]) -> io::Result<Option<Tick>> {
+    match packet.len() {
+        8 | 28 | 32 | 44 | 184 => {},
+        length => {
+            debug!("undefined packet length received: {}", length);
+            return Ok(None);
+        }
+    }
+
+    let mut reader = Cursor::new(packet);
+    let instrument_token = reader.read_u32::<BigEndian>()?;
+    let divisor = price_divisor(instrument_token);
+    let mut price = || -> io::Result<f64> {
+        Ok(reader.read_i32::<BigEndian>()? as f64 / divisor)
+    };
+
+    let mut tick = Tick {
+        instrument_token,
+        tradable: instrument_token & 0xFF != SEGMENT_INDICES,
+        last_price: price()?,
+        ..Tick::default()
+    };
+
+    match packet.len() {
+        // LTP
+        8 => {},
+
+        // Index quote/full
+        28 | 32 => {
+            tick.mode = if packet.len() == 28 { Mode::Quote } else { Mode::Full };
+            tick.ohlc = Ohlc {
+                high: price()?,
+                low: price()?,
+                open: price()?,
+                close: price()?,
+            };
+            tick.change = change(tick.last_price, tick.ohlc.close);
+
+            if packet.len() == 32 {
+                // Skip the 4 byte price change sent before the timestamp
+                reader.set_position(28);
+                tick.exchange_timestamp = exchange_time(reader.read_u32::<BigEndian>()?);
+            }
+        },
+
+        // Quote/Full
+        _ => {
+            tick.mode = if packet.len() == 44 { Mode::Quote } else { Mode::Full };
+            tick.last_quantity = reader.read_u32::<BigEndian>()?;
+            tick.average_price = reader.read_i32::<BigEndian>()? as f64 / divisor;
+            tick.volume = reader.read_u32::<BigEndian>()?;
+            tick.buy_quantity = reader.read_u32::<BigEndian>()?;
+            tick.sell_quantity = reader.read_u32::<BigEndian>()?;
+            tick.ohlc = Ohlc {
+                open: reader.read_i32::<BigEndian>()? as f64 / divisor,
+                high: reader.read_i32::<BigEndian>()? as f64 / divisor,
+                low: reader.read_i32::<BigEndian>()? as f64 / divisor,
+                close: reader.read_i32::<BigEndian>()? as f64 / divisor,
+            };
+            tick.change = change(tick.last_price, tick.ohlc.close);
+
+            if packet.len() == 184 {
+                tick.last_trade_time = exchange_time(reader.read_u32::<BigEndian>()?);
+                tick.oi = reader.read_u32::<BigEndian>()?;
+                tick.oi_day_high = reader.read_u32::<BigEndian>()?;
+                tick.oi_day_low = reader.read_u32::<BigEndian>()?;
+                tick.exchange_timestamp = exchange_time(reader.read_u32::<BigEndian>()?);
+
+                // 64 bytes read so far, the remaining 120 are 10 depth entries of 12 bytes
+                let mut depth = Depth {
+                    buy: Vec::with_capacity(5),
+                    sell: Vec::with_capacity(5),
+                };
+                for index in 0..10 {
+                    let item = DepthItem {
+                        quantity: reader.read_u32::<BigEndian>()?,
+                        price: reader.read_i32::<BigEndian>()? as f64 / divisor,
+                        orders: reader.read_u16::<BigEndian>()?,
+                    };
+
+                    if index < 5 {
+                        depth.buy.push(item);
+                    } else {
+                        depth.sell.push(item);
+                    }
+
+                    // Dont care 2 bytes padding
+                    reader.read_u16::<BigEndian>()?;
+                }
+                tick.depth = Some(depth);
+            }
+        }
+    }
+
+    Ok(Some(tick))
+}
+
+/// Serializes ticks into a binary ticker frame, the inverse of
+/// `parse_binary`. Each tick is encoded with the packet layout of its `mode`
+/// and segment; a full mode tick without depth sends empty depth entries.
+///
+/// Panics if more than `u16::MAX` ticks are passed, the most a frame holds.
+pub fn encode_binary(ticks: &[Tick]) -> Vec<u8> {
+    assert!(ticks.len() <= u16::MAX as usize, "{} ticks don't fit in a single frame", ticks.len());
+
+    let mut data = (ticks.len() as u16).to_be_bytes().to_vec();
+    for tick in ticks {
+        let packet = encode_packet(tick);
+        data.extend_from_slice(&(packet.len() as u16).to_be_bytes());
+        data.extend_from_slice(&packet);
+    }
+    data
+}
+
+fn encode_packet(tick: &Tick) -> Vec<u8> {
+    let divisor = price_divisor(tick.instrument_token);
+    let price = |value: f64| ((value * divisor).round() as i32).to_be_bytes();
+    let time = |value: Option<DateTime<FixedOffset>>| {
+        (value.map(|time| time.timestamp()).unwrap_or(0) as u32).to_be_bytes()
+    };
+
+    let mut packet: Vec<u8> = Vec::with_capacity(184);
+    packet.extend_from_slice(&tick.instrument_token.to_be_bytes());
+    packet.extend_from_slice(&price(tick.last_price));
+    if tick.mode == Mode::Ltp {
+        return packet;
+    }
+
+    if tick.segment() == SEGMENT_INDICES {
+        packet.extend_from_slice(&price(tick.ohlc.high));
+        packet.extend_from_slice(&price(tick.ohlc.low));
+        packet.extend_from_slice(&price(tick.ohlc.open));
+        packet.extend_from_slice(&price(tick.ohlc.close));
+        packet.extend_from_slice(&price(tick.last_price - tick.ohlc.close));
+        if tick.mode == Mode::Full {
+            packet.extend_from_slice(&time(tick.exchange_timestamp));
+        }
+        return packet;
+    }
+
+    packet.extend_from_slice(&tick.last_quantity.to_be_bytes());
+    packet.extend_from_slice(&price(tick.average_price));
+    packet.extend_from_slice(&tick.volume.to_be_bytes());
+    packet.extend_from_slice(&tick.buy_quantity.to_be_bytes());
+    packet.extend_from_slice(&tick.sell_quantity.to_be_bytes());
+    packet.extend_from_slice(&price(tick.ohlc.open));
+    packet.extend_from_slice(&price(tick.ohlc.high));
+    packet.extend_from_slice(&price(tick.ohlc.low));
+    packet.extend_from_slice(&price(tick.ohlc.close));
+    if tick.mode == Mode::Quote {
+        return packet;
+    }
+
+    packet.extend_from_slice(&time(tick.last_trade_time));
+    packet.extend_from_slice(&tick.oi.to_be_bytes());
+    packet.extend_from_slice(&tick.oi_day_high.to_be_bytes());
+    packet.extend_from_slice(&tick.oi_day_low.to_be_bytes());
+    packet.extend_from_slice(&time(tick.exchange_timestamp));
+
+    let empty = Depth::default();
+    let depth = tick.depth.as_ref().unwrap_or(&empty);
+    for side in [&depth.buy, &depth.sell].iter() {
+        for index in 0..5 {
+            let item = side.get(index).cloned().unwrap_or_default();
+            packet.extend_from_slice(&item.quantity.to_be_bytes());
+            packet.extend_from_slice(&price(item.price));
+            packet.extend_from_slice(&item.orders.to_be_bytes());
+            packet.extend_from_slice(&[0, 0]);
+        }
+    }
+    packet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(packets: &[Vec<u8>]) -> Vec<u8> {
+        let mut data = (packets.len() as u16).to_be_bytes().to_vec();
+        for packet in packets {
+            data.extend_from_slice(&(packet.len() as u16).to_be_bytes());
+            data.extend_from_slice(packet);
+        }
+        data
+    }
+
+    fn packet(values: &[i32]) -> Vec<u8> {
+        values.iter().flat_map(|value| value.to_be_bytes().to_vec()).collect()
+    }
+
+    // 2020-01-01 09:15:00 IST
+    const MARKET_OPEN: i32 = 1577850300;
+
+    #[test]
+    fn test_ltp_packet() {
+        let ticks = parse_binary(&frame(&[packet(&[408065, 123450])])).unwrap();
+        assert_eq!(ticks, vec![Tick {
+            instrument_token: 408065,
+            last_price: 1234.5,
+            ..Tick::default()
+        }]);
+    }
+
+    #[test]
+    fn test_cds_divisor() {
+        // Segment 3 is currency derivatives
+        let token = (1000 << 8) | 3;
+        let ticks = parse_binary(&frame(&[packet(&[token, 745_012_345])])).unwrap();
+        assert_eq!(ticks[0].last_price, 74.5012345);
+        assert_eq!(ticks[0].segment(), SEGMENT_CDS);
+    }
+
+    #[test]
+    fn test_index_full_packet() {
+        // NIFTY 50: last price, high, low, open, close, price change, timestamp
+        let token = 256265;
+        let ticks = parse_binary(&frame(&[
+            packet(&[token, 1210000, 1220000, 1200000, 1205000, 1100000, 110000, MARKET_OPEN]),
+            packet(&[token, 1210000, 1220000, 1200000, 1205000, 1100000, 110000]),
+        ])).unwrap();
+
+        let tick = &ticks[0];
+        assert_eq!(tick.mode, Mode::Full);
+        assert!(!tick.tradable);
+        assert_eq!(tick.ohlc, Ohlc { open: 12050.0, high: 12200.0, low: 12000.0, close: 11000.0 });
+        assert_eq!(tick.change, 10.0);
+        let timestamp = tick.exchange_timestamp.unwrap();
+        assert_eq!(timestamp.to_rfc3339(), "2020-01-01T09:15:00+05:30");
+
+        assert_eq!(ticks[1].mode, Mode::Quote);
+        assert_eq!(ticks[1].exchange_timestamp, None);
+    }
+
+    fn full_packet() -> Vec<u8> {
+        let values = [
+            408065, 123450, 10, 123000, 50000, 700, 800,
+            122000, 124000, 121000, 120000,
+            MARKET_OPEN - 5, 1500, 1600, 1400, MARKET_OPEN,
+        ];
+        let mut data = packet(&values);
+        for level in 0..10_i32 {
+            data.extend_from_slice(&(100 + level).to_be_bytes());
+            data.extend_from_slice(&(123400 + level * 5).to_be_bytes());
+            data.extend_from_slice(&(level as u16 + 1).to_be_bytes());
+            data.extend_from_slice(&[0, 0]);
+        }
+        data
+    }
+
+    #[test]
+    fn test_full_packet() {
+        let ticks = parse_binary(&frame(&[full_packet()])).unwrap();
+        let tick = &ticks[0];
+        assert_eq!(tick.mode, Mode::Full);
+        assert_eq!(tick.last_quantity, 10);
+        assert_eq!(tick.average_price, 1230.0);
+        assert_eq!(tick.volume, 50000);
+        assert_eq!(tick.ohlc, Ohlc { open: 1220.0, high: 1240.0, low: 1210.0, close: 1200.0 });
+        assert_eq!(tick.change, 2.875);
+        assert_eq!(tick.last_trade_time.unwrap().to_rfc3339(), "2020-01-01T09:14:55+05:30");
+        assert_eq!(tick.exchange_timestamp.unwrap().to_rfc3339(), "2020-01-01T09:15:00+05:30");
+        assert_eq!((tick.oi, tick.oi_day_high, tick.oi_day_low), (1500, 1600, 1400));
+
+        let depth = tick.depth.as_ref().unwrap();
+        assert_eq!(depth.buy[0], DepthItem { quantity: 100, price: 1234.0, orders: 1 });
+        assert_eq!(depth.sell[4], DepthItem { quantity: 109, price: 1234.45, orders: 10 });
+    }
+
+    #[test]
+    fn test_truncated_frame() {
+        let mut data = frame(&[packet(&[408065, 123450])]);
+        data.truncate(data.len() - 1);
+        assert!(parse_binary(&data).is_err());
+        assert!(parse_binary(&[0xff, 0xff]).is_err());
+
+        // Unknown packet lengths are skipped
+        let ticks = parse_binary(&frame(&[vec![0; 12], packet(&[408065, 100])])).unwrap();
+        assert_eq!(ticks.len(), 1);
+    }
+
+    #[test]
+    fn test_encode_round_trip() {
+        let token = 256265;
+        let data = frame(&[
+            packet(&[408065, 123450]),
+            packet(&[token, 1210000, 1220000, 1200000, 1205000, 1100000, 110000]),
+            packet(&[token, 1210000, 1220000, 1200000, 1205000, 1100000, 110000, MARKET_OPEN]),
+            packet(&[408065, 123450, 10, 123000, 50000, 700, 800, 122000, 124000, 121000, 120000]),
+            full_packet(),
+        ]);
+
+        let ticks = parse_binary(&data).unwrap();
+        assert_eq!(encode_binary(&ticks), data);
+        assert_eq!(parse_binary(&encode_binary(&ticks)).unwrap(), ticks);
+    }
+
+    #[test]
+    fn test_encode_full_without_depth() {
+        let tick = Tick {
+            mode: Mode::Full,
+            instrument_token: 408065,
+            last_price: 1234.5,
+            ..Tick::default()
+        };
+        let data = encode_binary(&[tick]);
+        assert_eq!(data.len(), 2 + 2 + 184);
+
+        let depth = parse_binary(&data).unwrap()[0].depth.clone().unwrap();
+        assert_eq!(depth.buy, vec![DepthItem::default(); 5]);
+    }
+
+    mod properties {
+        use super::*;
+        use proptest::collection::vec;
+        use proptest::prelude::*;
+
+        fn token(segment: impl Strategy<Value = u32>) -> impl Strategy<Value = i32> {
+            (0_u32..0xFF_FFFF, segment).prop_map(|(id, segment)| ((id << 8) | segment) as i32)
+        }
+
+        /// Prices small enough that differences of two still fit a packet field
+        fn price() -> impl Strategy<Value = i32> {
+            -1_000_000_000..1_000_000_000
+        }
+
+        fn unsigned() -> impl Strategy<Value = i32> {
+            any::<u32>().prop_map(|value| value as i32)
+        }
+
+        fn ltp_packet() -> impl Strategy<Value = Vec<u8>> {
+            (token(any::<u8>().prop_map(u32::from)), price())
+                .prop_map(|(token, last_price)| packet(&[token, last_price]))
+        }
+
+        fn index_packet() -> impl Strategy<Value = Vec<u8>> {
+            (token(Just(SEGMENT_INDICES)), price(), price(), price(), price(), price(), any::<Option<u32>>())
+                .prop_map(|(token, last_price, high, low, open, close, timestamp)| {
+                    let mut values = vec![token, last_price, high, low, open, close, last_price - close];
+                    values.extend(timestamp.map(|timestamp| timestamp as i32));
+                    packet(&values)
+                })
+        }
+
+        fn tradable_token() -> impl Strategy<Value = i32> {
+            token(any::<u8>().prop_map(u32::from).prop_filter("not an index", |segment| *segment != SEGMENT_INDICES))
+        }
+
+        fn quote_values() -> impl Strategy<Value = Vec<i32>> {
+            (
+                tradable_token(), price(), unsigned(), price(), unsigned(), unsigned(), unsigned(),
+                (price(), price(), price(), price()),
+            ).prop_map(|(token, last_price, quantity, average, volume, buy, sell, (open, high, low, close))| {
+                vec![token, last_price, quantity, average, volume, buy, sell, open, high, low, close]
+            })
+        }
+
+        fn full_packet() -> impl Strategy<Value = Vec<u8>> {
+            (quote_values(), vec(unsigned(), 5), vec((unsigned(), price(), any::<u16>()), 10))
+                .prop_map(|(mut values, extended, depth)| {
+                    values.extend(extended);
+                    let mut data = packet(&values);
+                    for (quantity, price, orders) in depth {
+                        data.extend_from_slice(&quantity.to_be_bytes());
+                        data.extend_from_slice(&price.to_be_bytes());
+                        data.extend_from_slice(&orders.to_be_bytes());
+                        data.extend_from_slice(&[0, 0]);
+                    }
+                    data
+                })
+        }
+
+        fn any_packet() -> impl Strategy<Value = Vec<u8>> {
+            prop_oneof![
+                ltp_packet(),
+                index_packet(),
+                quote_values().prop_map(|values| packet(&values)),
+                full_packet(),
+            ]
+        }
+
+        proptest! {
+            #[test]
+            fn round_trip(packets in vec(any_packet(), 0..20)) {
+                let data = frame(&packets);
+                let ticks = parse_binary(&data).unwrap();
+                prop_assert_eq!(ticks.len(), packets.len());
+                prop_assert_eq!(&encode_binary(&ticks), &data);
+                prop_assert_eq!(parse_binary(&encode_binary(&ticks)).unwrap(), ticks);
+            }
+
+            #[test]
+            fn random_bytes_never_panic(data in vec(any::<u8>(), 0..1024)) {
+                let _ = parse_binary(&data);
+            }
+
+            #[test]
+            fn random_packets_never_panic(packets in vec(vec(any::<u8>(), 0..200), 0..10)) {
+                // Well framed packets of any content parse, skipping unknown lengths
+                let ticks = parse_binary(&frame(&packets)).unwrap();
+                prop_assert!(ticks.len() <= packets.len());
+            }
+
+            #[test]
+            fn cds_divisor(token in token(Just(SEGMENT_CDS)), last_price in price(), average in price()) {
+                let mut values = vec![token, last_price, 1, average, 0, 0, 0, 0, 0, 0, 0];
+                let tick = &parse_binary(&frame(&[packet(&values)])).unwrap()[0];
+                prop_assert_eq!(tick.segment(), SEGMENT_CDS);
+                prop_assert_eq!(tick.last_price, last_price as f64 / 10000000.0);
+                prop_assert_eq!(tick.average_price, average as f64 / 10000000.0);
+
+                // Other segments are quoted in paise
+                values[0] = token + 1;
+                let tick = &parse_binary(&frame(&[packet(&values)])).unwrap()[0];
+                prop_assert_eq!(tick.last_price, last_price as f64 / 100.0);
+            }
+
+            #[test]
+            fn index_packet_shapes(token in token(Just(SEGMENT_INDICES)), data in vec(any::<u8>(), 28), full in any::<bool>()) {
+                let mut data = data;
+                data[..4].copy_from_slice(&token.to_be_bytes());
+                if full {
+                    data.extend_from_slice(&[0, 0, 0, 0]);
+                }
+
+                let tick = &parse_binary(&frame(&[data])).unwrap()[0];
+                prop_assert_eq!(tick.mode, if full { Mode::Full } else { Mode::Quote });
+                prop_assert!(!tick.tradable);
+                prop_assert_eq!((tick.last_quantity, tick.volume, tick.oi), (0, 0, 0));
+                prop_assert_eq!(tick.exchange_timestamp, None);
+                prop_assert!(tick.depth.is_none());
+            }
+        }
+    }
+}